@@ -48,6 +48,12 @@ pub enum ChangeLogMsg {
         title: String,
         operator: String,
     },
+    /// a sync would have done too much, automation in the cluster waits for an admin
+    Paused {
+        cluster: String,
+        issue: i32,
+        title: String,
+    },
 }
 
 #[cfg(feature = "slack")]
@@ -55,6 +61,7 @@ pub enum ChangeLogMsg {
 pub async fn slack_updater(mut rx: mpsc::Receiver<ChangeLogMsg>, conf: Conf) {
     let mut interval = time::interval(Duration::from_secs(conf.poll_interval));
     interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    use crate::hostlist;
     use std::collections::{HashMap, HashSet};
 
     let connector = SlackClientHyperConnector::new().unwrap();
@@ -120,6 +127,22 @@ pub async fn slack_updater(mut rx: mpsc::Receiver<ChangeLogMsg>, conf: Conf) {
                         }
                         operators.insert(o);
                     }
+                    // an admin has to act on this, so it isn't held for the next batch
+                    ChangeLogMsg::Paused {
+                        cluster: c,
+                        issue: i,
+                        title: t,
+                    } => {
+                        let post_chat_req = SlackApiChatPostMessageRequest::new(
+                            format!("#{}", conf.slack.channel).into(),
+                            SlackMessageContent::new()
+                                .with_text(format!("{} automation paused, issue {}: {}", c, i, t)),
+                        );
+                        let session = client.open_session(&token);
+                        if let Err(e) = session.chat_post_message(&post_chat_req).await {
+                            warn!("error sending slack message {}", e);
+                        };
+                    }
                 }
             }
             _ = interval.tick() => {
@@ -133,13 +156,13 @@ pub async fn slack_updater(mut rx: mpsc::Receiver<ChangeLogMsg>, conf: Conf) {
                 let session = client.open_session(&token);
 
                 let msg = format!(
-                    "{:?} Opened: {:?}, Updated: {:?}, Closed: {:?}, Offlined: {:?}, Resumed: {:?}",
+                    "{:?} Opened: {:?}, Updated: {:?}, Closed: {:?}, Offlined: {}, Resumed: {}",
                     operators,
                     open_issues,
                     update_issues,
                     close_issues,
-                    offline_nodes,
-                    resume_nodes,
+                    hostlist::compress(&offline_nodes.iter().collect::<Vec<_>>()),
+                    hostlist::compress(&resume_nodes.iter().collect::<Vec<_>>()),
                 );
                 let post_chat_req = SlackApiChatPostMessageRequest::new(
                     format!("#{}", conf.slack.channel).into(),
@@ -0,0 +1,224 @@
+use super::infrastructure::Infrastructure;
+use super::scheduler::{BatchResult, NodeSnapshot, SchedulerError, SchedulerTrait};
+use super::Topology;
+use crate::cluster::ClusterTrait;
+use crate::conf::TopologyAttributes;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use tracing::{debug, instrument};
+
+/// cluster whose layout comes from attributes the scheduler reports for each node
+///
+/// the layout is rebuilt from every `nodes_status`, so hardware added to the scheduler is picked up
+/// on the next sync. Every node the scheduler reports is real, until the first sync none are
+///
+/// the api and the sync loop share one instance, so both see the topology the last sync found
+#[derive(Debug)]
+pub struct AttributeCluster {
+    name: String,
+    attributes: TopologyAttributes,
+    topology: RwLock<Topology>,
+    infrastructure: Infrastructure,
+    sched: Box<dyn SchedulerTrait>,
+}
+
+impl AttributeCluster {
+    pub fn new(name: &str, attributes: TopologyAttributes, sched: Box<dyn SchedulerTrait>) -> Self {
+        Self {
+            name: name.to_string(),
+            attributes,
+            topology: RwLock::default(),
+            infrastructure: Infrastructure::default(),
+            sched,
+        }
+    }
+
+    /// add targets that aren't nodes, see [`Infrastructure`]
+    pub fn with_infrastructure(mut self, infrastructure: Infrastructure) -> Self {
+        self.infrastructure = infrastructure;
+        self
+    }
+
+    fn topology(&self) -> std::sync::RwLockReadGuard<'_, Topology> {
+        self.topology.read().unwrap()
+    }
+}
+
+#[async_trait]
+impl ClusterTrait for AttributeCluster {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    #[instrument]
+    fn siblings(&self, target: &str) -> Vec<String> {
+        self.topology().siblings(target)
+    }
+    #[instrument]
+    fn cousins(&self, target: &str) -> Vec<String> {
+        self.topology().cousins(target)
+    }
+    #[instrument]
+    fn chassis(&self, target: &str) -> Vec<String> {
+        self.topology().chassis(target)
+    }
+    #[instrument]
+    fn rack(&self, target: &str) -> Vec<String> {
+        self.topology().rack(target)
+    }
+    /// there are no node types to take tags from
+    fn tags(&self, _target: &str) -> Vec<String> {
+        vec![]
+    }
+    #[instrument]
+    fn domains(&self, target: &str) -> Vec<String> {
+        self.topology().domains(target)
+    }
+    #[instrument]
+    fn domain(&self, target: &str, domain: &str) -> Vec<String> {
+        self.topology().domain(target, domain)
+    }
+    #[instrument]
+    fn real_node(&self, target: &str) -> bool {
+        self.topology().location(target).is_some()
+    }
+    fn infrastructure(&self) -> &Infrastructure {
+        &self.infrastructure
+    }
+
+    /// also rebuilds the topology from the attributes of the nodes returned
+    ///
+    /// errors, keeping the old topology, if a node has the name of infrastructure
+    #[instrument]
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        let nodes = self.sched.nodes_status().await?;
+        let topology = Topology::from_attributes(&nodes, &self.attributes);
+        if let Some(name) = self
+            .infrastructure
+            .names()
+            .find(|n| topology.location(n).is_some())
+        {
+            return Err(SchedulerError::Other(format!(
+                "infrastructure {} is also a node",
+                name
+            )));
+        }
+        debug!("refreshed topology of {}, {:?}", self.name, topology);
+        *self.topology.write().unwrap() = topology;
+        Ok(nodes)
+    }
+    #[instrument]
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult {
+        self.sched.release_nodes(targets).await
+    }
+    #[instrument]
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult {
+        self.sched.offline_nodes(targets).await
+    }
+    #[instrument]
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        self.sched
+            .create_reservation(name, targets, start, end)
+            .await
+    }
+    #[instrument]
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        self.sched.delete_reservation(id).await
+    }
+    #[instrument]
+    fn refresh_conn(&self) {
+        self.sched.refresh_conn();
+    }
+}
+
+#[cfg(test)]
+fn snapshot(attributes: &[(&str, &str)]) -> NodeSnapshot {
+    use crate::entities::target::TargetStatus;
+    let mut snap = NodeSnapshot::new(TargetStatus::Online, "free", "");
+    snap.attributes = attributes
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    snap
+}
+
+#[tokio::test]
+async fn topology_from_attributes() {
+    let sched = super::scheduler::FakeScheduler::new();
+    for (node, blade, card) in [
+        ("gu0001", "b1", "0"),
+        ("gu0002", "b1", "0"),
+        ("gu0003", "b1", "1"),
+        ("gu0004", "b2", "0"),
+    ] {
+        sched.set_snapshot(
+            node,
+            snapshot(&[("blade", blade), ("card", card), ("leaf", "sw1")]),
+        );
+    }
+    let attributes = TopologyAttributes {
+        card: Some("card".to_string()),
+        blade: Some("blade".to_string()),
+        domains: [("switch".to_string(), "leaf".to_string())].into(),
+        ..Default::default()
+    };
+    let cluster = AttributeCluster::new("attribute-test", attributes, Box::new(sched.clone()));
+    assert!(!cluster.real_node("gu0001"));
+
+    cluster.nodes_status().await.unwrap();
+    assert!(cluster.real_node("gu0001"));
+    assert!(!cluster.real_node("gu0005"));
+    assert_eq!(cluster.siblings("gu0001"), vec!["gu0001", "gu0002"]);
+    assert_eq!(cluster.siblings("gu0004"), vec!["gu0004"]);
+    assert_eq!(
+        cluster.cousins("gu0002"),
+        vec!["gu0001", "gu0002", "gu0003"]
+    );
+    // no chassis or rack attributes, so they fall back to the blade
+    assert_eq!(cluster.rack("gu0004"), vec!["gu0004"]);
+    assert_eq!(cluster.domains("gu0001"), vec!["switch"]);
+    assert_eq!(cluster.domain("gu0004", "switch").len(), 4);
+
+    // new hardware and moved nodes are picked up on the next sync
+    sched.set_snapshot("gu0005", snapshot(&[("blade", "b2"), ("card", "0")]));
+    sched.set_snapshot("gu0003", snapshot(&[]));
+    cluster.nodes_status().await.unwrap();
+    assert_eq!(cluster.siblings("gu0004"), vec!["gu0004", "gu0005"]);
+    assert_eq!(cluster.cousins("gu0002"), vec!["gu0001", "gu0002"]);
+    assert_eq!(cluster.cousins("gu0003"), vec!["gu0003"]);
+    assert!(cluster.domains("gu0005").is_empty());
+}
+
+#[tokio::test]
+async fn infrastructure_named_like_a_node() {
+    use crate::conf;
+    let sched = super::scheduler::FakeScheduler::new();
+    sched.set_snapshot("gu0001", snapshot(&[("blade", "b1")]));
+    let infrastructure = Infrastructure::from_conf(&[conf::Infrastructure {
+        name: "sw1".to_string(),
+        kind: "switch".to_string(),
+        nodes: "gu0001".to_string(),
+    }])
+    .unwrap();
+    let attributes = TopologyAttributes {
+        blade: Some("blade".to_string()),
+        ..Default::default()
+    };
+    let cluster = AttributeCluster::new("attribute-test", attributes, Box::new(sched.clone()))
+        .with_infrastructure(infrastructure);
+    cluster.nodes_status().await.unwrap();
+    assert!(cluster.real_node("gu0001"));
+
+    // the old topology is kept until the clash is fixed
+    sched.set_snapshot("sw1", snapshot(&[("blade", "b1")]));
+    assert!(cluster.nodes_status().await.is_err());
+    assert!(!cluster.real_node("sw1"));
+    assert!(cluster.real_node("gu0001"));
+}
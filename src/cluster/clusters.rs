@@ -0,0 +1,84 @@
+use super::ClusterTrait;
+use crate::conf::Conf;
+use tracing::instrument;
+
+/// every cluster a cttd instance manages
+#[derive(Debug)]
+pub struct Clusters {
+    clusters: Vec<Box<dyn ClusterTrait>>,
+}
+
+impl Clusters {
+    pub fn new(clusters: Vec<Box<dyn ClusterTrait>>) -> Self {
+        Self { clusters }
+    }
+
+    /// connect to the scheduler of every cluster in the config
+    ///
+    /// errors if any cluster's topology file is invalid
+    pub fn from_conf(conf: &Conf) -> Result<Self, String> {
+        let clusters = conf
+            .clusters()
+            .iter()
+            .map(|c| super::from_conf(c).map_err(|e| format!("cluster {}: {}", c.name, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(clusters))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn ClusterTrait> {
+        self.clusters
+            .iter()
+            .find(|c| c.name() == name)
+            .map(|c| c.as_ref())
+    }
+
+    /// cluster a node or infrastructure belongs to
+    ///
+    /// without a cluster name it is the first cluster with a node type or infrastructure matching
+    /// `target`
+    #[instrument]
+    pub fn find(&self, cluster: Option<&str>, target: &str) -> Option<&dyn ClusterTrait> {
+        match cluster {
+            Some(name) => self.get(name).filter(|c| c.real_target(target)),
+            None => self
+                .clusters
+                .iter()
+                .find(|c| c.real_target(target))
+                .map(|c| c.as_ref()),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn ClusterTrait> {
+        self.clusters.iter().map(|c| c.as_ref())
+    }
+}
+
+#[test]
+fn find_cluster() {
+    use super::RegexCluster;
+    use crate::cluster::scheduler;
+    use crate::conf::NodeType;
+    let node_type = |prefix: &str| NodeType {
+        digits: Some(4),
+        ..NodeType::with_prefix(prefix)
+    };
+    let clusters = Clusters::new(vec![
+        Box::new(RegexCluster::new(
+            "cpu",
+            vec![node_type("dec")],
+            Box::new(scheduler::FakeScheduler::new()),
+        )),
+        Box::new(RegexCluster::new(
+            "gpu",
+            vec![node_type("deg"), node_type("dec")],
+            Box::new(scheduler::FakeScheduler::new()),
+        )),
+    ]);
+    assert_eq!(clusters.find(None, "dec0001").unwrap().name(), "cpu");
+    assert_eq!(clusters.find(None, "deg0001").unwrap().name(), "gpu");
+    assert_eq!(clusters.find(Some("gpu"), "dec0001").unwrap().name(), "gpu");
+    assert!(clusters.find(Some("cpu"), "deg0001").is_none());
+    assert!(clusters.find(Some("casper"), "dec0001").is_none());
+    assert!(clusters.find(None, "NotANode").is_none());
+    assert_eq!(clusters.iter().count(), 2);
+}
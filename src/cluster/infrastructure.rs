@@ -0,0 +1,78 @@
+use crate::conf;
+use crate::hostlist;
+use std::collections::BTreeMap;
+
+/// targets that aren't nodes, by name, with the kind of each and the nodes behind it
+#[derive(Clone, Debug, Default)]
+pub struct Infrastructure {
+    targets: BTreeMap<String, (String, Vec<String>)>,
+}
+
+impl Infrastructure {
+    /// errors if a name is used twice or a hostlist is bad
+    pub fn from_conf(conf: &[conf::Infrastructure]) -> Result<Self, String> {
+        let mut targets = BTreeMap::new();
+        for i in conf {
+            if i.name.is_empty() || i.kind.is_empty() {
+                return Err("infrastructure needs a name and a kind".to_string());
+            }
+            let nodes = hostlist::expand(&i.nodes).map_err(|e| format!("{}: {}", i.name, e))?;
+            if targets
+                .insert(i.name.clone(), (i.kind.clone(), nodes))
+                .is_some()
+            {
+                return Err(format!("infrastructure {} is listed twice", i.name));
+            }
+        }
+        Ok(Self { targets })
+    }
+
+    /// what `name` is, eg switch, None if it isn't infrastructure
+    pub fn kind(&self, name: &str) -> Option<&str> {
+        self.targets.get(name).map(|(kind, _)| kind.as_str())
+    }
+
+    /// nodes an issue on `name` takes out, None if it isn't infrastructure
+    pub fn nodes(&self, name: &str) -> Option<&[String]> {
+        self.targets.get(name).map(|(_, nodes)| nodes.as_slice())
+    }
+
+    /// infrastructure `node` depends on
+    pub fn serving(&self, node: &str) -> Vec<String> {
+        self.targets
+            .iter()
+            .filter(|(_, (_, nodes))| nodes.iter().any(|n| n == node))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.targets.keys().map(|n| n.as_str())
+    }
+}
+
+#[test]
+fn infrastructure_from_conf() {
+    let item = |name: &str, kind: &str, nodes: &str| conf::Infrastructure {
+        name: name.to_string(),
+        kind: kind.to_string(),
+        nodes: nodes.to_string(),
+    };
+    let infra = Infrastructure::from_conf(&[
+        item("switch-r3-l2", "switch", "gu[0001-0004]"),
+        item("scratch", "filesystem", "gu[0001-0016]"),
+    ])
+    .unwrap();
+    assert_eq!(infra.kind("switch-r3-l2"), Some("switch"));
+    assert_eq!(infra.kind("gu0001"), None);
+    assert_eq!(infra.nodes("switch-r3-l2").unwrap().len(), 4);
+    assert_eq!(infra.serving("gu0002"), vec!["scratch", "switch-r3-l2"]);
+    assert_eq!(infra.serving("gu0010"), vec!["scratch"]);
+    assert!(infra.serving("gu0017").is_empty());
+
+    assert!(Infrastructure::from_conf(&[item("a", "switch", "gu[1-")]).is_err());
+    assert!(Infrastructure::from_conf(&[item("a", "", "gu1")]).is_err());
+    assert!(
+        Infrastructure::from_conf(&[item("a", "switch", "gu1"), item("a", "pdu", "gu2")]).is_err()
+    );
+}
@@ -1,15 +1,112 @@
-use crate::entities::target::TargetStatus;
+use crate::conf;
+use crate::entities::issue::ToOffline;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use scheduler::{BatchResult, NodeSnapshot, SchedulerError};
 use std::collections::HashMap;
+use std::fmt::Debug;
 
-pub trait ClusterTrait {
+#[async_trait]
+pub trait ClusterTrait: Debug + Send + Sync {
+    /// name targets in this cluster are stored under
+    fn name(&self) -> &str;
     fn siblings(&self, target: &str) -> Vec<String>;
     fn cousins(&self, target: &str) -> Vec<String>;
+    /// nodes in the same chassis as `target`
+    fn chassis(&self, target: &str) -> Vec<String>;
+    /// nodes in the same rack as `target`
+    fn rack(&self, target: &str) -> Vec<String>;
+    /// names of the failure domains, eg switch or pdu, `target` is in
+    fn domains(&self, target: &str) -> Vec<String>;
+    /// nodes sharing `target`'s failure domain `domain`, empty if it isn't in one
+    fn domain(&self, target: &str, domain: &str) -> Vec<String>;
+    /// nodes an issue on `target` takes out, every node behind it for infrastructure
+    fn group(&self, target: &str, to_offline: ToOffline, domain: Option<&str>) -> Vec<String> {
+        if let Some(nodes) = self.infrastructure().nodes(target) {
+            return nodes.to_vec();
+        }
+        match to_offline {
+            ToOffline::Node => vec![target.to_string()],
+            ToOffline::Card => self.siblings(target),
+            ToOffline::Blade => self.cousins(target),
+            ToOffline::Chassis => self.chassis(target),
+            ToOffline::Rack => self.rack(target),
+            ToOffline::Domain => domain.map(|d| self.domain(target, d)).unwrap_or_default(),
+        }
+    }
     fn real_node(&self, target: &str) -> bool;
-    fn nodes_status(&self) -> Result<HashMap<String, (TargetStatus, String)>, String>;
-    fn release_node(&self, target: &str) -> Result<(), ()>;
-    fn offline_node(&self, target: &str, comment: &str) -> Result<(), ()>;
+    /// targets that aren't nodes, eg switches
+    fn infrastructure(&self) -> &Infrastructure;
+    /// issues can be opened against `target`, it is a node or infrastructure
+    fn real_target(&self, target: &str) -> bool {
+        self.real_node(target) || self.infrastructure().kind(target).is_some()
+    }
+    /// tags `target` gets from the config, tags set through the api are stored on the target
+    fn tags(&self, target: &str) -> Vec<String>;
+    /// the sync loop only records what it would do to `target`, set by its node type
+    fn observed(&self, _target: &str) -> bool {
+        false
+    }
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError>;
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult;
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult;
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError>;
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError>;
+    fn refresh_conn(&self);
 }
 
+/// build a cluster from the config, using its topology file or scheduler attributes if it has
+/// them
+///
+/// errors if a node type pattern is bad, the topology file can't be read or doesn't make sense,
+/// both a topology file and attributes are set, the infrastructure is invalid, or the scheduler
+/// backend wasn't built in
+pub fn from_conf(conf: &conf::Cluster) -> Result<Box<dyn ClusterTrait>, String> {
+    regex_cluster::check_node_types(&conf.node_types)?;
+    let infrastructure = Infrastructure::from_conf(&conf.infrastructure)?;
+    let sched = scheduler::from_conf(&conf.scheduler)?;
+    let cluster: Box<dyn ClusterTrait> = match (&conf.topology, &conf.topology_attributes) {
+        (Some(_), Some(_)) => {
+            return Err("topology and topology_attributes can't both be set".to_string())
+        }
+        (Some(path), None) => Box::new(
+            TopologyCluster::new(&conf.name, Topology::from_file(path)?, sched)
+                .with_infrastructure(infrastructure),
+        ),
+        (None, Some(attributes)) => Box::new(
+            AttributeCluster::new(&conf.name, attributes.clone(), sched)
+                .with_infrastructure(infrastructure),
+        ),
+        (None, None) => Box::new(
+            RegexCluster::new(&conf.name, conf.node_types.clone(), sched)
+                .with_infrastructure(infrastructure),
+        ),
+    };
+    // an attribute cluster has no nodes until its first sync, it checks on every sync instead
+    if let Some(name) = cluster
+        .infrastructure()
+        .names()
+        .find(|n| cluster.real_node(n))
+    {
+        return Err(format!("infrastructure {} is also a node", name));
+    }
+    Ok(cluster)
+}
+
+mod attribute_cluster;
+mod clusters;
+mod infrastructure;
 mod regex_cluster;
 pub mod scheduler;
+mod topology_cluster;
+pub use attribute_cluster::AttributeCluster;
+pub use clusters::Clusters;
+pub use infrastructure::Infrastructure;
 pub use regex_cluster::RegexCluster;
+pub use topology_cluster::{Topology, TopologyCluster};
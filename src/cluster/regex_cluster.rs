@@ -1,133 +1,421 @@
 #![allow(unused_variables)]
-use super::scheduler::{PbsScheduler, SchedulerTrait};
+use super::infrastructure::Infrastructure;
+use super::scheduler::{BatchResult, NodeSnapshot, SchedulerError, SchedulerTrait};
 use crate::cluster::ClusterTrait;
 use crate::conf::NodeType;
-use crate::entities::target::TargetStatus;
-use regex::Regex;
+use crate::entities::tag;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use regex::{Captures, Regex};
 use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
 use tracing::instrument;
 use tracing::warn;
 
-#[derive(Debug)]
 pub struct RegexCluster {
-    node_types: Vec<NodeType>,
-    //TODO have sched be of type SchedulerTrait instead
-    sched: PbsScheduler,
+    name: String,
+    node_types: Vec<CompiledType>,
+    /// every node of the prefix node types with a `last_num` and `digits`, so most lookups skip
+    /// the regexes. Without `digits` the regex also takes zero padded names, eg gu01 for gu1
+    index: HashMap<String, NodeEntry>,
+    /// every node type is in `index`, so a name missing from it isn't a node
+    indexed: bool,
+    infrastructure: Infrastructure,
+    sched: Box<dyn SchedulerTrait>,
 }
 
-impl RegexCluster {
-    #[instrument]
-    pub fn refresh_conn(&mut self) {
-        self.sched.refresh_conn();
+// the index has an entry per node, keep it out of the tracing spans
+impl fmt::Debug for RegexCluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegexCluster")
+            .field("name", &self.name)
+            .field("node_types", &self.node_types.len())
+            .field("index", &self.index.len())
+            .field("infrastructure", &self.infrastructure)
+            .field("sched", &self.sched)
+            .finish()
     }
+}
 
-    #[instrument]
-    //TODO have sched be of type SchedulerTrait instead
-    pub fn new(node_types: Vec<NodeType>, sched: PbsScheduler) -> Self {
-        Self { sched, node_types }
+/// a node type with its regex compiled once
+#[derive(Debug)]
+struct CompiledType {
+    ntype: NodeType,
+    re: Regex,
+    sizes: [u32; 4],
+}
+
+impl CompiledType {
+    fn new(ntype: NodeType) -> Self {
+        Self {
+            re: node_regex(&ntype),
+            sizes: level_sizes(&ntype),
+            ntype,
+        }
     }
 
-    #[instrument]
-    fn get_node_type(&self, target: &str) -> Option<NodeType> {
-        for ntype in self.node_types.clone() {
-            //let re = Regex::new(&ntype.names).unwrap();
-            let re = if let Some(digits) = ntype.digits {
-                Regex::new(&format!(r"^{}\d{{{}}}$", ntype.prefix, digits)).unwrap()
-            } else {
-                Regex::new(&format!(r"^{}\d+$", ntype.prefix)).unwrap()
+    /// number of the node within its node type, from the `node` capture for patterns
+    fn node_num(&self, target: &str) -> Option<u32> {
+        let val = if self.ntype.pattern.is_some() {
+            self.re.captures(target)?.name("node")?.as_str()
+        } else {
+            target.strip_prefix(&self.ntype.prefix)?
+        };
+        FromStr::from_str(val).ok()
+    }
+
+    fn matches(&self, target: &str) -> Option<u32> {
+        if !self.re.is_match(target) {
+            return None;
+        }
+        self.node_num(target).filter(|&num| {
+            self.ntype.first_num.unwrap_or(1) <= num
+                && self.ntype.last_num.is_none_or(|last| num <= last)
+        })
+    }
+
+    fn prefix_name(&self, num: u32) -> String {
+        match self.ntype.digits {
+            Some(digits) => format!("{}{:0>width$}", self.ntype.prefix, num, width = digits),
+            None => format!("{}{}", self.ntype.prefix, num),
+        }
+    }
+}
+
+/// node type of a prefix node, and the card, blade, chassis and rack it is in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct NodeEntry {
+    ntype: usize,
+    groups: [u32; 4],
+}
+
+impl NodeEntry {
+    fn new(ntype: usize, sizes: &[u32; 4], num: u32) -> Self {
+        Self {
+            ntype,
+            groups: sizes.map(|size| num.saturating_sub(1) / size.max(1)),
+        }
+    }
+}
+
+/// hardware levels a `pattern` can capture, smallest first
+const LEVELS: [&str; 4] = ["card", "blade", "chassis", "rack"];
+
+/// nodes per card, blade, chassis and rack, a missing size falls back to the level below it
+fn level_sizes(nodetype: &NodeType) -> [u32; 4] {
+    let card = nodetype.board.unwrap_or(1);
+    let blade = nodetype.slot.unwrap_or(card);
+    let chassis = nodetype.chassis.unwrap_or(blade);
+    let rack = nodetype.rack.unwrap_or(chassis);
+    [card, blade, chassis, rack]
+}
+
+/// regex matching the whole name of a node of `ntype`
+fn node_regex(ntype: &NodeType) -> Regex {
+    if let Some(pattern) = &ntype.pattern {
+        Regex::new(&format!("^(?:{})$", pattern)).unwrap()
+    } else if let Some(digits) = ntype.digits {
+        Regex::new(&format!(r"^{}\d{{{}}}$", ntype.prefix, digits)).unwrap()
+    } else {
+        Regex::new(&format!(r"^{}\d+$", ntype.prefix)).unwrap()
+    }
+}
+
+/// check every `pattern` compiles and captures a node number
+pub fn check_node_types(node_types: &[NodeType]) -> Result<(), String> {
+    for pattern in node_types.iter().filter_map(|n| n.pattern.as_ref()) {
+        let re = Regex::new(pattern).map_err(|e| format!("bad pattern {}: {}", pattern, e))?;
+        if !re.capture_names().any(|n| n == Some("node")) {
+            return Err(format!("pattern {} has no node capture group", pattern));
+        }
+    }
+    for tag in node_types.iter().flat_map(|n| &n.tags) {
+        tag::check_name(tag)?;
+    }
+    Ok(())
+}
+
+/// `target` with each named capture replaced by the new value, keeping any zero padding
+fn format_name(
+    target: &str,
+    caps: &Captures,
+    values: &[(&str, u32)],
+    digits: Option<usize>,
+) -> String {
+    let mut spans: Vec<_> = values
+        .iter()
+        .filter_map(|(group, val)| {
+            let m = caps.name(group)?;
+            let width = match (*group, digits) {
+                ("node", Some(d)) => d,
+                _ if m.as_str().starts_with('0') => m.as_str().len(),
+                _ => 0,
             };
-            if re.is_match(target) {
-                let val = target.strip_prefix(&ntype.prefix).unwrap();
-                let num: u32 = FromStr::from_str(val).unwrap();
-                if ntype.first_num.unwrap_or(1) <= num {
-                    if let Some(last) = ntype.last_num {
-                        if num <= last {
-                            return Some(ntype);
-                        }
-                    } else {
-                        return Some(ntype);
-                    }
+            Some((m.range(), format!("{:0>width$}", val, width = width)))
+        })
+        .collect();
+    spans.sort_by_key(|(r, _)| r.start);
+    let mut name = String::with_capacity(target.len());
+    let mut last = 0;
+    for (r, val) in spans {
+        name.push_str(&target[last..r.start]);
+        name.push_str(&val);
+        last = r.end;
+    }
+    name.push_str(&target[last..]);
+    name
+}
+
+impl RegexCluster {
+    #[instrument(skip(node_types))]
+    pub fn new(name: &str, node_types: Vec<NodeType>, sched: Box<dyn SchedulerTrait>) -> Self {
+        let node_types: Vec<CompiledType> = node_types.into_iter().map(CompiledType::new).collect();
+        let mut index = HashMap::new();
+        let mut indexed = true;
+        for (i, t) in node_types.iter().enumerate() {
+            let Some(last) = t
+                .ntype
+                .last_num
+                .filter(|_| t.ntype.pattern.is_none() && t.ntype.digits.is_some())
+            else {
+                indexed = false;
+                continue;
+            };
+            for num in t.ntype.first_num.unwrap_or(1)..=last {
+                let name = t.prefix_name(num);
+                // earlier node types win, same as when looking a name up
+                if t.re.is_match(&name)
+                    && !node_types[..i].iter().any(|e| e.matches(&name).is_some())
+                {
+                    index
+                        .entry(name)
+                        .or_insert(NodeEntry::new(i, &t.sizes, num));
                 }
             }
         }
-        None
+        Self {
+            name: name.to_string(),
+            sched,
+            node_types,
+            index,
+            indexed,
+            infrastructure: Infrastructure::default(),
+        }
     }
-    #[instrument]
-    fn get_related(&self, target: &str, nodetype: NodeType, size: u32) -> Vec<String> {
+
+    /// add targets that aren't nodes, see [`Infrastructure`]
+    pub fn with_infrastructure(mut self, infrastructure: Infrastructure) -> Self {
+        self.infrastructure = infrastructure;
+        self
+    }
+
+    /// node type `target` belongs to
+    fn node_type(&self, target: &str) -> Option<&NodeType> {
+        let i = match self.index.get(target) {
+            Some(e) => e.ntype,
+            None => self.get_node_type(target)?.0,
+        };
+        Some(&self.node_types[i].ntype)
+    }
+
+    /// index of the node type `target` belongs to and its node number, for names missing from
+    /// the index
+    fn get_node_type(&self, target: &str) -> Option<(usize, u32)> {
+        if self.indexed {
+            return None;
+        }
+        self.node_types
+            .iter()
+            .enumerate()
+            .find_map(|(i, t)| t.matches(target).map(|num| (i, num)))
+    }
+    /// nodes in the same block of `size` as the node in `group`
+    fn get_related(
+        &self,
+        target: &str,
+        ntype: &CompiledType,
+        group: u32,
+        size: u32,
+    ) -> Vec<String> {
         if size > 1 {
-            let val = target.strip_prefix(&nodetype.prefix).unwrap();
-            let num: u32 = FromStr::from_str(val).unwrap();
-            let start = (((num - 1) / size) * size) + 1;
-            let mut related = Vec::with_capacity(size.try_into().unwrap());
-            for i in start..start + size {
-                if let Some(digits) = nodetype.digits {
-                    related.push(format!("{}{:0>width$}", nodetype.prefix, i, width = digits));
-                } else {
-                    related.push(format!("{}{}", nodetype.prefix, i,));
-                }
-            }
-            related
+            let start = group * size + 1;
+            (start..start + size)
+                .map(|i| ntype.prefix_name(i))
+                .collect()
         } else {
             vec![target.to_string()]
         }
     }
+    /// nodes sharing `LEVELS[level]` with `target`, for node types with a `pattern`
+    ///
+    /// captured levels are numbered from 0 within the next captured level up, and the node from
+    /// `first_num` within the lowest captured level. Nodes are numbered by their position in the
+    /// lowest captured level at or above `level` and grouped into blocks of that level's size,
+    /// the same as prefix node types
+    #[instrument]
+    fn get_related_captures(
+        &self,
+        target: &str,
+        compiled: &CompiledType,
+        level: usize,
+    ) -> Vec<String> {
+        let nodetype = &compiled.ntype;
+        let Some(caps) = compiled.re.captures(target) else {
+            return vec![];
+        };
+        let sizes = compiled.sizes;
+        let upper = (level..LEVELS.len()).find(|&l| caps.name(LEVELS[l]).is_some());
+        let below: Vec<usize> = (0..upper.unwrap_or(LEVELS.len()))
+            .filter(|&l| caps.name(LEVELS[l]).is_some())
+            .collect();
+        let node_base = nodetype.first_num.unwrap_or(1);
+        let Some(mut pos) = compiled.node_num(target).map(|n| n - node_base) else {
+            return vec![];
+        };
+        for &l in &below {
+            match caps[LEVELS[l]].parse::<u32>() {
+                Ok(v) => pos += v * sizes[l],
+                Err(_) => {
+                    warn!(
+                        "can't number {} {} of {}",
+                        LEVELS[l], &caps[LEVELS[l]], target
+                    );
+                    return vec![target.to_string()];
+                }
+            }
+        }
+        let size = sizes[level];
+        let start = (pos / size) * size;
+        (start..start + size)
+            .map(|p| {
+                // split the position back into the captured levels and the node number
+                let mut rem = p;
+                let mut values = Vec::with_capacity(below.len() + 1);
+                for &l in below.iter().rev() {
+                    values.push((LEVELS[l], rem / sizes[l]));
+                    rem %= sizes[l];
+                }
+                values.push(("node", rem + node_base));
+                format_name(target, &caps, &values, nodetype.digits)
+            })
+            .collect()
+    }
+    /// nodes sharing `LEVELS[level]` with `target`
+    fn related(&self, target: &str, level: usize) -> Vec<String> {
+        let entry = match self.index.get(target) {
+            Some(e) => *e,
+            None => match self.get_node_type(target) {
+                Some((i, _)) if self.node_types[i].ntype.pattern.is_some() => {
+                    return self.get_related_captures(target, &self.node_types[i], level);
+                }
+                Some((i, num)) => NodeEntry::new(i, &self.node_types[i].sizes, num),
+                //TODO return None instead
+                None => return vec![],
+            },
+        };
+        let ntype = &self.node_types[entry.ntype];
+        self.get_related(target, ntype, entry.groups[level], ntype.sizes[level])
+    }
 }
 
+#[async_trait]
 impl ClusterTrait for RegexCluster {
+    fn name(&self) -> &str {
+        &self.name
+    }
     #[instrument]
     fn siblings(&self, target: &str) -> Vec<String> {
-        if let Some(nodetype) = self.get_node_type(target) {
-            self.get_related(target, nodetype.clone(), nodetype.board.unwrap_or(1))
-        } else {
-            //TODO return None instead
-            vec![]
-        }
+        self.related(target, 0)
     }
     #[instrument]
     fn cousins(&self, target: &str) -> Vec<String> {
-        if let Some(nodetype) = self.get_node_type(target) {
-            self.get_related(
-                target,
-                nodetype.clone(),
-                nodetype.slot.unwrap_or(nodetype.board.unwrap_or(1)),
-            )
-        } else {
-            //TODO return None instead
-            vec![]
-        }
+        self.related(target, 1)
+    }
+    #[instrument]
+    fn chassis(&self, target: &str) -> Vec<String> {
+        self.related(target, 2)
+    }
+    #[instrument]
+    fn rack(&self, target: &str) -> Vec<String> {
+        self.related(target, 3)
+    }
+    /// named failure domains need a topology file
+    fn domains(&self, target: &str) -> Vec<String> {
+        vec![]
+    }
+    fn domain(&self, target: &str, domain: &str) -> Vec<String> {
+        vec![]
     }
     #[instrument]
     fn real_node(&self, target: &str) -> bool {
-        self.get_node_type(target).is_some()
+        self.index.contains_key(target) || self.get_node_type(target).is_some()
+    }
+    fn infrastructure(&self) -> &Infrastructure {
+        &self.infrastructure
+    }
+    fn tags(&self, target: &str) -> Vec<String> {
+        self.node_type(target)
+            .map(|t| t.tags.clone())
+            .unwrap_or_default()
+    }
+    fn observed(&self, target: &str) -> bool {
+        self.node_type(target).is_some_and(|t| t.observe)
     }
 
     #[instrument]
-    fn nodes_status(&self) -> Result<HashMap<String, (TargetStatus, String)>, String> {
-        self.sched.nodes_status()
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        self.sched.nodes_status().await
+    }
+    #[instrument]
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult {
+        self.sched.release_nodes(targets).await
+    }
+    #[instrument]
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult {
+        self.sched.offline_nodes(targets).await
     }
     #[instrument]
-    fn release_node(&self, target: &str) -> Result<(), ()> {
-        self.sched.release_node(target)
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        self.sched
+            .create_reservation(name, targets, start, end)
+            .await
     }
     #[instrument]
-    fn offline_node(&self, target: &str, comment: &str) -> Result<(), ()> {
-        self.sched.offline_node(target, comment)
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        self.sched.delete_reservation(id).await
+    }
+    #[instrument]
+    fn refresh_conn(&self) {
+        self.sched.refresh_conn();
     }
 }
 
-/*
-// TODO FIXME fix tests need to mock out PbsScheduler
+#[cfg(test)]
+fn gust(sched: &super::scheduler::FakeScheduler) -> RegexCluster {
+    RegexCluster::new(
+        "gust",
+        vec![NodeType {
+            digits: Some(4),
+            last_num: Some(18),
+            board: Some(2),
+            slot: Some(4),
+            chassis: Some(8),
+            ..NodeType::with_prefix("gu")
+        }],
+        Box::new(sched.clone()),
+    )
+}
+
 #[test]
 fn siblings() {
-    let gust = RegexCluster::new(vec![NodeType {
-        prefix: "gu".to_string(),
-        digits: Some(4),
-        first_num: None,
-        last_num: Some(18),
-        board: Some(2),
-        slot: Some(4),
-    }]);
+    let gust = gust(&super::scheduler::FakeScheduler::new());
     let expected = vec![
         vec!["gu0001", "gu0002"],
         vec!["gu0003", "gu0004"],
@@ -136,22 +424,15 @@ fn siblings() {
     for e in &expected {
         for s in e.iter() {
             let actual = gust.siblings(s);
-            println!("expected: {:?} actual: {:?}", &e, &actual);
-            assert!(e.eq(&actual));
+            assert_eq!(e, &actual, "for {}", s);
         }
     }
+    assert!(gust.siblings("NotANode").is_empty());
 }
 
 #[test]
 fn cousins() {
-    let gust = RegexCluster::new(vec![NodeType {
-        prefix: "gu".to_string(),
-        digits: Some(4),
-        first_num: None,
-        last_num: Some(18),
-        board: Some(2),
-        slot: Some(4),
-    }]);
+    let gust = gust(&super::scheduler::FakeScheduler::new());
     let expected = vec![
         vec!["gu0001", "gu0002", "gu0003", "gu0004"],
         vec!["gu0005", "gu0006", "gu0007", "gu0008"],
@@ -159,33 +440,305 @@ fn cousins() {
     for e in &expected {
         for s in e.iter() {
             let actual = gust.cousins(s);
-            println!("expected: {:?} actual: {:?}", &e, &actual);
-            assert!(e.eq(&actual));
+            assert_eq!(e, &actual, "for {}", s);
         }
     }
+    assert!(gust.cousins("NotANode").is_empty());
+}
+
+#[test]
+fn chassis_and_rack() {
+    use crate::entities::issue::ToOffline;
+    let gust = gust(&super::scheduler::FakeScheduler::new());
+    let first: Vec<String> = (1..=8).map(|i| format!("gu{:0>4}", i)).collect();
+    assert_eq!(gust.chassis("gu0005"), first);
+    // no rack size falls back to the chassis size
+    assert_eq!(gust.rack("gu0005"), first);
+    assert_eq!(gust.chassis("gu0009")[0], "gu0009");
+    assert!(gust.chassis("NotANode").is_empty());
+    assert!(gust.domains("gu0001").is_empty());
+    assert_eq!(
+        gust.group("gu0003", ToOffline::Card, None),
+        vec!["gu0003", "gu0004"]
+    );
+    assert_eq!(gust.group("gu0003", ToOffline::Node, None), vec!["gu0003"]);
+    assert!(gust
+        .group("gu0003", ToOffline::Domain, Some("switch"))
+        .is_empty());
 }
 
 #[test]
 fn real_node() {
-    let gust = RegexCluster::new(vec![NodeType {
-        prefix: "gu".to_string(),
-        digits: Some(4),
-        first_num: None,
-        last_num: Some(18),
-        board: Some(2),
-        slot: Some(4),
-    }]);
+    let gust = gust(&super::scheduler::FakeScheduler::new());
     let expected_true = vec!["gu0001", "gu0002", "gu0015", "gu0016", "gu0017", "gu0018"];
     let expected_false = vec!["gu1", "gu0000", "NotANode", "gu-001", "gu0019", "gu00017"];
     for n in &expected_true {
-        let actual = gust.real_node(n);
-        println!("for {} expected: true, actual: {}", n, actual);
-        assert!(actual);
+        assert!(gust.real_node(n), "{} should be a node", n);
     }
     for n in &expected_false {
-        let actual = gust.real_node(n);
-        println!("for {} expected: false, actual: {}", n, actual);
-        assert!(!actual);
+        assert!(!gust.real_node(n), "{} shouldn't be a node", n);
+    }
+}
+
+#[test]
+fn mixed_node_types() {
+    let cluster = RegexCluster::new(
+        "test",
+        vec![
+            NodeType {
+                first_num: Some(3),
+                slot: Some(2),
+                ..NodeType::with_prefix("gug")
+            },
+            NodeType {
+                digits: Some(4),
+                board: Some(2),
+                ..NodeType::with_prefix("guc")
+            },
+        ],
+        Box::new(super::scheduler::FakeScheduler::new()),
+    );
+    assert!(!cluster.real_node("gug2"));
+    assert!(cluster.real_node("gug3"));
+    assert!(cluster.real_node("gug0100"));
+    assert!(cluster.real_node("guc0001"));
+    assert!(!cluster.real_node("guc1"));
+    // no board size means every node is its own card
+    assert_eq!(cluster.siblings("gug3"), vec!["gug3"]);
+    assert_eq!(cluster.cousins("gug3"), vec!["gug3", "gug4"]);
+    // no slot size falls back to the board size
+    assert_eq!(cluster.siblings("guc0004"), vec!["guc0003", "guc0004"]);
+    assert_eq!(cluster.cousins("guc0004"), vec!["guc0003", "guc0004"]);
+}
+
+#[test]
+fn unpadded_prefix_isnt_indexed() {
+    let cluster = RegexCluster::new(
+        "test",
+        vec![NodeType {
+            last_num: Some(8),
+            board: Some(2),
+            ..NodeType::with_prefix("gu")
+        }],
+        Box::new(super::scheduler::FakeScheduler::new()),
+    );
+    assert!(!cluster.indexed);
+    assert!(cluster.real_node("gu1"));
+    // any width matches without digits
+    assert!(cluster.real_node("gu01"));
+    assert!(!cluster.real_node("gu09"));
+    assert_eq!(cluster.siblings("gu03"), vec!["gu3", "gu4"]);
+}
+
+#[cfg(test)]
+fn pattern_type(pattern: &str) -> NodeType {
+    NodeType {
+        pattern: Some(pattern.to_string()),
+        first_num: Some(0),
+        ..NodeType::with_prefix("")
     }
 }
-*/
+
+#[test]
+fn pattern_node_types() {
+    // cray xnames, 2 nodes per card, 2 cards per blade and 8 blades per chassis
+    let xname = NodeType {
+        board: Some(2),
+        slot: Some(4),
+        chassis: Some(32),
+        ..pattern_type(r"x(?<rack>\d+)c(?<chassis>\d)s(?<blade>\d)b(?<card>\d)n(?<node>\d)")
+    };
+    // sgi, 36 nodes per iru numbered from 0 and 4 per blade
+    let sgi = NodeType {
+        slot: Some(4),
+        chassis: Some(36),
+        ..pattern_type(r"r(?<rack>\d+)i(?<chassis>\d)n(?<node>\d+)")
+    };
+    // nothing but a node number, zero padded to 3
+    let gpu = NodeType {
+        first_num: None,
+        board: Some(4),
+        ..pattern_type(r"gpu-(a100|h100)-(?<node>\d{3})")
+    };
+    let cluster = RegexCluster::new(
+        "test",
+        vec![xname, sgi, gpu],
+        Box::new(super::scheduler::FakeScheduler::new()),
+    );
+    assert!(cluster.real_node("x1000c2s3b1n0"));
+    assert!(cluster.real_node("r12i3n35"));
+    assert!(cluster.real_node("gpu-h100-004"));
+    assert!(!cluster.real_node("gpu-h100-000"));
+    assert!(!cluster.real_node("x1000c2s3b1"));
+
+    assert_eq!(
+        cluster.siblings("x1000c2s3b1n0"),
+        vec!["x1000c2s3b1n0", "x1000c2s3b1n1"]
+    );
+    assert_eq!(
+        cluster.cousins("x1000c2s3b1n0"),
+        vec![
+            "x1000c2s3b0n0",
+            "x1000c2s3b0n1",
+            "x1000c2s3b1n0",
+            "x1000c2s3b1n1"
+        ]
+    );
+    let chassis = cluster.chassis("x1000c2s3b1n0");
+    assert_eq!(chassis.len(), 32);
+    assert_eq!(chassis[0], "x1000c2s0b0n0");
+    assert_eq!(chassis[31], "x1000c2s7b1n1");
+
+    // no card capture or board size, every node is its own card
+    assert_eq!(cluster.siblings("r12i3n7"), vec!["r12i3n7"]);
+    assert_eq!(
+        cluster.cousins("r12i3n7"),
+        vec!["r12i3n4", "r12i3n5", "r12i3n6", "r12i3n7"]
+    );
+    assert_eq!(cluster.chassis("r12i3n7").len(), 36);
+    assert_eq!(cluster.chassis("r12i3n7")[35], "r12i3n35");
+
+    assert_eq!(
+        cluster.siblings("gpu-a100-006"),
+        vec![
+            "gpu-a100-005",
+            "gpu-a100-006",
+            "gpu-a100-007",
+            "gpu-a100-008"
+        ]
+    );
+}
+
+#[test]
+fn bad_patterns() {
+    assert!(check_node_types(&[pattern_type(r"dec(?<node>\d+)")]).is_ok());
+    assert_eq!(
+        check_node_types(&[pattern_type(r"dec(\d+)")]),
+        Err(r"pattern dec(\d+) has no node capture group".to_string())
+    );
+    assert!(check_node_types(&[pattern_type(r"dec(?<node>\d+")])
+        .unwrap_err()
+        .starts_with("bad pattern"));
+}
+
+#[test]
+fn index_matches_node_types() {
+    let node_type = |last: u32, board: u32| NodeType {
+        digits: Some(4),
+        last_num: Some(last),
+        board: Some(board),
+        ..NodeType::with_prefix("gu")
+    };
+    let cluster = RegexCluster::new(
+        "test",
+        vec![node_type(4, 2), node_type(8, 4)],
+        Box::new(super::scheduler::FakeScheduler::new()),
+    );
+    assert!(cluster.indexed);
+    assert_eq!(cluster.index.len(), 8);
+    // the first node type that matches wins
+    assert_eq!(cluster.siblings("gu0003"), vec!["gu0003", "gu0004"]);
+    assert_eq!(
+        cluster.siblings("gu0006"),
+        vec!["gu0005", "gu0006", "gu0007", "gu0008"]
+    );
+    assert!(!cluster.real_node("gu0009"));
+    assert!(!cluster.real_node("gu9"));
+}
+
+// #[bench] needs nightly's test crate, build these with `cargo bench -F bench`
+#[cfg(all(test, feature = "bench"))]
+mod benches {
+    use super::*;
+    use test::Bencher;
+
+    fn large_cluster() -> (RegexCluster, Vec<String>) {
+        let nodes = (1..=5000).map(|i| format!("dec{:0>4}", i)).collect();
+        let cluster = RegexCluster::new(
+            "large",
+            vec![NodeType {
+                digits: Some(4),
+                last_num: Some(5000),
+                board: Some(4),
+                slot: Some(8),
+                chassis: Some(64),
+                rack: Some(256),
+                ..NodeType::with_prefix("dec")
+            }],
+            Box::new(super::super::scheduler::FakeScheduler::new()),
+        );
+        (cluster, nodes)
+    }
+
+    #[bench]
+    fn large_cluster_lookups(b: &mut Bencher) {
+        let (cluster, nodes) = large_cluster();
+        b.iter(|| {
+            for n in &nodes {
+                test::black_box((
+                    cluster.real_node(n),
+                    cluster.siblings(n),
+                    cluster.cousins(n),
+                ));
+            }
+        });
+    }
+
+    #[bench]
+    fn large_cluster_misses(b: &mut Bencher) {
+        let (cluster, _) = large_cluster();
+        let misses: Vec<String> = (1..=5000).map(|i| format!("login{}", i)).collect();
+        b.iter(|| {
+            for n in &misses {
+                test::black_box(cluster.real_node(n));
+            }
+        });
+    }
+}
+
+#[tokio::test]
+async fn scheduler_calls() {
+    use super::scheduler::SchedulerCall;
+    use crate::entities::target::TargetStatus;
+    let sched = super::scheduler::FakeScheduler::with_nodes(&["gu0001".to_string()]);
+    let gust = gust(&sched);
+    sched.set_node("gu0002", TargetStatus::Down, "bad dimm");
+
+    let status = gust.nodes_status().await.unwrap();
+    assert_eq!(status.len(), 2);
+    let node = status.get("gu0002").unwrap();
+    assert_eq!(
+        (node.status, node.comment.as_str()),
+        (TargetStatus::Down, "bad dimm")
+    );
+
+    let offline = [("gu0001".to_string(), "testing".to_string())];
+    assert_eq!(
+        gust.offline_nodes(&offline).await,
+        vec![("gu0001".to_string(), Ok(()))]
+    );
+    assert_eq!(
+        sched.node("gu0001"),
+        Some((TargetStatus::Offline, "testing".to_string()))
+    );
+    assert_eq!(
+        gust.release_nodes(&["gu0001".to_string()]).await,
+        vec![("gu0001".to_string(), Ok(()))]
+    );
+    assert_eq!(
+        sched.node("gu0001"),
+        Some((TargetStatus::Online, "".to_string()))
+    );
+    assert_eq!(
+        sched.calls(),
+        vec![
+            SchedulerCall::Offline {
+                target: "gu0001".to_string(),
+                comment: "testing".to_string()
+            },
+            SchedulerCall::Release {
+                target: "gu0001".to_string()
+            },
+        ]
+    );
+}
@@ -0,0 +1,85 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Format of the comments ctt writes on nodes it offlines, eg `ctt#{id}: {title}`
+///
+/// `{id}` is replaced with the id of the issue the node is offline for and `{title}` with the
+/// comment for the node, usually the issue title. Comments that don't match the format were not
+/// written by ctt
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CommentFormat {
+    format: String,
+    re: Regex,
+}
+
+impl CommentFormat {
+    pub fn new(format: &str) -> Result<Self, String> {
+        if !format.contains("{id}") {
+            return Err(format!("comment format '{}' is missing {{id}}", format));
+        }
+        let re = regex::escape(format)
+            .replacen(r"\{id\}", r"(?P<id>\d+)", 1)
+            .replacen(r"\{title\}", "(?P<title>.*)", 1);
+        let re = Regex::new(&format!("(?s)^{}$", re)).map_err(|e| e.to_string())?;
+        Ok(Self {
+            format: format.to_string(),
+            re,
+        })
+    }
+
+    /// comment for a node offline because of issue `id`
+    pub fn render(&self, id: i32, title: &str) -> String {
+        self.format
+            .replacen("{id}", &id.to_string(), 1)
+            .replacen("{title}", title, 1)
+    }
+
+    /// issue id and title from a comment ctt wrote, None for any other comment
+    pub fn parse(&self, comment: &str) -> Option<(i32, String)> {
+        let caps = self.re.captures(comment)?;
+        let id = caps.name("id")?.as_str().parse().ok()?;
+        let title = caps.name("title").map(|t| t.as_str()).unwrap_or_default();
+        Some((id, title.to_string()))
+    }
+}
+
+impl Default for CommentFormat {
+    fn default() -> Self {
+        Self::new("ctt#{id}: {title}").unwrap()
+    }
+}
+
+impl TryFrom<String> for CommentFormat {
+    type Error = String;
+
+    fn try_from(format: String) -> Result<Self, Self::Error> {
+        Self::new(&format)
+    }
+}
+
+impl From<CommentFormat> for String {
+    fn from(format: CommentFormat) -> Self {
+        format.format
+    }
+}
+
+#[test]
+fn comment_format() {
+    let f = CommentFormat::default();
+    assert_eq!(f.render(123, "bad dimm"), "ctt#123: bad dimm");
+    assert_eq!(
+        f.parse("ctt#123: bad dimm"),
+        Some((123, "bad dimm".to_string()))
+    );
+    assert_eq!(f.parse("ctt#7: "), Some((7, "".to_string())));
+    assert_eq!(f.parse("bad dimm"), None);
+    assert_eq!(f.parse("ctt#abc: bad dimm"), None);
+
+    let f = CommentFormat::new("[{title}] (ctt {id})").unwrap();
+    let comment = f.render(5, "gu0005 sibling");
+    assert_eq!(comment, "[gu0005 sibling] (ctt 5)");
+    assert_eq!(f.parse(&comment), Some((5, "gu0005 sibling".to_string())));
+
+    assert!(CommentFormat::new("{title}").is_err());
+}
@@ -0,0 +1,313 @@
+use crate::entities::target::TargetStatus;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::instrument;
+use tracing::{info, warn};
+
+use super::{NodeSnapshot, SchedulerError, SchedulerTrait};
+
+/// In memory scheduler, for tests and local development
+///
+/// clones share the same state, so a test can keep a handle to change node states between syncs
+/// and check what ctt asked the scheduler to do after handing a clone to a cluster
+#[derive(Clone, Debug, Default)]
+pub struct FakeScheduler {
+    state: Arc<Mutex<FakeState>>,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    nodes: HashMap<String, NodeSnapshot>,
+    calls: Vec<SchedulerCall>,
+    // number of upcoming calls that should fail
+    failures: u32,
+    // nodes the scheduler won't let ctt change
+    denied: HashSet<String>,
+    // how long every call takes
+    delay: Duration,
+    // nodes in each reservation, keyed by reservation id
+    reservations: HashMap<String, Vec<String>>,
+}
+
+/// a state changing call made against the scheduler
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchedulerCall {
+    Offline { target: String, comment: String },
+    Release { target: String },
+    Reserve { name: String, targets: Vec<String> },
+    Unreserve { id: String },
+}
+
+impl FakeScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// scheduler with every node in `nodes` online
+    pub fn with_nodes(nodes: &[String]) -> Self {
+        let sched = Self::new();
+        for n in nodes {
+            sched.set_node(n, TargetStatus::Online, "");
+        }
+        sched
+    }
+
+    /// add a node, or overwrite the state and comment of an existing one
+    pub fn set_node(&self, target: &str, state: TargetStatus, comment: &str) {
+        self.set_snapshot(target, NodeSnapshot::new(state, &raw_state(state), comment));
+    }
+
+    /// add a node, or overwrite everything the scheduler reports about an existing one
+    pub fn set_snapshot(&self, target: &str, snapshot: NodeSnapshot) {
+        self.state
+            .lock()
+            .unwrap()
+            .nodes
+            .insert(target.to_string(), snapshot);
+    }
+
+    /// act like a real scheduler call, returns false if the call should fail
+    async fn call(&self) -> bool {
+        let delay = self.state.lock().unwrap().delay;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            false
+        } else {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+impl FakeScheduler {
+    pub fn remove_node(&self, target: &str) {
+        self.state.lock().unwrap().nodes.remove(target);
+    }
+
+    /// state and comment of a node
+    pub fn node(&self, target: &str) -> Option<(TargetStatus, String)> {
+        self.state
+            .lock()
+            .unwrap()
+            .nodes
+            .get(target)
+            .map(|n| (n.status, n.comment.clone()))
+    }
+
+    /// nodes in each current reservation, keyed by id
+    pub fn reservations(&self) -> HashMap<String, Vec<String>> {
+        self.state.lock().unwrap().reservations.clone()
+    }
+
+    /// every state changing call made so far, oldest first
+    pub fn calls(&self) -> Vec<SchedulerCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    pub fn clear_calls(&self) {
+        self.state.lock().unwrap().calls.clear();
+    }
+
+    /// make the next `n` calls, of any kind, fail
+    pub fn fail_calls(&self, n: u32) {
+        self.state.lock().unwrap().failures = n;
+    }
+
+    /// refuse every offline/release call for `target` until cleared
+    pub fn deny_node(&self, target: &str, deny: bool) {
+        let denied = &mut self.state.lock().unwrap().denied;
+        if deny {
+            denied.insert(target.to_string());
+        } else {
+            denied.remove(target);
+        }
+    }
+
+    /// make every call take `delay` before returning
+    pub fn set_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+}
+
+// injected failures look like a flaky connection, so they get retried
+fn fake_failure() -> SchedulerError {
+    SchedulerError::ConnectionFailed("fake failure".to_string())
+}
+
+// what the raw state string looks like for nodes set with just a TargetStatus
+fn raw_state(state: TargetStatus) -> String {
+    format!("{:?}", state).to_lowercase()
+}
+
+#[async_trait]
+impl SchedulerTrait for FakeScheduler {
+    #[instrument]
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        if !self.call().await {
+            return Err(fake_failure());
+        }
+        Ok(self.state.lock().unwrap().nodes.clone())
+    }
+
+    #[instrument]
+    async fn release_node(&self, target: &str) -> Result<(), SchedulerError> {
+        info!("resuming node {}", target);
+        let ok = self.call().await;
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(SchedulerCall::Release {
+            target: target.to_string(),
+        });
+        if !ok {
+            return Err(fake_failure());
+        }
+        if state.denied.contains(target) {
+            return Err(SchedulerError::PermissionDenied(target.to_string()));
+        }
+        match state.nodes.get_mut(target) {
+            Some(node) => {
+                // like pbs, releasing only clears offline, down nodes stay down
+                if node.status != TargetStatus::Down {
+                    node.status = TargetStatus::Online;
+                    node.state = raw_state(TargetStatus::Online);
+                }
+                node.comment = "".to_string();
+                Ok(())
+            }
+            None => {
+                warn!("Error resuming node {}: unknown node", target);
+                Err(SchedulerError::UnknownNode(target.to_string()))
+            }
+        }
+    }
+
+    #[instrument]
+    async fn offline_node(&self, target: &str, comment: &str) -> Result<(), SchedulerError> {
+        info!("offlining: {}, {}", target, comment);
+        let ok = self.call().await;
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(SchedulerCall::Offline {
+            target: target.to_string(),
+            comment: comment.to_string(),
+        });
+        if !ok {
+            return Err(fake_failure());
+        }
+        if state.denied.contains(target) {
+            return Err(SchedulerError::PermissionDenied(target.to_string()));
+        }
+        match state.nodes.get_mut(target) {
+            Some(node) => {
+                // there are never jobs running, so nodes go straight to offline
+                node.status = TargetStatus::Offline;
+                node.state = raw_state(TargetStatus::Offline);
+                node.comment = comment.to_string();
+                Ok(())
+            }
+            None => {
+                warn!("Error offlining node {}: unknown node", target);
+                Err(SchedulerError::UnknownNode(target.to_string()))
+            }
+        }
+    }
+
+    #[instrument]
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        info!("reserving {:?} from {} to {}", targets, start, end);
+        let ok = self.call().await;
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(SchedulerCall::Reserve {
+            name: name.to_string(),
+            targets: targets.to_vec(),
+        });
+        if !ok {
+            return Err(fake_failure());
+        }
+        if let Some(t) = targets.iter().find(|t| !state.nodes.contains_key(*t)) {
+            return Err(SchedulerError::UnknownNode(t.to_string()));
+        }
+        // ids are just the name, like slurm
+        state
+            .reservations
+            .insert(name.to_string(), targets.to_vec());
+        Ok(name.to_string())
+    }
+
+    #[instrument]
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        info!("deleting reservation {}", id);
+        let ok = self.call().await;
+        let mut state = self.state.lock().unwrap();
+        state
+            .calls
+            .push(SchedulerCall::Unreserve { id: id.to_string() });
+        if !ok {
+            return Err(fake_failure());
+        }
+        match state.reservations.remove(id) {
+            Some(_) => Ok(()),
+            None => Err(SchedulerError::Other(format!("no reservation {}", id))),
+        }
+    }
+}
+
+#[tokio::test]
+async fn fake_scheduler_calls() {
+    let sched = FakeScheduler::with_nodes(&["gu0001".to_string(), "gu0002".to_string()]);
+    let handle = sched.clone();
+    sched.set_node("gu0002", TargetStatus::Down, "bad dimm");
+
+    assert!(sched.offline_node("gu0001", "testing").await.is_ok());
+    assert!(sched.release_node("gu0002").await.is_ok());
+    assert_eq!(
+        sched.offline_node("gu0003", "not a node").await,
+        Err(SchedulerError::UnknownNode("gu0003".to_string()))
+    );
+
+    let status = sched.nodes_status().await.unwrap();
+    assert_eq!(
+        status.get("gu0001"),
+        Some(&NodeSnapshot::new(
+            TargetStatus::Offline,
+            "offline",
+            "testing"
+        ))
+    );
+    // release doesn't bring down nodes back up
+    assert_eq!(
+        status.get("gu0002"),
+        Some(&NodeSnapshot::new(TargetStatus::Down, "down", ""))
+    );
+    assert_eq!(
+        handle.calls(),
+        vec![
+            SchedulerCall::Offline {
+                target: "gu0001".to_string(),
+                comment: "testing".to_string()
+            },
+            SchedulerCall::Release {
+                target: "gu0002".to_string()
+            },
+            SchedulerCall::Offline {
+                target: "gu0003".to_string(),
+                comment: "not a node".to_string()
+            },
+        ]
+    );
+    handle.clear_calls();
+    handle.remove_node("gu0002");
+    assert!(sched.calls().is_empty());
+    assert_eq!(sched.node("gu0002"), None);
+}
@@ -0,0 +1,23 @@
+#!/bin/sh
+# status command for ScriptScheduler tests
+echo "checking 3 nodes" >&2
+cat <<'JSON'
+{
+  "nodes": [
+    {"name": "gu0001", "status": "online"},
+    {
+      "name": "gu0002",
+      "status": "draining",
+      "state": "job-busy,offline",
+      "comment": "bad dimm",
+      "jobs": ["1234.pbs"],
+      "ncpus": 128,
+      "ngpus": 4,
+      "mem": 262144,
+      "last_state_change": 1697040000,
+      "attributes": {"blade": "b1"}
+    },
+    {"name": "gu0003", "status": "provisioning"}
+  ]
+}
+JSON
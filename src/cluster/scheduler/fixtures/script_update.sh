@@ -0,0 +1,8 @@
+#!/bin/sh
+# offline/release command for ScriptScheduler tests, exit code depends on the node
+case "$1" in
+    gu9999) echo "no such node $1" >&2; exit 2 ;;
+    root*) echo "not allowed to touch $1" >&2; exit 3 ;;
+    gu0002) echo "something broke" >&2; exit 1 ;;
+    *) echo "updated $1" >&2; exit 0 ;;
+esac
@@ -1,11 +1,217 @@
+use crate::conf;
 use crate::entities::target::TargetStatus;
-use std::collections::HashMap;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Debug};
+use std::time::Duration;
 
-pub trait SchedulerTrait {
-    fn nodes_status(&self) -> Result<HashMap<String, (TargetStatus, String)>, String>;
-    fn release_node(&self, target: &str) -> Result<(), ()>;
-    fn offline_node(&self, target: &str, comment: &str) -> Result<(), ()>;
+#[async_trait]
+pub trait SchedulerTrait: Debug + Send + Sync {
+    /// snapshot of every node the scheduler knows about, keyed by node name
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError>;
+    async fn release_node(&self, target: &str) -> Result<(), SchedulerError>;
+    async fn offline_node(&self, target: &str, comment: &str) -> Result<(), SchedulerError>;
+    /// release several nodes, one failing doesn't stop the rest from being released
+    ///
+    /// backends that can change many nodes in one round trip should override this
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult {
+        let mut results = Vec::with_capacity(targets.len());
+        for t in targets {
+            results.push((t.clone(), self.release_node(t).await));
+        }
+        results
+    }
+    /// offline several nodes, each with its own comment
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult {
+        let mut results = Vec::with_capacity(targets.len());
+        for (t, comment) in targets {
+            results.push((t.clone(), self.offline_node(t, comment).await));
+        }
+        results
+    }
+    /// reserve `targets` for maintenance from `start` to `end` (utc) so jobs that would run into
+    /// the window aren't started on them, returns the id of the reservation
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        let _ = (name, targets, start, end);
+        Err(SchedulerError::Unsupported("reservations".to_string()))
+    }
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        let _ = id;
+        Err(SchedulerError::Unsupported("reservations".to_string()))
+    }
+    /// reconnect to the scheduler, only needed by backends that hold a connection open
+    fn refresh_conn(&self) {}
 }
 
+/// result of a batch call for each node, not necessarily in the order the nodes were given
+pub type BatchResult = Vec<(String, Result<(), SchedulerError>)>;
+
+/// group nodes that get the same comment so they can share a scheduler call
+fn by_comment(targets: &[(String, String)]) -> Vec<(String, Vec<String>)> {
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for (t, comment) in targets {
+        match groups.iter_mut().find(|(c, _)| c == comment) {
+            Some((_, nodes)) => nodes.push(t.clone()),
+            None => groups.push((comment.clone(), vec![t.clone()])),
+        }
+    }
+    groups
+}
+
+/// what the scheduler reported about a single node
+///
+/// fields a backend can't get from its scheduler are left empty
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeSnapshot {
+    /// nodes in a state the backend doesn't recognize are `TargetStatus::Unknown`
+    pub status: TargetStatus,
+    /// state exactly as the scheduler reported it, eg "down,offline"
+    pub state: String,
+    pub comment: String,
+    /// ids of the jobs running on the node
+    pub jobs: Vec<String>,
+    pub ncpus: Option<i32>,
+    pub ngpus: Option<i32>,
+    /// memory in MB
+    pub mem: Option<i64>,
+    /// utc
+    pub last_state_change: Option<NaiveDateTime>,
+    /// site specific node attributes, eg pbs custom resources or slurm `name:value` features,
+    /// used to discover the cluster topology
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl NodeSnapshot {
+    pub fn new(status: TargetStatus, state: &str, comment: &str) -> Self {
+        Self {
+            status,
+            state: state.to_string(),
+            comment: comment.to_string(),
+            jobs: vec![],
+            ncpus: None,
+            ngpus: None,
+            mem: None,
+            last_state_change: None,
+            attributes: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SchedulerError {
+    /// credentials used to talk to the scheduler have expired, reconnecting should fix it
+    CredentialExpired,
+    /// could not reach the scheduler
+    ConnectionFailed(String),
+    /// the scheduler doesn't know about the node
+    UnknownNode(String),
+    /// the scheduler refused the request
+    PermissionDenied(String),
+    /// the call didn't finish in time
+    Timeout,
+    /// the backend can't do this at all, eg reservations with the script backend
+    Unsupported(String),
+    /// anything else the scheduler complained about
+    Other(String),
+}
+
+impl SchedulerError {
+    /// is making the same call again likely to work
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::ConnectionFailed(_) | Self::Timeout | Self::Other(_)
+        )
+    }
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CredentialExpired => write!(f, "scheduler credentials have expired"),
+            Self::ConnectionFailed(e) => write!(f, "could not connect to scheduler: {}", e),
+            Self::UnknownNode(n) => write!(f, "scheduler doesn't know node {}", n),
+            Self::PermissionDenied(e) => write!(f, "permission denied: {}", e),
+            Self::Timeout => write!(f, "scheduler call timed out"),
+            Self::Unsupported(what) => write!(f, "scheduler doesn't support {}", what),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SchedulerError {}
+
+/// build the scheduler backend selected in the config
+///
+/// errors if the backend wasn't built into this cttd
+pub fn from_conf(conf: &conf::Scheduler) -> Result<Box<dyn SchedulerTrait>, String> {
+    let backend: Box<dyn SchedulerTrait> = match &conf.backend {
+        #[cfg(feature = "pbs")]
+        conf::SchedulerBackend::Pbs => Box::new(PbsScheduler::new(pbs::Server::new())),
+        #[cfg(not(feature = "pbs"))]
+        conf::SchedulerBackend::Pbs => {
+            return Err("cttd was built without the pbs feature".to_string())
+        }
+        conf::SchedulerBackend::Pbsnodes { pbsnodes } => match pbsnodes {
+            Some(p) => Box::new(PbsnodesScheduler::with_pbsnodes(p)),
+            None => Box::new(PbsnodesScheduler::new()),
+        },
+        conf::SchedulerBackend::Slurm { scontrol } => match scontrol {
+            Some(s) => Box::new(SlurmScheduler::with_scontrol(s)),
+            None => Box::new(SlurmScheduler::new()),
+        },
+        conf::SchedulerBackend::Script {
+            status,
+            offline,
+            release,
+        } => Box::new(ScriptScheduler::new(
+            status.clone(),
+            offline.clone(),
+            release.clone(),
+        )),
+        conf::SchedulerBackend::Fake { nodes } => Box::new(FakeScheduler::with_nodes(nodes)),
+    };
+    Ok(Box::new(RetryScheduler::new(
+        backend,
+        Duration::from_secs(conf.timeout),
+        conf.retries,
+    )))
+}
+
+#[cfg(not(feature = "pbs"))]
+#[test]
+fn pbs_without_feature() {
+    assert!(from_conf(&conf::Scheduler::default()).is_err());
+    let pbsnodes = conf::Scheduler {
+        backend: conf::SchedulerBackend::Pbsnodes { pbsnodes: None },
+        ..Default::default()
+    };
+    assert!(from_conf(&pbsnodes).is_ok());
+}
+
+mod comment_format;
+mod fake_scheduler;
+mod pbs_common;
+#[cfg(feature = "pbs")]
 mod pbs_scheduler;
+mod pbsnodes_scheduler;
+mod retry_scheduler;
+mod script_scheduler;
+mod slurm_scheduler;
+pub use comment_format::CommentFormat;
+pub use fake_scheduler::FakeScheduler;
+#[cfg(test)]
+pub use fake_scheduler::SchedulerCall;
+#[cfg(feature = "pbs")]
 pub use pbs_scheduler::PbsScheduler;
+pub use pbsnodes_scheduler::PbsnodesScheduler;
+pub use retry_scheduler::RetryScheduler;
+pub use script_scheduler::ScriptScheduler;
+pub use slurm_scheduler::SlurmScheduler;
@@ -0,0 +1,135 @@
+//! PBS state mapping and attribute parsing shared by the libpbs and pbsnodes backends
+use crate::entities::target::TargetStatus;
+use chrono::{DateTime, NaiveDateTime};
+
+use super::SchedulerError;
+
+/// classify the error text libpbs or pbsnodes gives back
+pub fn pbs_error(msg: &str, target: Option<&str>) -> SchedulerError {
+    let lower = msg.to_lowercase();
+    if lower.contains("expired credential") {
+        SchedulerError::CredentialExpired
+    } else if lower.contains("unknown node") {
+        SchedulerError::UnknownNode(target.unwrap_or_default().to_string())
+    } else if lower.contains("unauthorized request")
+        || lower.contains("access from host not allowed")
+    {
+        SchedulerError::PermissionDenied(msg.to_string())
+    } else if lower.contains("communication")
+        || lower.contains("connection")
+        || lower.contains("cannot connect")
+    {
+        SchedulerError::ConnectionFailed(msg.to_string())
+    } else {
+        SchedulerError::Other(msg.to_string())
+    }
+}
+
+/// job ids from a vnode's jobs attribute, eg "1234.pbs/0, 1234.pbs/1, 1235.pbs/0"
+pub fn parse_jobs(jobs: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for j in jobs.split(',').map(|j| j.trim()).filter(|j| !j.is_empty()) {
+        // one entry per cpu the job has on the node
+        let id = j.split('/').next().unwrap().to_string();
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// pbs size, eg "196608mb" or "256gb", in MB
+pub fn parse_mem(mem: &str) -> Option<i64> {
+    let mem = mem.to_lowercase();
+    let split = mem.find(|c: char| !c.is_ascii_digit()).unwrap_or(mem.len());
+    let (num, unit) = mem.split_at(split);
+    let num: i64 = num.parse().ok()?;
+    let kb = match unit {
+        "" | "b" => return Some(num / (1024 * 1024)),
+        "kb" => num,
+        "mb" => num * 1024,
+        "gb" => num * 1024 * 1024,
+        "tb" => num * 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    Some(kb / 1024)
+}
+
+pub fn parse_epoch(secs: &str) -> Option<NaiveDateTime> {
+    DateTime::from_timestamp(secs.parse().ok()?, 0).map(|d| d.naive_utc())
+}
+
+/// map a pbs vnode state, eg "down,offline", onto a TargetStatus
+pub fn node_state(state: &str, jobs: bool) -> TargetStatus {
+    match state {
+        //order matters, before "down" to capture down,offline nodes
+        x if x.contains("offline") => {
+            if jobs {
+                TargetStatus::Draining
+            } else {
+                TargetStatus::Offline
+            }
+        }
+        x if x.contains("down") => {
+            if jobs {
+                TargetStatus::Draining
+            } else {
+                TargetStatus::Down
+            }
+        }
+        //job-excl or resv-excl
+        x if x.contains("exclusive") => TargetStatus::Online,
+        "job-busy" => TargetStatus::Online,
+        "free" => TargetStatus::Online,
+        _ => TargetStatus::Unknown,
+    }
+}
+
+#[test]
+fn pbs_states() {
+    assert_eq!(node_state("free", false), TargetStatus::Online);
+    assert_eq!(node_state("job-busy", true), TargetStatus::Online);
+    assert_eq!(node_state("resv-exclusive", true), TargetStatus::Online);
+    assert_eq!(node_state("offline", true), TargetStatus::Draining);
+    assert_eq!(node_state("down,offline", false), TargetStatus::Offline);
+    assert_eq!(node_state("down", false), TargetStatus::Down);
+    assert_eq!(node_state("state-unknown", false), TargetStatus::Unknown);
+    assert_eq!(node_state("provisioning", true), TargetStatus::Unknown);
+}
+
+#[test]
+fn pbs_attributes() {
+    assert_eq!(
+        parse_jobs("1234.pbs/0, 1234.pbs/1, 1235.pbs/0"),
+        vec!["1234.pbs", "1235.pbs"]
+    );
+    assert!(parse_jobs("").is_empty());
+    assert_eq!(parse_mem("196608mb"), Some(196608));
+    assert_eq!(parse_mem("256gb"), Some(262144));
+    assert_eq!(parse_mem("1048576kb"), Some(1024));
+    assert_eq!(parse_mem("lots"), None);
+    assert_eq!(
+        parse_epoch("1697040000").unwrap().to_string(),
+        "2023-10-11 16:00:00"
+    );
+}
+
+#[test]
+fn pbs_errors() {
+    assert_eq!(
+        pbs_error("Expired credential", None),
+        SchedulerError::CredentialExpired
+    );
+    assert_eq!(
+        pbs_error("Unknown node ", Some("gu0001")),
+        SchedulerError::UnknownNode("gu0001".to_string())
+    );
+    assert!(matches!(
+        pbs_error("Unauthorized Request ", Some("gu0001")),
+        SchedulerError::PermissionDenied(_)
+    ));
+    assert!(matches!(
+        pbs_error("bad attribute", None),
+        SchedulerError::Other(_)
+    ));
+}
@@ -1,24 +1,155 @@
 use crate::entities::target::TargetStatus;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
 use core::fmt;
-use pbs::{Attrl, Op, Server};
+use pbs::{Attribs, Attrl, Op, ResvSubFlag, Server};
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+use tokio::task;
 use tracing::instrument;
 use tracing::{info, warn};
 
-use super::SchedulerTrait;
+use super::pbs_common::{node_state, parse_epoch, parse_jobs, parse_mem, pbs_error};
+use super::{BatchResult, NodeSnapshot, SchedulerError, SchedulerTrait};
 
 pub struct PbsScheduler {
-    srv: Server,
+    // libpbs calls block, so they are made from spawn_blocking tasks which take the connection's
+    // lock with them. The mutex keeps those tasks from using the connection concurrently, the
+    // outer lock lets a new connection be swapped in while the cluster is shared
+    srv: RwLock<Arc<Mutex<Server>>>,
 }
 
 impl PbsScheduler {
     pub fn new(srv: Server) -> Self {
-        Self { srv }
+        Self {
+            srv: RwLock::new(Arc::new(Mutex::new(srv))),
+        }
+    }
+
+    /// run a blocking libpbs call against the connection without stalling the runtime
+    ///
+    /// `target` is the node the call is about, if any, so unknown node errors can name it. Waits
+    /// for any other call using the connection, which counts against the caller's timeout, and
+    /// swaps in a new connection if the caller gives up on the call before it finishes
+    async fn blocking<T, F>(&self, target: Option<&str>, f: F) -> Result<T, SchedulerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Server) -> Result<T, String> + Send + 'static,
+    {
+        let srv = self.srv.read().unwrap().clone();
+        let mut abandoned = Abandoned(Some(self));
+        let resp = locked(srv, f).await;
+        abandoned.0 = None;
+        resp.map_err(|e| SchedulerError::Other(format!("pbs call failed: {}", e)))?
+            .map_err(|e| pbs_error(&e, target))
+    }
+
+    /// replace the connection, the old one is dropped once any call still using it returns
+    fn reconnect(&self) {
+        *self.srv.write().unwrap() = Arc::new(Mutex::new(Server::new()))
+    }
+}
+
+/// run `f` on `conn` from a blocking task once no other call is using it
+async fn locked<S, T, F>(conn: Arc<Mutex<S>>, f: F) -> Result<T, task::JoinError>
+where
+    S: Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&S) -> T + Send + 'static,
+{
+    let conn = conn.lock_owned().await;
+    task::spawn_blocking(move || f(&conn)).await
+}
+
+/// reconnects if dropped while still holding the scheduler, ie the future of a call was dropped
+/// because it timed out. The hung call keeps the old connection so later calls don't queue up
+/// behind it
+struct Abandoned<'a>(Option<&'a PbsScheduler>);
+
+impl Drop for Abandoned<'_> {
+    fn drop(&mut self) {
+        if let Some(sched) = self.0 {
+            warn!("pbs call abandoned, reconnecting");
+            sched.reconnect();
+        }
+    }
+}
+
+/// per node results of libpbs calls made in one blocking task
+type NodeResults = Vec<(String, Result<(), String>)>;
+
+/// turn the per node libpbs results from a batch into scheduler errors, every node gets the error
+/// if the batch itself failed
+fn batch_result(targets: &[String], resp: Result<NodeResults, SchedulerError>) -> BatchResult {
+    match resp {
+        Ok(results) => results
+            .into_iter()
+            .map(|(n, r)| {
+                let r = r.map_err(|e| pbs_error(&e, Some(&n)));
+                if let Err(e) = &r {
+                    warn!("Error updating node {}: {}", n, e);
+                }
+                (n, r)
+            })
+            .collect(),
+        Err(e) => targets
+            .iter()
+            .map(|t| (t.clone(), Err(e.clone())))
+            .collect(),
     }
+}
 
-    pub fn refresh_conn(&mut self) {
-        self.srv = Server::new()
+fn stat_nodes(srv: &Server) -> Result<HashMap<String, NodeSnapshot>, String> {
+    //TODO filter stat attribs
+    let mut resp = HashMap::new();
+    let vnode_stat = srv.stat_vnode(&None, None);
+    if let Err(e) = vnode_stat {
+        warn!("error statting vnode: {}", e);
+        return Err(e);
     }
+    for n in vnode_stat.unwrap().resources.iter() {
+        let name = n.name();
+        let value = |attr: &str| match n.attribs().get(attr) {
+            Some(Attrl::Value(Op::Default(v))) => Some(v.as_str()),
+            _ => None,
+        };
+        let available = |res: &str| match n.attribs().get("resources_available") {
+            Some(Attrl::Resource(r)) => match r.get(res) {
+                Some(Op::Default(v)) => Some(v.as_str()),
+                _ => None,
+            },
+            _ => None,
+        };
+        let jobs = value("jobs").map(parse_jobs).unwrap_or_default();
+        let Some(state) = value("state") else {
+            let raw = format!("{:?}", n.attribs().get("state"));
+            warn!("unexpected state attribute for {}, {}", name, raw);
+            let mut snap = NodeSnapshot::new(TargetStatus::Unknown, &raw, "");
+            snap.jobs = jobs;
+            resp.insert(name, snap);
+            continue;
+        };
+        let status = node_state(state, !jobs.is_empty());
+        if status == TargetStatus::Unknown {
+            warn!("unrecognized node state for {}, '{}'", name, state);
+        }
+        let mut snap = NodeSnapshot::new(status, state, value("comment").unwrap_or_default());
+        snap.jobs = jobs;
+        snap.ncpus = available("ncpus").and_then(|c| c.parse().ok());
+        snap.ngpus = available("ngpus").and_then(|c| c.parse().ok());
+        snap.mem = available("mem").and_then(parse_mem);
+        snap.last_state_change = value("last_state_change_time").and_then(parse_epoch);
+        if let Some(Attrl::Resource(r)) = n.attribs().get("resources_available") {
+            for (res, v) in r {
+                if let Op::Default(v) = v {
+                    snap.attributes.insert(res.clone(), v.clone());
+                }
+            }
+        }
+        resp.insert(name, snap);
+    }
+    Ok(resp)
 }
 
 impl fmt::Debug for PbsScheduler {
@@ -27,95 +158,133 @@ impl fmt::Debug for PbsScheduler {
     }
 }
 
+#[async_trait]
 impl SchedulerTrait for PbsScheduler {
     #[instrument]
-    fn nodes_status(&self) -> Result<HashMap<String, (TargetStatus, String)>, String> {
-        //TODO filter stat attribs (just need hostname, jobs, and state)
-        //TODO consider calling pbs_srv.stat_vnode from a spawn_blocking task
-        //TODO add a timeout
-        let mut resp = HashMap::new();
-        let vnode_stat = self.srv.stat_vnode(&None, None);
-        if let Err(e) = vnode_stat {
-            warn!("error statting vnode: {}", e);
-            return Err(e);
-        }
-        for n in vnode_stat.unwrap().resources.iter() {
-            let name = n.name();
-            let jobs = {
-                if let Some(Attrl::Value(Op::Default(j))) = n.attribs().get("jobs") {
-                    !j.is_empty()
-                } else {
-                    false
-                }
-            };
-            #[allow(clippy::manual_unwrap_or_default)]
-            let comment =
-                if let Some(pbs::Attrl::Value(pbs::Op::Default(c))) = n.attribs().get("comment") {
-                    c
-                } else {
-                    ""
-                };
-            // vnodes always have a state attrib so the unwrap is safe
-            let state = match n.attribs().get("state").unwrap() {
-                Attrl::Value(Op::Default(j)) => j,
-                x => {
-                    println!("{:?}", x);
-                    panic!("bad state");
-                }
-            };
-            let state = match state.as_str() {
-                //order matters, before "down" to capture down,offline nodes
-                x if x.contains("offline") => {
-                    if jobs {
-                        TargetStatus::Draining
-                    } else {
-                        TargetStatus::Offline
-                    }
-                }
-                x if x.contains("down") => {
-                    if jobs {
-                        TargetStatus::Draining
-                    } else {
-                        TargetStatus::Down
-                    }
-                }
-                //job-excl or resv-excl
-                x if x.contains("exclusive") => TargetStatus::Online,
-                "job-busy" => TargetStatus::Online,
-                "free" => TargetStatus::Online,
-                x => {
-                    warn!("unrecognized node state, '{}'", x);
-                    //TODO should we really offline nodes randomly while checking node status?
-                    if let Err(e) = self.srv.offline_vnode(&name, None) {
-                        warn!("Error offlining node {}: {}", name, e);
-                    }
-                    if jobs {
-                        TargetStatus::Draining
-                    } else {
-                        TargetStatus::Down
-                    }
-                }
-            };
-            resp.insert(name, (state, comment.to_string()));
-        }
-        Ok(resp)
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        self.blocking(None, stat_nodes).await
     }
 
     #[instrument]
-    fn release_node(&self, target: &str) -> Result<(), ()> {
+    async fn release_node(&self, target: &str) -> Result<(), SchedulerError> {
         info!("resuming node {}", target);
-        if self.srv.clear_vnode(target, Some("")).is_err() {
-            return Err(());
+        let t = target.to_string();
+        if let Err(e) = self
+            .blocking(Some(target), move |srv| srv.clear_vnode(&t, Some("")))
+            .await
+        {
+            warn!("Error resuming node {}: {}", target, e);
+            return Err(e);
         }
         Ok(())
     }
 
     #[instrument]
-    fn offline_node(&self, target: &str, comment: &str) -> Result<(), ()> {
+    async fn offline_node(&self, target: &str, comment: &str) -> Result<(), SchedulerError> {
         info!("offlining: {}, {}", target, comment);
-        if self.srv.offline_vnode(target, Some(comment)).is_err() {
-            return Err(());
+        let (t, c) = (target.to_string(), comment.to_string());
+        if let Err(e) = self
+            .blocking(Some(target), move |srv| srv.offline_vnode(&t, Some(&c)))
+            .await
+        {
+            warn!("Error offlining node {}: {}", target, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult {
+        info!("resuming nodes {}", targets.join(","));
+        let t = targets.to_vec();
+        let resp = self
+            .blocking(None, move |srv| {
+                Ok(t.into_iter()
+                    .map(|n| {
+                        let r = srv.clear_vnode(&n, Some(""));
+                        (n, r)
+                    })
+                    .collect())
+            })
+            .await;
+        batch_result(targets, resp)
+    }
+
+    #[instrument]
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult {
+        info!("offlining nodes {:?}", targets);
+        let t = targets.to_vec();
+        let resp = self
+            .blocking(None, move |srv| {
+                Ok(t.into_iter()
+                    .map(|(n, c)| {
+                        let r = srv.offline_vnode(&n, Some(&c));
+                        (n, r)
+                    })
+                    .collect())
+            })
+            .await;
+        let names: Vec<String> = targets.iter().map(|(t, _)| t.clone()).collect();
+        batch_result(&names, resp)
+    }
+
+    #[instrument]
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        info!("reserving {} from {} to {}", targets.join(","), start, end);
+        let attribs = vec![
+            format!("Reserve_Name={}", name),
+            format!("reserve_start={}", start.and_utc().timestamp()),
+            format!("reserve_end={}", end.and_utc().timestamp()),
+            format!(
+                "Resource_List.select={}",
+                targets
+                    .iter()
+                    .map(|t| format!("host={}", t))
+                    .collect::<Vec<_>>()
+                    .join("+")
+            ),
+        ];
+        let resp = self
+            .blocking(None, move |srv| {
+                srv.submit_resv(Attribs::from(&attribs), vec![ResvSubFlag::Maintenance])
+            })
+            .await;
+        if let Err(e) = &resp {
+            warn!("Error creating reservation {}: {}", name, e);
+        }
+        resp
+    }
+
+    #[instrument]
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        info!("deleting reservation {}", id);
+        let i = id.to_string();
+        if let Err(e) = self.blocking(None, move |srv| srv.del_resv(&i)).await {
+            warn!("Error deleting reservation {}: {}", id, e);
+            return Err(e);
         }
         Ok(())
     }
+
+    fn refresh_conn(&self) {
+        self.reconnect()
+    }
+}
+
+#[tokio::test]
+async fn concurrent_calls_wait_for_the_connection() {
+    let conn = Arc::new(Mutex::new(0));
+    let slow = locked(conn.clone(), |n: &i32| {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        *n + 1
+    });
+    let fast = locked(conn.clone(), |n: &i32| *n + 2);
+    let (slow, fast) = tokio::join!(slow, fast);
+    assert_eq!((slow.unwrap(), fast.unwrap()), (1, 2));
 }
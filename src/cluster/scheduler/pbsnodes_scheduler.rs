@@ -0,0 +1,349 @@
+use crate::entities::target::TargetStatus;
+use async_trait::async_trait;
+use chrono::{Local, NaiveDateTime, TimeZone};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::process::Command;
+use tracing::instrument;
+use tracing::{info, warn};
+
+use super::pbs_common::{node_state, parse_epoch, parse_jobs, parse_mem, pbs_error};
+use super::{by_comment, BatchResult, NodeSnapshot, SchedulerError, SchedulerTrait};
+
+/// PBS backend that shells out to `pbsnodes` instead of linking libpbs
+///
+/// every call is a new pbsnodes process, so there is no connection to refresh
+#[derive(Debug)]
+pub struct PbsnodesScheduler {
+    pbsnodes: String,
+    pbs_rsub: String,
+    pbs_rdel: String,
+}
+
+impl PbsnodesScheduler {
+    pub fn new() -> Self {
+        Self::with_pbsnodes("pbsnodes")
+    }
+
+    /// use a specific pbsnodes binary instead of the one found in $PATH
+    ///
+    /// pbs_rsub and pbs_rdel are expected next to it
+    pub fn with_pbsnodes(pbsnodes: &str) -> Self {
+        let sibling = |cmd: &str| {
+            if pbsnodes.contains('/') {
+                Path::new(pbsnodes)
+                    .with_file_name(cmd)
+                    .to_string_lossy()
+                    .to_string()
+            } else {
+                cmd.to_string()
+            }
+        };
+        Self {
+            pbsnodes: pbsnodes.to_string(),
+            pbs_rsub: sibling("pbs_rsub"),
+            pbs_rdel: sibling("pbs_rdel"),
+        }
+    }
+
+    /// `target` is the node the call is about, if any, so unknown node errors can name it
+    async fn pbsnodes(
+        &self,
+        args: &[&str],
+        target: Option<&str>,
+    ) -> Result<String, SchedulerError> {
+        self.run(&self.pbsnodes, args, target).await
+    }
+
+    async fn run(
+        &self,
+        cmd: &str,
+        args: &[&str],
+        target: Option<&str>,
+    ) -> Result<String, SchedulerError> {
+        let out = Command::new(cmd)
+            .args(args)
+            // don't leave the command running if the call times out
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| SchedulerError::Other(format!("could not run {}: {}", cmd, e)))?;
+        if !out.status.success() {
+            return Err(pbs_error(
+                String::from_utf8_lossy(&out.stderr).trim(),
+                target,
+            ));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).to_string())
+    }
+}
+
+/// pbs_rsub reads times in the local timezone
+fn rsub_time(t: &NaiveDateTime) -> String {
+    Local
+        .from_utc_datetime(t)
+        .format("%Y%m%d%H%M.%S")
+        .to_string()
+}
+
+impl Default for PbsnodesScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// subset of `pbsnodes -av -F json` output ctt cares about
+#[derive(Deserialize, Debug)]
+struct Pbsnodes {
+    nodes: HashMap<String, PbsNode>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PbsNode {
+    state: String,
+    #[serde(default)]
+    comment: String,
+    // a list of "<job>/<cpu>" on recent versions, a comma separated string on older ones
+    #[serde(default)]
+    jobs: Value,
+    #[serde(default)]
+    resources_available: HashMap<String, Value>,
+    #[serde(default)]
+    last_state_change_time: Option<Value>,
+}
+
+// pbsnodes prints some numbers as strings, and some strings as numbers
+fn value_str(v: &Value) -> Option<String> {
+    match v {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+impl PbsNode {
+    fn jobs(&self) -> Vec<String> {
+        let jobs = match &self.jobs {
+            Value::Array(a) => a.iter().filter_map(value_str).collect::<Vec<_>>().join(","),
+            v => value_str(v).unwrap_or_default(),
+        };
+        parse_jobs(&jobs)
+    }
+
+    fn available(&self, res: &str) -> Option<String> {
+        self.resources_available.get(res).and_then(value_str)
+    }
+
+    fn snapshot(&self, name: &str) -> NodeSnapshot {
+        let jobs = self.jobs();
+        let status = node_state(&self.state, !jobs.is_empty());
+        if status == TargetStatus::Unknown {
+            warn!("unrecognized node state for {}, '{}'", name, self.state);
+        }
+        NodeSnapshot {
+            status,
+            state: self.state.clone(),
+            comment: self.comment.clone(),
+            jobs,
+            ncpus: self.available("ncpus").and_then(|c| c.parse().ok()),
+            ngpus: self.available("ngpus").and_then(|c| c.parse().ok()),
+            mem: self.available("mem").and_then(|m| parse_mem(&m)),
+            last_state_change: self
+                .last_state_change_time
+                .as_ref()
+                .and_then(value_str)
+                .and_then(|t| parse_epoch(&t)),
+            attributes: self
+                .resources_available
+                .iter()
+                .filter_map(|(res, v)| Some((res.clone(), value_str(v)?)))
+                .collect(),
+        }
+    }
+}
+
+fn parse_nodes(json: &str) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+    let resp: Pbsnodes = serde_json::from_str(json)
+        .map_err(|e| SchedulerError::Other(format!("error parsing pbsnodes output: {}", e)))?;
+    Ok(resp
+        .nodes
+        .iter()
+        .map(|(name, n)| (name.clone(), n.snapshot(name)))
+        .collect())
+}
+
+#[async_trait]
+impl SchedulerTrait for PbsnodesScheduler {
+    #[instrument]
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        let out = self.pbsnodes(&["-av", "-F", "json"], None).await;
+        if let Err(e) = out {
+            warn!("error getting node state: {}", e);
+            return Err(e);
+        }
+        parse_nodes(&out.unwrap())
+    }
+
+    #[instrument]
+    async fn release_node(&self, target: &str) -> Result<(), SchedulerError> {
+        info!("resuming node {}", target);
+        if let Err(e) = self.pbsnodes(&["-r", "-C", "", target], Some(target)).await {
+            warn!("Error resuming node {}: {}", target, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    async fn offline_node(&self, target: &str, comment: &str) -> Result<(), SchedulerError> {
+        info!("offlining: {}, {}", target, comment);
+        if let Err(e) = self
+            .pbsnodes(&["-o", "-C", comment, target], Some(target))
+            .await
+        {
+            warn!("Error offlining node {}: {}", target, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult {
+        if let [t] = targets {
+            return vec![(t.clone(), self.release_node(t).await)];
+        }
+        info!("resuming nodes {}", targets.join(","));
+        let mut args = vec!["-r", "-C", ""];
+        args.extend(targets.iter().map(|t| t.as_str()));
+        match self.pbsnodes(&args, None).await {
+            Ok(_) => targets.iter().map(|t| (t.clone(), Ok(()))).collect(),
+            // pbsnodes doesn't say which node it choked on
+            Err(e) => {
+                warn!("Error resuming nodes: {}, trying one at a time", e);
+                let mut results = Vec::with_capacity(targets.len());
+                for t in targets {
+                    results.push((t.clone(), self.release_node(t).await));
+                }
+                results
+            }
+        }
+    }
+
+    #[instrument]
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult {
+        let mut results = Vec::with_capacity(targets.len());
+        for (comment, group) in by_comment(targets) {
+            if let [t] = group.as_slice() {
+                results.push((t.clone(), self.offline_node(t, &comment).await));
+                continue;
+            }
+            info!("offlining: {}, {}", group.join(","), comment);
+            let mut args = vec!["-o", "-C", &comment];
+            args.extend(group.iter().map(|t| t.as_str()));
+            match self.pbsnodes(&args, None).await {
+                Ok(_) => results.extend(group.into_iter().map(|t| (t, Ok(())))),
+                Err(e) => {
+                    warn!("Error offlining nodes: {}, trying one at a time", e);
+                    for t in group {
+                        let r = self.offline_node(&t, &comment).await;
+                        results.push((t, r));
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    #[instrument]
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        info!("reserving {} from {} to {}", targets.join(","), start, end);
+        let (start, end) = (rsub_time(&start), rsub_time(&end));
+        let mut args = vec!["-N", name, "-R", &start, "-E", &end, "--hosts"];
+        args.extend(targets.iter().map(|t| t.as_str()));
+        match self.run(&self.pbs_rsub, &args, None).await {
+            // prints the id followed by the reservation state, eg "M1234.pbs CONFIRMED"
+            Ok(out) => match out.split_whitespace().next() {
+                Some(id) => Ok(id.to_string()),
+                None => Err(SchedulerError::Other(format!(
+                    "no reservation id from pbs_rsub for {}",
+                    name
+                ))),
+            },
+            Err(e) => {
+                warn!("Error creating reservation {}: {}", name, e);
+                Err(e)
+            }
+        }
+    }
+
+    #[instrument]
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        info!("deleting reservation {}", id);
+        if let Err(e) = self.run(&self.pbs_rdel, &[id], None).await {
+            warn!("Error deleting reservation {}: {}", id, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn parse_pbsnodes_json() {
+    let nodes = parse_nodes(include_str!("fixtures/pbsnodes_av.json")).unwrap();
+    let expected = vec![
+        ("gu0001", TargetStatus::Online, ""),
+        ("gu0002", TargetStatus::Online, ""),
+        ("gu0003", TargetStatus::Draining, "bad dimm"),
+        ("gu0004", TargetStatus::Offline, "replacing blade"),
+        (
+            "gu0005",
+            TargetStatus::Down,
+            "node down: communication closed",
+        ),
+        ("gu0006", TargetStatus::Unknown, ""),
+    ];
+    assert_eq!(nodes.len(), expected.len());
+    for (name, state, comment) in expected {
+        let actual = nodes.get(name).unwrap();
+        assert_eq!(
+            (actual.status, actual.comment.as_str()),
+            (state, comment),
+            "for {}: {:?}",
+            name,
+            actual
+        );
+    }
+}
+
+#[test]
+fn pbsnodes_snapshot() {
+    let nodes = parse_nodes(include_str!("fixtures/pbsnodes_av.json")).unwrap();
+    let gu0001 = nodes.get("gu0001").unwrap();
+    assert_eq!(gu0001.state, "free");
+    assert!(gu0001.jobs.is_empty());
+    assert_eq!(gu0001.ncpus, Some(128));
+    assert_eq!(gu0001.ngpus, Some(4));
+    assert_eq!(gu0001.mem, Some(257363));
+    assert_eq!(gu0001.attributes["blade"], "b1");
+    assert_eq!(gu0001.attributes["ncpus"], "128");
+    assert_eq!(
+        gu0001.last_state_change.unwrap().to_string(),
+        "2023-10-11 16:00:00"
+    );
+    assert_eq!(
+        nodes.get("gu0002").unwrap().jobs,
+        vec!["1234.gusched01", "1235.gusched01"]
+    );
+    assert_eq!(nodes.get("gu0003").unwrap().jobs, vec!["1236.gusched01"]);
+    assert_eq!(nodes.get("gu0003").unwrap().ngpus, None);
+    assert_eq!(nodes.get("gu0006").unwrap().state, "provisioning");
+    assert!(parse_nodes("not json").is_err());
+}
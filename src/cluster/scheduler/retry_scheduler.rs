@@ -0,0 +1,294 @@
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time;
+use tracing::instrument;
+use tracing::warn;
+
+use super::{BatchResult, NodeSnapshot, SchedulerError, SchedulerTrait};
+
+/// Wraps another scheduler, giving every call a timeout and retrying calls that failed for
+/// transient reasons
+///
+/// a hung scheduler can only ever hold up a caller for about `(retries + 1) * timeout`
+#[derive(Debug)]
+pub struct RetryScheduler {
+    sched: Box<dyn SchedulerTrait>,
+    timeout: Duration,
+    retries: u32,
+    // wait between attempts
+    delay: Duration,
+}
+
+impl RetryScheduler {
+    pub fn new(sched: Box<dyn SchedulerTrait>, timeout: Duration, retries: u32) -> Self {
+        Self {
+            sched,
+            timeout,
+            retries,
+            delay: Duration::from_secs(1),
+        }
+    }
+
+    async fn retry<T, F, Fut>(&self, call: &str, f: F) -> Result<T, SchedulerError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, SchedulerError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let resp = match time::timeout(self.timeout, f()).await {
+                Ok(r) => r,
+                Err(_) => {
+                    warn!("{} timed out after {:?}", call, self.timeout);
+                    Err(SchedulerError::Timeout)
+                }
+            };
+            match resp {
+                Err(e) if e.is_transient() && attempt < self.retries => {
+                    attempt += 1;
+                    warn!(
+                        "{} failed: {}, retrying ({}/{})",
+                        call, e, attempt, self.retries
+                    );
+                    time::sleep(self.delay).await;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// like `retry` but only the nodes that failed for transient reasons are tried again
+    ///
+    /// `name` gets the node name out of an item in `targets`, results are in the same order as
+    /// `targets`
+    async fn retry_batch<A, N, F, Fut>(
+        &self,
+        call: &str,
+        targets: &[A],
+        name: N,
+        f: F,
+    ) -> BatchResult
+    where
+        A: Clone,
+        N: Fn(&A) -> &str,
+        F: Fn(Vec<A>) -> Fut,
+        Fut: Future<Output = BatchResult>,
+    {
+        let mut results: HashMap<String, Result<(), SchedulerError>> = HashMap::new();
+        let mut pending = targets.to_vec();
+        let mut attempt = 0;
+        while !pending.is_empty() {
+            let resp = match time::timeout(self.timeout, f(pending.clone())).await {
+                Ok(r) => r,
+                Err(_) => {
+                    warn!("{} timed out after {:?}", call, self.timeout);
+                    pending
+                        .iter()
+                        .map(|t| (name(t).to_string(), Err(SchedulerError::Timeout)))
+                        .collect()
+                }
+            };
+            results.extend(resp);
+            if attempt >= self.retries {
+                break;
+            }
+            pending.retain(|t| match results.get(name(t)) {
+                Some(Ok(())) => false,
+                Some(Err(e)) => e.is_transient(),
+                // backend didn't report on the node, try it again
+                None => true,
+            });
+            if !pending.is_empty() {
+                attempt += 1;
+                warn!(
+                    "{} failed for {} nodes, retrying ({}/{})",
+                    call,
+                    pending.len(),
+                    attempt,
+                    self.retries
+                );
+                time::sleep(self.delay).await;
+            }
+        }
+        targets
+            .iter()
+            .map(|t| {
+                let n = name(t).to_string();
+                let r = results
+                    .remove(&n)
+                    .unwrap_or_else(|| Err(SchedulerError::Other(format!("no result for {}", n))));
+                (n, r)
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl SchedulerTrait for RetryScheduler {
+    #[instrument]
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        self.retry("nodes_status", || self.sched.nodes_status())
+            .await
+    }
+
+    #[instrument]
+    async fn release_node(&self, target: &str) -> Result<(), SchedulerError> {
+        self.retry("release_node", || self.sched.release_node(target))
+            .await
+    }
+
+    #[instrument]
+    async fn offline_node(&self, target: &str, comment: &str) -> Result<(), SchedulerError> {
+        self.retry("offline_node", || self.sched.offline_node(target, comment))
+            .await
+    }
+
+    #[instrument]
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult {
+        self.retry_batch(
+            "release_nodes",
+            targets,
+            |t| t.as_str(),
+            |t| async move { self.sched.release_nodes(&t).await },
+        )
+        .await
+    }
+
+    #[instrument]
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult {
+        self.retry_batch(
+            "offline_nodes",
+            targets,
+            |(t, _)| t.as_str(),
+            |t| async move { self.sched.offline_nodes(&t).await },
+        )
+        .await
+    }
+
+    #[instrument]
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        // a create that timed out may still have gone through, so it isn't retried to avoid
+        // leaving duplicate reservations behind
+        match time::timeout(
+            self.timeout,
+            self.sched.create_reservation(name, targets, start, end),
+        )
+        .await
+        {
+            Ok(r) => r,
+            Err(_) => {
+                warn!("create_reservation timed out after {:?}", self.timeout);
+                Err(SchedulerError::Timeout)
+            }
+        }
+    }
+
+    #[instrument]
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        self.retry("delete_reservation", || self.sched.delete_reservation(id))
+            .await
+    }
+
+    fn refresh_conn(&self) {
+        self.sched.refresh_conn()
+    }
+}
+
+#[cfg(test)]
+fn retrying(sched: &super::FakeScheduler, retries: u32) -> RetryScheduler {
+    RetryScheduler {
+        sched: Box::new(sched.clone()),
+        timeout: Duration::from_millis(100),
+        retries,
+        delay: Duration::ZERO,
+    }
+}
+
+#[tokio::test]
+async fn retries_failed_calls() {
+    let sched = super::FakeScheduler::with_nodes(&["gu0001".to_string()]);
+    let retry = retrying(&sched, 2);
+
+    sched.fail_calls(2);
+    assert!(retry.nodes_status().await.is_ok());
+    sched.fail_calls(2);
+    assert!(retry.offline_node("gu0001", "testing").await.is_ok());
+    // failed attempts still reach the scheduler
+    assert_eq!(sched.calls().len(), 3);
+
+    sched.fail_calls(3);
+    assert!(retry.release_node("gu0001").await.is_err());
+    assert_eq!(sched.calls().len(), 6);
+}
+
+#[tokio::test]
+async fn doesnt_retry_permanent_errors() {
+    let sched = super::FakeScheduler::with_nodes(&["gu0001".to_string()]);
+    let retry = retrying(&sched, 2);
+
+    assert_eq!(
+        retry.offline_node("gu0002", "testing").await,
+        Err(SchedulerError::UnknownNode("gu0002".to_string()))
+    );
+    assert_eq!(sched.calls().len(), 1);
+}
+
+#[tokio::test]
+async fn times_out_hung_calls() {
+    let sched = super::FakeScheduler::with_nodes(&["gu0001".to_string()]);
+    let retry = retrying(&sched, 1);
+    sched.set_delay(Duration::from_secs(60));
+
+    let start = time::Instant::now();
+    assert_eq!(retry.nodes_status().await, Err(SchedulerError::Timeout));
+    assert_eq!(
+        retry.offline_node("gu0001", "testing").await,
+        Err(SchedulerError::Timeout)
+    );
+    // 2 calls, each with 2 attempts of 100ms
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn retries_failed_nodes_in_batch() {
+    let sched = super::FakeScheduler::with_nodes(&["gu0001".to_string(), "gu0002".to_string()]);
+    let retry = retrying(&sched, 2);
+    let targets = vec![
+        ("gu0001".to_string(), "testing".to_string()),
+        ("gu0002".to_string(), "testing".to_string()),
+        ("gu0003".to_string(), "testing".to_string()),
+    ];
+
+    // first attempt fails for gu0001, only it is tried again
+    sched.fail_calls(1);
+    let results = retry.offline_nodes(&targets).await;
+    assert_eq!(
+        results,
+        vec![
+            ("gu0001".to_string(), Ok(())),
+            ("gu0002".to_string(), Ok(())),
+            (
+                "gu0003".to_string(),
+                Err(SchedulerError::UnknownNode("gu0003".to_string()))
+            ),
+        ]
+    );
+    assert_eq!(sched.calls().len(), 4);
+
+    sched.clear_calls();
+    sched.set_delay(Duration::from_secs(60));
+    let results = retry.release_nodes(&["gu0001".to_string()]).await;
+    assert_eq!(
+        results,
+        vec![("gu0001".to_string(), Err(SchedulerError::Timeout))]
+    );
+}
@@ -0,0 +1,289 @@
+//! Scheduler backend that runs site provided executables
+//!
+//! each command is configured as an argv list, ctt appends its own arguments
+//! - `status` gets no arguments and prints the state of every node to stdout as json
+//!   ```json
+//!   {"nodes": [{"name": "gu0001", "status": "offline", "state": "down,offline",
+//!     "comment": "bad dimm", "jobs": ["1234.pbs"], "ncpus": 128, "ngpus": 4,
+//!     "mem": 262144, "last_state_change": 1697040000, "attributes": {"blade": "b1"}}]}
+//!   ```
+//!   `name` and `status` (online, draining, offline or down) are required, any other status is
+//!   treated as unknown. `state` is the raw scheduler state and defaults to `status`, `mem` is in
+//!   MB, `last_state_change` is seconds since the epoch and `attributes` is a map of strings
+//! - `offline` gets the node name and the comment to set on it
+//! - `release` gets the node name
+//!
+//! commands exit 0 on success, otherwise the exit code says what went wrong
+//! - 2: the scheduler doesn't know the node
+//! - 3: permission denied
+//! - 4: credentials expired
+//! - 5: could not reach the scheduler
+//! - anything else: some other error, the call is retried
+//!
+//! anything written to stderr is logged. Commands are killed if they run past the scheduler timeout
+use crate::entities::target::TargetStatus;
+use async_trait::async_trait;
+use chrono::DateTime;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::process::Stdio;
+use tokio::process::Command;
+use tracing::instrument;
+use tracing::{info, warn};
+
+use super::{NodeSnapshot, SchedulerError, SchedulerTrait};
+
+#[derive(Debug)]
+pub struct ScriptScheduler {
+    status: Vec<String>,
+    offline: Vec<String>,
+    release: Vec<String>,
+}
+
+impl ScriptScheduler {
+    pub fn new(status: Vec<String>, offline: Vec<String>, release: Vec<String>) -> Self {
+        Self {
+            status,
+            offline,
+            release,
+        }
+    }
+
+    /// run `argv` with `args` appended, returning its stdout
+    async fn run(&self, argv: &[String], args: &[&str]) -> Result<String, SchedulerError> {
+        let Some((cmd, base)) = argv.split_first() else {
+            return Err(SchedulerError::Other("no command configured".to_string()));
+        };
+        let out = Command::new(cmd)
+            .args(base)
+            .args(args)
+            .stdin(Stdio::null())
+            // don't leave the script running if the call times out
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| SchedulerError::Other(format!("could not run {}: {}", cmd, e)))?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        for line in stderr.lines().filter(|l| !l.trim().is_empty()) {
+            info!("{}: {}", cmd, line);
+        }
+        let msg = || format!("{} exited with {}: {}", cmd, out.status, stderr.trim());
+        match out.status.code() {
+            Some(0) => Ok(String::from_utf8_lossy(&out.stdout).to_string()),
+            Some(2) => Err(SchedulerError::UnknownNode(
+                args.first().unwrap_or(&"").to_string(),
+            )),
+            Some(3) => Err(SchedulerError::PermissionDenied(msg())),
+            Some(4) => Err(SchedulerError::CredentialExpired),
+            Some(5) => Err(SchedulerError::ConnectionFailed(msg())),
+            _ => Err(SchedulerError::Other(msg())),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ScriptNodes {
+    nodes: Vec<ScriptNode>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ScriptNode {
+    name: String,
+    status: String,
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    comment: String,
+    #[serde(default)]
+    jobs: Vec<String>,
+    #[serde(default)]
+    ncpus: Option<i32>,
+    #[serde(default)]
+    ngpus: Option<i32>,
+    #[serde(default)]
+    mem: Option<i64>,
+    #[serde(default)]
+    last_state_change: Option<i64>,
+    #[serde(default)]
+    attributes: BTreeMap<String, String>,
+}
+
+impl ScriptNode {
+    fn snapshot(self) -> NodeSnapshot {
+        let status = match self.status.to_lowercase().as_str() {
+            "online" => TargetStatus::Online,
+            "draining" => TargetStatus::Draining,
+            "offline" => TargetStatus::Offline,
+            "down" => TargetStatus::Down,
+            x => {
+                warn!("unrecognized node state for {}, '{}'", self.name, x);
+                TargetStatus::Unknown
+            }
+        };
+        NodeSnapshot {
+            status,
+            state: self.state.unwrap_or(self.status),
+            comment: self.comment,
+            jobs: self.jobs,
+            ncpus: self.ncpus,
+            ngpus: self.ngpus,
+            mem: self.mem,
+            last_state_change: self
+                .last_state_change
+                .and_then(|s| DateTime::from_timestamp(s, 0))
+                .map(|d| d.naive_utc()),
+            attributes: self.attributes,
+        }
+    }
+}
+
+fn parse_nodes(json: &str) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+    let resp: ScriptNodes = serde_json::from_str(json)
+        .map_err(|e| SchedulerError::Other(format!("error parsing status output: {}", e)))?;
+    Ok(resp
+        .nodes
+        .into_iter()
+        .map(|n| (n.name.clone(), n.snapshot()))
+        .collect())
+}
+
+#[async_trait]
+impl SchedulerTrait for ScriptScheduler {
+    #[instrument]
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        let out = self.run(&self.status, &[]).await;
+        if let Err(e) = out {
+            warn!("error getting node state: {}", e);
+            return Err(e);
+        }
+        parse_nodes(&out.unwrap())
+    }
+
+    #[instrument]
+    async fn release_node(&self, target: &str) -> Result<(), SchedulerError> {
+        info!("resuming node {}", target);
+        if let Err(e) = self.run(&self.release, &[target]).await {
+            warn!("Error resuming node {}: {}", target, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    async fn offline_node(&self, target: &str, comment: &str) -> Result<(), SchedulerError> {
+        info!("offlining: {}, {}", target, comment);
+        if let Err(e) = self.run(&self.offline, &[target, comment]).await {
+            warn!("Error offlining node {}: {}", target, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn fixture_scheduler() -> ScriptScheduler {
+    let script = |s: &str| {
+        vec![
+            "sh".to_string(),
+            format!(
+                "{}/src/cluster/scheduler/fixtures/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                s
+            ),
+        ]
+    };
+    ScriptScheduler::new(
+        script("script_status.sh"),
+        script("script_update.sh"),
+        script("script_update.sh"),
+    )
+}
+
+#[tokio::test]
+async fn script_status() {
+    let nodes = fixture_scheduler().nodes_status().await.unwrap();
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(
+        nodes.get("gu0001"),
+        Some(&NodeSnapshot::new(TargetStatus::Online, "online", ""))
+    );
+    let gu0002 = nodes.get("gu0002").unwrap();
+    assert_eq!(gu0002.status, TargetStatus::Draining);
+    assert_eq!(gu0002.state, "job-busy,offline");
+    assert_eq!(gu0002.comment, "bad dimm");
+    assert_eq!(gu0002.jobs, vec!["1234.pbs"]);
+    assert_eq!(gu0002.ncpus, Some(128));
+    assert_eq!(gu0002.ngpus, Some(4));
+    assert_eq!(gu0002.mem, Some(262144));
+    assert_eq!(gu0002.attributes["blade"], "b1");
+    assert_eq!(
+        gu0002.last_state_change.unwrap().to_string(),
+        "2023-10-11 16:00:00"
+    );
+    assert_eq!(nodes.get("gu0003").unwrap().status, TargetStatus::Unknown);
+    assert_eq!(nodes.get("gu0003").unwrap().state, "provisioning");
+}
+
+#[tokio::test]
+async fn script_updates() {
+    let sched = fixture_scheduler();
+    assert!(sched.offline_node("gu0001", "bad dimm").await.is_ok());
+    assert!(sched.release_node("gu0001").await.is_ok());
+    assert_eq!(
+        sched.offline_node("gu9999", "bad dimm").await,
+        Err(SchedulerError::UnknownNode("gu9999".to_string()))
+    );
+    assert!(matches!(
+        sched.release_node("root0001").await,
+        Err(SchedulerError::PermissionDenied(_))
+    ));
+    assert!(matches!(
+        sched.release_node("gu0002").await,
+        Err(SchedulerError::Other(_))
+    ));
+}
+
+#[tokio::test]
+async fn script_errors() {
+    let cmd = |c: &str| vec!["sh".to_string(), "-c".to_string(), c.to_string()];
+    let sched = ScriptScheduler::new(cmd("echo not json"), vec![], cmd("exit 4"));
+    assert!(matches!(
+        sched.nodes_status().await,
+        Err(SchedulerError::Other(_))
+    ));
+    assert!(sched.offline_node("gu0001", "").await.is_err());
+    assert_eq!(
+        sched.release_node("gu0001").await,
+        Err(SchedulerError::CredentialExpired)
+    );
+
+    // hung scripts are killed once the retry wrapper gives up on them
+    let hung = super::RetryScheduler::new(
+        Box::new(ScriptScheduler::new(cmd("sleep 60"), vec![], vec![])),
+        std::time::Duration::from_millis(200),
+        0,
+    );
+    let start = std::time::Instant::now();
+    assert_eq!(hung.nodes_status().await, Err(SchedulerError::Timeout));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn no_reservations() {
+    let start = DateTime::from_timestamp(1697040000, 0).unwrap().naive_utc();
+    let sched = super::RetryScheduler::new(
+        Box::new(ScriptScheduler::new(vec![], vec![], vec![])),
+        std::time::Duration::from_secs(5),
+        2,
+    );
+    let e = sched
+        .create_reservation("ctt1", &["gu0001".to_string()], start, start)
+        .await
+        .unwrap_err();
+    assert_eq!(e, SchedulerError::Unsupported("reservations".to_string()));
+    assert!(!e.is_transient());
+    // not retried
+    let now = std::time::Instant::now();
+    assert!(sched.delete_reservation("1.pbs").await.is_err());
+    assert!(now.elapsed() < std::time::Duration::from_secs(1));
+}
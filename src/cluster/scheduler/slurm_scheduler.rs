@@ -0,0 +1,513 @@
+use crate::entities::target::TargetStatus;
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use tokio::process::Command;
+use tracing::instrument;
+use tracing::{info, warn};
+
+use super::{by_comment, BatchResult, NodeSnapshot, SchedulerError, SchedulerTrait};
+
+#[derive(Debug)]
+pub struct SlurmScheduler {
+    scontrol: String,
+}
+
+impl SlurmScheduler {
+    pub fn new() -> Self {
+        Self::with_scontrol("scontrol")
+    }
+
+    /// use a specific scontrol binary instead of the one found in $PATH
+    pub fn with_scontrol(scontrol: &str) -> Self {
+        Self {
+            scontrol: scontrol.to_string(),
+        }
+    }
+
+    async fn scontrol(&self, args: &[&str]) -> Result<String, SchedulerError> {
+        let out = Command::new(&self.scontrol)
+            .args(args)
+            // don't leave scontrol running if the call times out
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| {
+                SchedulerError::Other(format!("could not run {}: {}", self.scontrol, e))
+            })?;
+        if !out.status.success() {
+            return Err(scontrol_error(
+                String::from_utf8_lossy(&out.stderr).trim(),
+                args,
+            ));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).to_string())
+    }
+}
+
+impl Default for SlurmScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// classify scontrol's stderr, `args` is used to find the node an update was for
+fn scontrol_error(stderr: &str, args: &[&str]) -> SchedulerError {
+    let msg = stderr.to_lowercase();
+    if msg.contains("invalid node name") {
+        let node = args
+            .iter()
+            .find_map(|a| a.strip_prefix("NodeName="))
+            .unwrap_or_default();
+        SchedulerError::UnknownNode(node.to_string())
+    } else if msg.contains("expired credential") {
+        SchedulerError::CredentialExpired
+    } else if msg.contains("access/permission denied") || msg.contains("invalid user id") {
+        SchedulerError::PermissionDenied(stderr.to_string())
+    } else if msg.contains("unable to contact slurm controller")
+        || msg.contains("connection refused")
+        || msg.contains("communication")
+    {
+        SchedulerError::ConnectionFailed(stderr.to_string())
+    } else {
+        SchedulerError::Other(stderr.to_string())
+    }
+}
+
+/// subset of `scontrol show node --json` output ctt cares about
+#[derive(Deserialize, Debug)]
+struct ScontrolNodes {
+    nodes: Vec<ScontrolNode>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ScontrolNode {
+    name: String,
+    state: SlurmState,
+    // slurm < 23.02 reports flags (DRAIN, NOT_RESPONDING, ...) separately from the base state
+    #[serde(default)]
+    state_flags: Vec<String>,
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default)]
+    cpus: Option<i32>,
+    // MB
+    #[serde(default)]
+    real_memory: Option<i64>,
+    // eg "gpu:a100:4(S:0-1)"
+    #[serde(default)]
+    gres: Option<String>,
+    // closest thing slurm reports to a last state change
+    #[serde(default)]
+    reason_changed_at: Option<SlurmNumber>,
+    #[serde(default)]
+    features: Option<SlurmList>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SlurmNumber {
+    // slurm < 23.11
+    Plain(i64),
+    // slurm >= 23.11, eg {"set": true, "infinite": false, "number": 1697040000}
+    Struct { number: i64 },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SlurmList {
+    // slurm >= 23.02
+    List(Vec<String>),
+    // slurm < 23.02, comma separated
+    Joined(String),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SlurmState {
+    // slurm >= 23.02, eg ["MIXED", "DRAIN"]
+    Flags(Vec<String>),
+    // slurm < 23.02, eg "mixed"
+    Base(String),
+}
+
+impl ScontrolNode {
+    fn states(&self) -> Vec<String> {
+        let mut states = match &self.state {
+            SlurmState::Flags(f) => f.clone(),
+            SlurmState::Base(s) => vec![s.clone()],
+        };
+        states.extend(self.state_flags.iter().cloned());
+        states.iter().map(|s| s.to_uppercase()).collect()
+    }
+
+    fn comment(&self) -> String {
+        // drain reason is what offline_node sets, so prefer it over the free form comment
+        match (&self.reason, &self.comment) {
+            (Some(r), _) if !r.is_empty() => r.clone(),
+            (_, Some(c)) => c.clone(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn ngpus(&self) -> Option<i32> {
+        let gres = self.gres.as_ref()?;
+        let mut count = 0;
+        for g in gres.split(',').filter(|g| g.starts_with("gpu")) {
+            // drop the socket binding, count is the last field, eg gpu:a100:4(S:0-1)
+            let g = g.split('(').next().unwrap();
+            count += g.rsplit(':').next().unwrap().parse::<i32>().ok()?;
+        }
+        Some(count)
+    }
+
+    fn last_state_change(&self) -> Option<NaiveDateTime> {
+        let secs = match self.reason_changed_at.as_ref()? {
+            SlurmNumber::Plain(n) => *n,
+            SlurmNumber::Struct { number } => *number,
+        };
+        if secs == 0 {
+            return None;
+        }
+        DateTime::from_timestamp(secs, 0).map(|d| d.naive_utc())
+    }
+
+    /// features like `rack:r12` as attributes, features without a `:` are flags and skipped
+    fn attributes(&self) -> BTreeMap<String, String> {
+        let features: Vec<&str> = match &self.features {
+            Some(SlurmList::List(l)) => l.iter().map(|f| f.as_str()).collect(),
+            Some(SlurmList::Joined(j)) => j.split(',').collect(),
+            None => vec![],
+        };
+        features
+            .into_iter()
+            .filter_map(|f| f.trim().split_once(':'))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn snapshot(&self) -> NodeSnapshot {
+        let states = self.states();
+        let raw = states.join("+");
+        let status = node_state(&states);
+        if status == TargetStatus::Unknown {
+            warn!("unrecognized node state for {}, '{}'", self.name, raw);
+        }
+        NodeSnapshot {
+            status,
+            state: raw,
+            comment: self.comment(),
+            // scontrol doesn't list the jobs on a node
+            jobs: vec![],
+            ncpus: self.cpus,
+            ngpus: self.ngpus(),
+            mem: self.real_memory,
+            last_state_change: self.last_state_change(),
+            attributes: self.attributes(),
+        }
+    }
+}
+
+/// map a slurm node's base state and flags onto a TargetStatus
+fn node_state(states: &[String]) -> TargetStatus {
+    let has = |s: &str| states.iter().any(|x| x == s);
+    let jobs = has("ALLOCATED") || has("ALLOC") || has("MIXED") || has("COMPLETING");
+    //order matters, drain before down to capture down+drain nodes
+    if has("DRAINING") {
+        TargetStatus::Draining
+    } else if has("DRAIN") || has("DRAINED") || has("FAIL") {
+        if jobs {
+            TargetStatus::Draining
+        } else {
+            TargetStatus::Offline
+        }
+    } else if has("DOWN") || has("NOT_RESPONDING") {
+        if jobs {
+            TargetStatus::Draining
+        } else {
+            TargetStatus::Down
+        }
+    } else if jobs || has("IDLE") || has("RESERVED") {
+        TargetStatus::Online
+    } else {
+        TargetStatus::Unknown
+    }
+}
+
+/// scontrol reads times in the local timezone
+fn scontrol_time(t: &NaiveDateTime) -> String {
+    Local
+        .from_utc_datetime(t)
+        .format("%Y-%m-%dT%H:%M:%S")
+        .to_string()
+}
+
+fn parse_nodes(json: &str) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+    let resp: ScontrolNodes = serde_json::from_str(json)
+        .map_err(|e| SchedulerError::Other(format!("error parsing scontrol output: {}", e)))?;
+    Ok(resp
+        .nodes
+        .iter()
+        .map(|n| (n.name.clone(), n.snapshot()))
+        .collect())
+}
+
+#[async_trait]
+impl SchedulerTrait for SlurmScheduler {
+    #[instrument]
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        let out = self.scontrol(&["--json", "show", "node"]).await;
+        if let Err(e) = out {
+            warn!("error getting node state: {}", e);
+            return Err(e);
+        }
+        parse_nodes(&out.unwrap())
+    }
+
+    #[instrument]
+    async fn release_node(&self, target: &str) -> Result<(), SchedulerError> {
+        info!("resuming node {}", target);
+        if let Err(e) = self
+            .scontrol(&["update", &format!("NodeName={}", target), "State=RESUME"])
+            .await
+        {
+            warn!("Error resuming node {}: {}", target, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    async fn offline_node(&self, target: &str, comment: &str) -> Result<(), SchedulerError> {
+        info!("draining: {}, {}", target, comment);
+        // slurm refuses to drain a node without a reason
+        let reason = if comment.is_empty() { "ctt" } else { comment };
+        if let Err(e) = self
+            .scontrol(&[
+                "update",
+                &format!("NodeName={}", target),
+                "State=DRAIN",
+                &format!("Reason={}", reason),
+            ])
+            .await
+        {
+            warn!("Error draining node {}: {}", target, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult {
+        let nodes = targets.join(",");
+        info!("resuming nodes {}", nodes);
+        let resp = self
+            .scontrol(&["update", &format!("NodeName={}", nodes), "State=RESUME"])
+            .await;
+        match resp {
+            Ok(_) => targets.iter().map(|t| (t.clone(), Ok(()))).collect(),
+            Err(e) if targets.len() == 1 => vec![(targets[0].clone(), Err(e))],
+            // scontrol gives up on the whole list when one node is bad, so find which one
+            Err(e) => {
+                warn!(
+                    "Error resuming nodes {}: {}, trying one at a time",
+                    nodes, e
+                );
+                let mut results = Vec::with_capacity(targets.len());
+                for t in targets {
+                    results.push((t.clone(), self.release_node(t).await));
+                }
+                results
+            }
+        }
+    }
+
+    #[instrument]
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult {
+        let mut results = Vec::with_capacity(targets.len());
+        for (comment, group) in by_comment(targets) {
+            let nodes = group.join(",");
+            info!("draining: {}, {}", nodes, comment);
+            let reason = if comment.is_empty() { "ctt" } else { &comment };
+            let resp = self
+                .scontrol(&[
+                    "update",
+                    &format!("NodeName={}", nodes),
+                    "State=DRAIN",
+                    &format!("Reason={}", reason),
+                ])
+                .await;
+            match resp {
+                Ok(_) => results.extend(group.into_iter().map(|t| (t, Ok(())))),
+                Err(e) if group.len() == 1 => results.push((nodes, Err(e))),
+                Err(e) => {
+                    warn!(
+                        "Error draining nodes {}: {}, trying one at a time",
+                        nodes, e
+                    );
+                    for t in group {
+                        let r = self.offline_node(&t, &comment).await;
+                        results.push((t, r));
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    #[instrument]
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        let nodes = targets.join(",");
+        info!("reserving {} from {} to {}", nodes, start, end);
+        if let Err(e) = self
+            .scontrol(&[
+                "create",
+                "reservation",
+                &format!("ReservationName={}", name),
+                &format!("StartTime={}", scontrol_time(&start)),
+                &format!("EndTime={}", scontrol_time(&end)),
+                &format!("Nodes={}", nodes),
+                "Users=root",
+                // jobs already running into the window are left alone
+                "Flags=MAINT,IGNORE_JOBS",
+            ])
+            .await
+        {
+            warn!("Error creating reservation {}: {}", name, e);
+            return Err(e);
+        }
+        Ok(name.to_string())
+    }
+
+    #[instrument]
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        info!("deleting reservation {}", id);
+        if let Err(e) = self
+            .scontrol(&["delete", &format!("ReservationName={}", id)])
+            .await
+        {
+            warn!("Error deleting reservation {}: {}", id, e);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn parse_scontrol_json() {
+    let nodes = parse_nodes(include_str!("fixtures/scontrol_show_node.json")).unwrap();
+    let expected = vec![
+        ("gu0001", TargetStatus::Online, ""),
+        ("gu0002", TargetStatus::Online, ""),
+        ("gu0003", TargetStatus::Online, ""),
+        ("gu0004", TargetStatus::Offline, "bad dimm"),
+        ("gu0005", TargetStatus::Draining, "gu0005 sibling"),
+        ("gu0006", TargetStatus::Down, "Not responding"),
+        ("gu0007", TargetStatus::Offline, "replacing blade"),
+        ("gu0008", TargetStatus::Unknown, ""),
+    ];
+    assert_eq!(nodes.len(), expected.len());
+    for (name, state, comment) in expected {
+        let actual = nodes.get(name).unwrap();
+        assert_eq!(
+            (actual.status, actual.comment.as_str()),
+            (state, comment),
+            "for {}: {:?}",
+            name,
+            actual
+        );
+    }
+}
+
+#[test]
+fn scontrol_snapshot() {
+    let nodes = parse_nodes(include_str!("fixtures/scontrol_show_node.json")).unwrap();
+    let gu0002 = nodes.get("gu0002").unwrap();
+    assert_eq!(gu0002.state, "ALLOCATED");
+    assert_eq!(gu0002.ncpus, Some(128));
+    assert_eq!(gu0002.ngpus, Some(4));
+    assert_eq!(gu0002.mem, Some(256000));
+    assert_eq!(gu0002.last_state_change, None);
+    assert!(gu0002.attributes.is_empty());
+    // features without a value are skipped
+    assert_eq!(
+        nodes.get("gu0001").unwrap().attributes,
+        [("rack".to_string(), "r1".to_string())].into()
+    );
+    let gu0007 = nodes.get("gu0007").unwrap();
+    assert_eq!(gu0007.state, "DOWN+DRAIN");
+    assert_eq!(gu0007.ngpus, Some(0));
+    assert_eq!(
+        gu0007.last_state_change.unwrap().to_string(),
+        "2023-10-11 16:00:00"
+    );
+    assert_eq!(nodes.get("gu0008").unwrap().state, "FUTURE");
+}
+
+#[test]
+fn parse_scontrol_json_21_08() {
+    let nodes = parse_nodes(include_str!("fixtures/scontrol_show_node_21.08.json")).unwrap();
+    let expected = vec![
+        ("gu0001", TargetStatus::Online, ""),
+        ("gu0002", TargetStatus::Draining, "bad dimm"),
+        ("gu0003", TargetStatus::Down, "node has been acting up"),
+    ];
+    assert_eq!(nodes.get("gu0001").unwrap().attributes["rack"], "r2");
+    assert_eq!(nodes.len(), expected.len());
+    for (name, state, comment) in expected {
+        let actual = nodes.get(name).unwrap();
+        assert_eq!(
+            (actual.status, actual.comment.as_str()),
+            (state, comment),
+            "for {}: {:?}",
+            name,
+            actual
+        );
+    }
+}
+
+#[test]
+fn slurm_states() {
+    let s = |v: &[&str]| node_state(&v.iter().map(|x| x.to_string()).collect::<Vec<_>>());
+    assert_eq!(s(&["IDLE"]), TargetStatus::Online);
+    assert_eq!(s(&["ALLOC"]), TargetStatus::Online);
+    assert_eq!(s(&["MIXED"]), TargetStatus::Online);
+    assert_eq!(s(&["IDLE", "DRAIN"]), TargetStatus::Offline);
+    assert_eq!(s(&["DRAINED"]), TargetStatus::Offline);
+    assert_eq!(s(&["DRAINING"]), TargetStatus::Draining);
+    assert_eq!(s(&["ALLOCATED", "DRAIN"]), TargetStatus::Draining);
+    assert_eq!(s(&["DOWN"]), TargetStatus::Down);
+    assert_eq!(s(&["DOWN", "DRAIN"]), TargetStatus::Offline);
+    assert_eq!(s(&["MIXED", "NOT_RESPONDING"]), TargetStatus::Draining);
+    assert_eq!(s(&["FUTURE"]), TargetStatus::Unknown);
+    assert!(parse_nodes("not json").is_err());
+}
+
+#[test]
+fn scontrol_errors() {
+    let update = ["update", "NodeName=gu0009", "State=RESUME"];
+    assert_eq!(
+        scontrol_error("slurm_update error: Invalid node name specified", &update),
+        SchedulerError::UnknownNode("gu0009".to_string())
+    );
+    assert!(matches!(
+        scontrol_error("slurm_update error: Access/permission denied", &update),
+        SchedulerError::PermissionDenied(_)
+    ));
+    assert!(matches!(
+        scontrol_error(
+            "slurm_load_node error: Unable to contact slurm controller (connect failure)",
+            &["--json", "show", "node"]
+        ),
+        SchedulerError::ConnectionFailed(_)
+    ));
+}
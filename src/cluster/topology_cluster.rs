@@ -0,0 +1,517 @@
+use super::infrastructure::Infrastructure;
+use super::scheduler::{BatchResult, NodeSnapshot, SchedulerError, SchedulerTrait};
+use crate::cluster::ClusterTrait;
+use crate::conf::TopologyAttributes;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use config::{Config, File, FileFormat};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use tracing::instrument;
+
+/// where a node physically sits
+///
+/// each id is only unique within its parent, so card 0 of blade 1 and card 0 of blade 2 are
+/// different cards
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeLocation {
+    pub card: Option<String>,
+    pub blade: Option<String>,
+    pub chassis: Option<String>,
+    pub rack: Option<String>,
+    /// named failure domains that cut across the hardware layout, eg switch or pdu, and the id
+    /// of the one the node is in
+    pub domains: BTreeMap<String, String>,
+}
+
+impl NodeLocation {
+    fn same_card(&self, other: &Self) -> bool {
+        self.card.is_some() && self.card == other.card && self.same_blade(other)
+    }
+
+    fn same_blade(&self, other: &Self) -> bool {
+        self.blade == other.blade && self.same_chassis(other)
+    }
+
+    fn same_chassis(&self, other: &Self) -> bool {
+        self.chassis == other.chassis && self.rack == other.rack
+    }
+}
+
+/// physical layout of a cluster, loaded from a topology file or scheduler attributes
+#[derive(Clone, Default)]
+pub struct Topology {
+    // in file order, which is the order related nodes are returned in
+    nodes: Vec<(String, NodeLocation)>,
+    index: HashMap<String, usize>,
+}
+
+// topologies can have thousands of nodes, keep them out of the tracing spans
+impl fmt::Debug for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Topology")
+            .field("nodes", &self.nodes.len())
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct TopologyFile {
+    nodes: Vec<TopologyEntry>,
+}
+
+#[derive(Deserialize)]
+struct TopologyEntry {
+    name: String,
+    card: Option<String>,
+    blade: Option<String>,
+    chassis: Option<String>,
+    rack: Option<String>,
+    #[serde(default)]
+    domains: BTreeMap<String, String>,
+}
+
+impl Topology {
+    /// errors if a node is listed twice, has no name, or the file has no nodes
+    pub fn new(nodes: Vec<(String, NodeLocation)>) -> Result<Self, String> {
+        if nodes.is_empty() {
+            return Err("topology has no nodes".to_string());
+        }
+        let mut index = HashMap::with_capacity(nodes.len());
+        for (i, (name, _)) in nodes.iter().enumerate() {
+            if name.is_empty() {
+                return Err(format!("node {} has no name", i + 1));
+            }
+            if index.insert(name.clone(), i).is_some() {
+                return Err(format!("{} is listed more than once", name));
+            }
+        }
+        Ok(Self { nodes, index })
+    }
+
+    /// locations from each node's scheduler attributes, `names` says which attribute holds which
+    /// level. Every node is included, sorted by name, nodes without the attributes are on their own
+    pub fn from_attributes(
+        snapshots: &HashMap<String, NodeSnapshot>,
+        names: &TopologyAttributes,
+    ) -> Self {
+        let get = |snap: &NodeSnapshot, attr: &Option<String>| {
+            attr.as_ref().and_then(|a| snap.attributes.get(a)).cloned()
+        };
+        let mut nodes: Vec<(String, NodeLocation)> = snapshots
+            .iter()
+            .map(|(name, snap)| {
+                let location = NodeLocation {
+                    card: get(snap, &names.card),
+                    blade: get(snap, &names.blade),
+                    chassis: get(snap, &names.chassis),
+                    rack: get(snap, &names.rack),
+                    domains: names
+                        .domains
+                        .iter()
+                        .filter_map(|(domain, attr)| {
+                            Some((domain.clone(), snap.attributes.get(attr)?.clone()))
+                        })
+                        .collect(),
+                };
+                (name.clone(), location)
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        let index = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (name.clone(), i))
+            .collect();
+        Self { nodes, index }
+    }
+
+    /// files ending in `.csv` are read as csv, anything else as yaml
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("could not read topology file {}: {}", path, e))?;
+        if path.ends_with(".csv") {
+            Self::from_csv(&contents)
+        } else {
+            Self::from_yaml(&contents)
+        }
+        .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// a `nodes` list, each with a `name` and optionally `card`, `blade`, `chassis`, `rack` and a
+    /// `domains` map of failure domain name to id
+    pub fn from_yaml(contents: &str) -> Result<Self, String> {
+        let file: TopologyFile = Config::builder()
+            .add_source(File::from_str(contents, FileFormat::Yaml))
+            .build()
+            .and_then(|c| c.try_deserialize())
+            .map_err(|e| e.to_string())?;
+        Self::new(
+            file.nodes
+                .into_iter()
+                .map(|n| {
+                    let location = NodeLocation {
+                        card: n.card,
+                        blade: n.blade,
+                        chassis: n.chassis,
+                        rack: n.rack,
+                        domains: n.domains,
+                    };
+                    (n.name, location)
+                })
+                .collect(),
+        )
+    }
+
+    /// header row naming the columns, `name` is required and `card`, `blade`, `chassis` and
+    /// `rack` are optional, in any order. Any other column is a named failure domain
+    ///
+    /// empty fields are left unset, blank lines and lines starting with `#` are skipped
+    pub fn from_csv(contents: &str) -> Result<Self, String> {
+        let mut lines = contents
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i + 1, l.trim()))
+            .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+        let Some((_, header)) = lines.next() else {
+            return Err("topology has no nodes".to_string());
+        };
+        let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+        if let Some((i, c)) = columns
+            .iter()
+            .enumerate()
+            .find(|(i, c)| columns[..*i].contains(c) || c.is_empty())
+        {
+            return Err(format!("bad column {}: {:?}", i + 1, c));
+        }
+        if !columns.contains(&"name") {
+            return Err("missing name column".to_string());
+        }
+        let mut nodes = Vec::new();
+        for (num, line) in lines {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != columns.len() {
+                return Err(format!(
+                    "line {}: expected {} fields, found {}",
+                    num,
+                    columns.len(),
+                    fields.len()
+                ));
+            }
+            let mut name = String::new();
+            let mut location = NodeLocation::default();
+            for (column, field) in columns.iter().zip(fields) {
+                let val = (!field.is_empty()).then(|| field.to_string());
+                match *column {
+                    "name" => name = val.unwrap_or_default(),
+                    "card" => location.card = val,
+                    "blade" => location.blade = val,
+                    "chassis" => location.chassis = val,
+                    "rack" => location.rack = val,
+                    domain => {
+                        if let Some(v) = val {
+                            location.domains.insert(domain.to_string(), v);
+                        }
+                    }
+                }
+            }
+            nodes.push((name, location));
+        }
+        Self::new(nodes)
+    }
+
+    pub fn location(&self, node: &str) -> Option<&NodeLocation> {
+        self.index.get(node).map(|&i| &self.nodes[i].1)
+    }
+
+    /// nodes on the same card, a node without a card is on its own
+    pub fn siblings(&self, target: &str) -> Vec<String> {
+        match self.location(target) {
+            Some(loc) if loc.card.is_some() => self.nodes_where(|l| loc.same_card(l)),
+            Some(_) => vec![target.to_string()],
+            None => vec![],
+        }
+    }
+
+    /// nodes on the same blade, a node without a blade only takes its siblings with it
+    pub fn cousins(&self, target: &str) -> Vec<String> {
+        match self.location(target) {
+            Some(loc) if loc.blade.is_some() => self.nodes_where(|l| loc.same_blade(l)),
+            Some(_) => self.siblings(target),
+            None => vec![],
+        }
+    }
+
+    pub fn chassis(&self, target: &str) -> Vec<String> {
+        match self.location(target) {
+            Some(loc) if loc.chassis.is_some() => self.nodes_where(|l| loc.same_chassis(l)),
+            Some(_) => self.cousins(target),
+            None => vec![],
+        }
+    }
+
+    pub fn rack(&self, target: &str) -> Vec<String> {
+        match self.location(target) {
+            Some(loc) if loc.rack.is_some() => self.nodes_where(|l| loc.rack == l.rack),
+            Some(_) => self.chassis(target),
+            None => vec![],
+        }
+    }
+
+    pub fn domains(&self, target: &str) -> Vec<String> {
+        self.location(target)
+            .map(|l| l.domains.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn domain(&self, target: &str, domain: &str) -> Vec<String> {
+        match self.location(target).and_then(|l| l.domains.get(domain)) {
+            Some(id) => self.nodes_where(|l| l.domains.get(domain) == Some(id)),
+            None => vec![],
+        }
+    }
+
+    fn nodes_where<F: Fn(&NodeLocation) -> bool>(&self, f: F) -> Vec<String> {
+        self.nodes
+            .iter()
+            .filter(|(_, l)| f(l))
+            .map(|(n, _)| n.clone())
+            .collect()
+    }
+}
+
+/// cluster whose layout comes from a topology file instead of node numbering
+#[derive(Debug)]
+pub struct TopologyCluster {
+    name: String,
+    topology: Topology,
+    infrastructure: Infrastructure,
+    sched: Box<dyn SchedulerTrait>,
+}
+
+impl TopologyCluster {
+    pub fn new(name: &str, topology: Topology, sched: Box<dyn SchedulerTrait>) -> Self {
+        Self {
+            name: name.to_string(),
+            topology,
+            infrastructure: Infrastructure::default(),
+            sched,
+        }
+    }
+
+    /// add targets that aren't nodes, see [`Infrastructure`]
+    pub fn with_infrastructure(mut self, infrastructure: Infrastructure) -> Self {
+        self.infrastructure = infrastructure;
+        self
+    }
+}
+
+#[async_trait]
+impl ClusterTrait for TopologyCluster {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    #[instrument]
+    fn siblings(&self, target: &str) -> Vec<String> {
+        self.topology.siblings(target)
+    }
+    #[instrument]
+    fn cousins(&self, target: &str) -> Vec<String> {
+        self.topology.cousins(target)
+    }
+    #[instrument]
+    fn chassis(&self, target: &str) -> Vec<String> {
+        self.topology.chassis(target)
+    }
+    #[instrument]
+    fn rack(&self, target: &str) -> Vec<String> {
+        self.topology.rack(target)
+    }
+    /// node types, and so their tags, aren't used with a topology file
+    fn tags(&self, _target: &str) -> Vec<String> {
+        vec![]
+    }
+    #[instrument]
+    fn domains(&self, target: &str) -> Vec<String> {
+        self.topology.domains(target)
+    }
+    #[instrument]
+    fn domain(&self, target: &str, domain: &str) -> Vec<String> {
+        self.topology.domain(target, domain)
+    }
+    #[instrument]
+    fn real_node(&self, target: &str) -> bool {
+        self.topology.location(target).is_some()
+    }
+    fn infrastructure(&self) -> &Infrastructure {
+        &self.infrastructure
+    }
+
+    #[instrument]
+    async fn nodes_status(&self) -> Result<HashMap<String, NodeSnapshot>, SchedulerError> {
+        self.sched.nodes_status().await
+    }
+    #[instrument]
+    async fn release_nodes(&self, targets: &[String]) -> BatchResult {
+        self.sched.release_nodes(targets).await
+    }
+    #[instrument]
+    async fn offline_nodes(&self, targets: &[(String, String)]) -> BatchResult {
+        self.sched.offline_nodes(targets).await
+    }
+    #[instrument]
+    async fn create_reservation(
+        &self,
+        name: &str,
+        targets: &[String],
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<String, SchedulerError> {
+        self.sched
+            .create_reservation(name, targets, start, end)
+            .await
+    }
+    #[instrument]
+    async fn delete_reservation(&self, id: &str) -> Result<(), SchedulerError> {
+        self.sched.delete_reservation(id).await
+    }
+    #[instrument]
+    fn refresh_conn(&self) {
+        self.sched.refresh_conn();
+    }
+}
+
+#[cfg(test)]
+fn derecho() -> TopologyCluster {
+    let topology = Topology::from_csv(
+        r#"
+name,rack,chassis,blade,card,switch
+# 2 cards of 2 nodes
+dec0001,r1,c1,b1,0,leaf1
+dec0002,r1,c1,b1,0,leaf1
+dec0003,r1,c1,b1,1,leaf1
+dec0004,r1,c1,b1,1,leaf1
+# a single card of 3, with a gap in the numbering
+dec0007,r1,c1,b2,0,leaf2
+dec0009,r1,c1,b2,0,leaf2
+dec0010,r1,c1,b2,0,leaf2
+# same blade and card ids in a different chassis
+dec0101,r1,c2,b1,0,leaf2
+# login node, not on any blade
+derecho1,r2,,,,
+"#,
+    )
+    .unwrap();
+    TopologyCluster::new(
+        "derecho",
+        topology,
+        Box::new(super::scheduler::FakeScheduler::new()),
+    )
+}
+
+#[test]
+fn related_from_file() {
+    let derecho = derecho();
+    assert_eq!(derecho.siblings("dec0002"), vec!["dec0001", "dec0002"]);
+    assert_eq!(
+        derecho.cousins("dec0002"),
+        vec!["dec0001", "dec0002", "dec0003", "dec0004"]
+    );
+    assert_eq!(
+        derecho.siblings("dec0009"),
+        vec!["dec0007", "dec0009", "dec0010"]
+    );
+    assert_eq!(
+        derecho.cousins("dec0009"),
+        vec!["dec0007", "dec0009", "dec0010"]
+    );
+    assert_eq!(derecho.siblings("dec0101"), vec!["dec0101"]);
+    assert_eq!(derecho.cousins("dec0101"), vec!["dec0101"]);
+    assert_eq!(derecho.siblings("derecho1"), vec!["derecho1"]);
+    assert_eq!(derecho.cousins("derecho1"), vec!["derecho1"]);
+    assert!(derecho.siblings("dec0008").is_empty());
+    assert!(derecho.cousins("dec0008").is_empty());
+    assert!(derecho.real_node("dec0010"));
+    assert!(!derecho.real_node("dec0008"));
+}
+
+#[test]
+fn failure_domains() {
+    let derecho = derecho();
+    let c1 = vec![
+        "dec0001", "dec0002", "dec0003", "dec0004", "dec0007", "dec0009", "dec0010",
+    ];
+    assert_eq!(derecho.chassis("dec0009"), c1);
+    assert_eq!(derecho.chassis("dec0101"), vec!["dec0101"]);
+    let mut r1 = c1.clone();
+    r1.push("dec0101");
+    assert_eq!(derecho.rack("dec0001"), r1);
+    assert_eq!(derecho.rack("derecho1"), vec!["derecho1"]);
+    assert_eq!(derecho.domains("dec0001"), vec!["switch"]);
+    assert!(derecho.domains("derecho1").is_empty());
+    assert_eq!(
+        derecho.domain("dec0101", "switch"),
+        vec!["dec0007", "dec0009", "dec0010", "dec0101"]
+    );
+    assert!(derecho.domain("dec0101", "pdu").is_empty());
+    assert!(derecho.domain("derecho1", "switch").is_empty());
+}
+
+#[test]
+fn yaml_topology() {
+    let topology = Topology::from_yaml(
+        r#"
+nodes:
+  - { name: "gu0001", card: 1, blade: 1 }
+  - { name: "gu0002", card: 1, blade: 1 }
+  - { name: "gu0003", blade: 1, chassis: "left", rack: 12, domains: { pdu: 3 } }
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        topology.location("gu0001"),
+        Some(&NodeLocation {
+            card: Some("1".to_string()),
+            blade: Some("1".to_string()),
+            chassis: None,
+            rack: None,
+            domains: BTreeMap::new(),
+        })
+    );
+    assert_eq!(
+        topology.location("gu0003").unwrap().rack.as_deref(),
+        Some("12")
+    );
+    assert_eq!(
+        topology.location("gu0003").unwrap().domains.get("pdu"),
+        Some(&"3".to_string())
+    );
+    assert!(topology.location("gu0004").is_none());
+}
+
+#[test]
+fn invalid_topology() {
+    let err = |csv: &str| Topology::from_csv(csv).unwrap_err();
+    assert_eq!(
+        err("name,card\ngu0001,1\ngu0001,2\n"),
+        "gu0001 is listed more than once"
+    );
+    assert_eq!(
+        err("name,card,card\ngu0001,1,1\n"),
+        "bad column 3: \"card\""
+    );
+    assert_eq!(err("card,blade\n1,1\n"), "missing name column");
+    assert_eq!(
+        err("name,card\ngu0001,1\ngu0002\n"),
+        "line 3: expected 2 fields, found 1"
+    );
+    assert_eq!(err("name,card\n,1\n"), "node 1 has no name");
+    assert_eq!(err("# nothing here\n"), "topology has no nodes");
+    assert_eq!(err("name,card\n"), "topology has no nodes");
+    assert!(Topology::from_yaml("nodes: [{ card: 1 }]").is_err());
+    assert!(Topology::from_file("/nonexistent/topology.csv")
+        .unwrap_err()
+        .starts_with("could not read topology file /nonexistent/topology.csv"));
+}
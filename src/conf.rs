@@ -1,5 +1,7 @@
+use crate::cluster::scheduler::CommentFormat;
 use config::{Config, ConfigError, File};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 pub fn get_config(path: Option<String>) -> Result<Conf, ConfigError> {
     let mut conf = Config::builder();
@@ -17,8 +19,91 @@ pub struct Conf {
     pub db: String,
     pub certs_dir: String,
     pub server_addr: String,
+    /// node types of the default cluster, only used when `clusters` is empty
+    #[serde(default)]
     pub node_types: Vec<NodeType>,
+    /// topology file of the default cluster, only used when `clusters` is empty
+    pub topology: Option<String>,
+    /// scheduler attributes holding the default cluster's topology, only used when `clusters` is
+    /// empty
+    pub topology_attributes: Option<TopologyAttributes>,
+    /// infrastructure of the default cluster, only used when `clusters` is empty
+    #[serde(default)]
+    pub infrastructure: Vec<Infrastructure>,
     pub auth: Auth,
+    /// scheduler of the default cluster, only used when `clusters` is empty
+    #[serde(default)]
+    pub scheduler: Scheduler,
+    /// sync policy of the default cluster, only used when `clusters` is empty
+    #[serde(default)]
+    pub sync: SyncPolicy,
+    #[serde(default)]
+    pub clusters: Vec<Cluster>,
+}
+
+/// name of the cluster made from the top level `node_types`, `scheduler` and `sync`
+pub const DEFAULT_CLUSTER: &str = "default";
+
+impl Conf {
+    /// every cluster this instance manages
+    pub fn clusters(&self) -> Vec<Cluster> {
+        if self.clusters.is_empty() {
+            vec![Cluster {
+                name: DEFAULT_CLUSTER.to_string(),
+                node_types: self.node_types.clone(),
+                topology: self.topology.clone(),
+                topology_attributes: self.topology_attributes.clone(),
+                infrastructure: self.infrastructure.clone(),
+                scheduler: self.scheduler.clone(),
+                sync: self.sync.clone(),
+            }]
+        } else {
+            self.clusters.clone()
+        }
+    }
+}
+
+/// a set of nodes managed through a single scheduler
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Cluster {
+    pub name: String,
+    #[serde(default)]
+    pub node_types: Vec<NodeType>,
+    /// yaml or csv file mapping each node to its card, blade, chassis and rack, node types are
+    /// ignored when it is set
+    pub topology: Option<String>,
+    /// read the topology from scheduler node attributes instead, refreshed every sync
+    pub topology_attributes: Option<TopologyAttributes>,
+    /// switches, filesystems and anything else issues can be opened against besides nodes
+    #[serde(default)]
+    pub infrastructure: Vec<Infrastructure>,
+    #[serde(default)]
+    pub scheduler: Scheduler,
+    #[serde(default)]
+    pub sync: SyncPolicy,
+}
+
+/// hardware or service that isn't a node, an issue on it takes out `nodes`
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Infrastructure {
+    pub name: String,
+    /// what it is, eg switch, filesystem or cooling
+    pub kind: String,
+    /// hostlist of the nodes that depend on it, eg `gu[0001-0016]`
+    pub nodes: String,
+}
+
+/// names of the scheduler node attributes holding each level of a node's location, eg the pbs
+/// custom resource `blade`. Levels without an attribute are skipped
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct TopologyAttributes {
+    pub card: Option<String>,
+    pub blade: Option<String>,
+    pub chassis: Option<String>,
+    pub rack: Option<String>,
+    /// failure domain name to the attribute holding its id, eg `switch: leaf_switch`
+    #[serde(default)]
+    pub domains: BTreeMap<String, String>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -35,10 +120,275 @@ pub struct Slack {
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct NodeType {
+    /// ignored when `pattern` is set
+    #[serde(default)]
     pub prefix: String,
+    /// regex matching the whole node name, with a `node` number capture and optionally `card`,
+    /// `blade`, `chassis` and `rack` captures, eg `r(?<rack>\d+)i(?<chassis>\d)n(?<node>\d+)`
+    pub pattern: Option<String>,
     pub digits: Option<usize>,
     pub board: Option<u32>,
     pub first_num: Option<u32>,
     pub last_num: Option<u32>,
     pub slot: Option<u32>,
+    /// nodes per chassis, defaults to the slot size
+    pub chassis: Option<u32>,
+    /// nodes per rack, defaults to the chassis size
+    pub rack: Option<u32>,
+    /// tags every node of this type gets, eg gpu or bigmem
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// the sync loop only records what it would do to these nodes, see `SyncPolicy::observe`
+    #[serde(default)]
+    pub observe: bool,
+}
+
+/// prefix node type with every size unset, tests fill in the rest with struct update syntax
+#[cfg(test)]
+impl NodeType {
+    pub fn with_prefix(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            pattern: None,
+            digits: None,
+            board: None,
+            first_num: None,
+            last_num: None,
+            slot: None,
+            chassis: None,
+            rack: None,
+            tags: vec![],
+            observe: false,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Scheduler {
+    #[serde(flatten)]
+    pub backend: SchedulerBackend,
+    /// seconds a single scheduler call gets before it is abandoned
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// how many times a failed or timed out scheduler call is retried
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+/// what the sync loop does on its own, per cluster
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct SyncPolicy {
+    /// what the sync loop does with nodes in a state the scheduler backend doesn't recognize
+    #[serde(default)]
+    pub unknown_state: UnknownState,
+    /// format of the comments ctt sets on nodes it offlines, must contain `{id}`
+    #[serde(default)]
+    pub comment_format: CommentFormat,
+    /// the sync loop records the scheduler calls and issue changes it would make as planned
+    /// actions instead of making them, for trying ctt out on a new system
+    #[serde(default)]
+    pub observe: bool,
+    /// most nodes a single sync may offline or open issues for before automation is paused, eg
+    /// `50` or `10%` of the cluster's nodes. Unlimited when unset
+    pub max_automated: Option<Threshold>,
+    /// open a single issue offlining nodes that keep going down and coming back up, instead of
+    /// an issue each time. Off when unset
+    pub flapping: Option<Flapping>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Flapping {
+    /// changes to or from down that make a node flapping
+    pub transitions: usize,
+    /// seconds they have to happen within
+    pub window: u64,
+}
+
+/// a number of nodes, or a percentage of a cluster's nodes
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Threshold {
+    Count(usize),
+    Percent(f64),
+}
+
+impl Threshold {
+    /// the number of nodes it is out of `total`
+    pub fn limit(&self, total: usize) -> usize {
+        match self {
+            Self::Count(n) => *n,
+            Self::Percent(p) => (total as f64 * p / 100.0).floor() as usize,
+        }
+    }
+}
+
+impl TryFrom<String> for Threshold {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let bad = || format!("bad threshold {:?}, expected a count or a percentage", s);
+        match s.trim().strip_suffix('%') {
+            Some(p) => match p.trim().parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(Self::Percent(p)),
+                _ => Err(bad()),
+            },
+            None => s.trim().parse().map(Self::Count).map_err(|_| bad()),
+        }
+    }
+}
+
+impl From<Threshold> for String {
+    fn from(t: Threshold) -> Self {
+        match t {
+            Threshold::Count(n) => n.to_string(),
+            Threshold::Percent(p) => format!("{}%", p),
+        }
+    }
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            backend: SchedulerBackend::default(),
+            timeout: default_timeout(),
+            retries: default_retries(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownState {
+    /// only log it
+    Ignore,
+    /// open an issue with the raw state, but leave the node alone
+    #[default]
+    Issue,
+    /// open an issue with the raw state and offline the node
+    Offline,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SchedulerBackend {
+    #[default]
+    Pbs,
+    /// pbs through the pbsnodes command, doesn't need libpbs
+    Pbsnodes {
+        pbsnodes: Option<String>,
+    },
+    Slurm {
+        scontrol: Option<String>,
+    },
+    /// runs site provided commands, each an argv list
+    Script {
+        status: Vec<String>,
+        offline: Vec<String>,
+        release: Vec<String>,
+    },
+    /// in memory scheduler for local development, every node starts online
+    Fake {
+        #[serde(default)]
+        nodes: Vec<String>,
+    },
+}
+
+#[test]
+fn example_config() {
+    let conf = get_config(Some(format!("{}/conf_ex.yaml", env!("CARGO_MANIFEST_DIR")))).unwrap();
+    assert!(matches!(conf.scheduler.backend, SchedulerBackend::Pbs));
+    assert_eq!(conf.scheduler.timeout, 30);
+    assert_eq!(conf.scheduler.retries, 2);
+    assert_eq!(conf.sync.unknown_state, UnknownState::Issue);
+    assert_eq!(
+        conf.sync.comment_format.render(1, "bad dimm"),
+        "ctt#1: bad dimm"
+    );
+    assert!(!conf.sync.observe);
+    assert!(conf.sync.max_automated.is_none());
+    assert!(conf.sync.flapping.is_none());
+    assert_eq!(conf.node_types.len(), 2);
+    let clusters = conf.clusters();
+    assert_eq!(clusters.len(), 1);
+    assert_eq!(clusters[0].name, DEFAULT_CLUSTER);
+    assert_eq!(clusters[0].node_types.len(), 2);
+}
+
+#[test]
+fn multiple_clusters() {
+    let conf = Config::builder()
+        .add_source(File::with_name(&format!(
+            "{}/conf_ex.yaml",
+            env!("CARGO_MANIFEST_DIR")
+        )))
+        .add_source(File::from_str(
+            r#"
+clusters:
+  - name: "derecho"
+    node_types: [{ prefix: "dec", digits: 4, board: 4, slot: 8 }]
+  - name: "gpu"
+    node_types: [{ prefix: "deg", digits: 4 }]
+    scheduler: { type: "pbsnodes", pbsnodes: "/opt/gpu/bin/pbsnodes" }
+    sync: { max_automated: 50 }
+  - name: "casper"
+    topology: "/etc/ctt/casper.csv"
+  - name: "gust"
+    topology_attributes: { blade: "blade", rack: "rack", domains: { switch: "leaf" } }
+    scheduler: { type: "slurm" }
+    sync: { flapping: { transitions: 6, window: 3600 } }
+"#,
+            config::FileFormat::Yaml,
+        ))
+        .build()
+        .unwrap()
+        .try_deserialize::<Conf>()
+        .unwrap();
+    let clusters = conf.clusters();
+    assert_eq!(clusters.len(), 4);
+    assert_eq!(clusters[0].name, "derecho");
+    assert!(matches!(
+        clusters[0].scheduler.backend,
+        SchedulerBackend::Pbs
+    ));
+    assert_eq!(clusters[1].name, "gpu");
+    assert!(matches!(
+        clusters[1].scheduler.backend,
+        SchedulerBackend::Pbsnodes { .. }
+    ));
+    assert_eq!(clusters[1].scheduler.retries, 2);
+    assert_eq!(clusters[1].sync.max_automated, Some(Threshold::Count(50)));
+    assert!(clusters[1].topology.is_none());
+    assert_eq!(clusters[2].topology.as_deref(), Some("/etc/ctt/casper.csv"));
+    assert!(clusters[2].node_types.is_empty());
+    let attrs = clusters[3].topology_attributes.as_ref().unwrap();
+    assert_eq!(attrs.blade.as_deref(), Some("blade"));
+    assert!(attrs.card.is_none());
+    assert_eq!(attrs.domains["switch"], "leaf");
+    assert_eq!(
+        clusters[3].sync.flapping,
+        Some(Flapping {
+            transitions: 6,
+            window: 3600
+        })
+    );
+}
+
+#[test]
+fn thresholds() {
+    let parse = |s: &str| Threshold::try_from(s.to_string());
+    assert_eq!(parse("50"), Ok(Threshold::Count(50)));
+    assert_eq!(parse("12.5%"), Ok(Threshold::Percent(12.5)));
+    assert!(parse("-1").is_err());
+    assert!(parse("150%").is_err());
+    assert!(parse("lots").is_err());
+    assert_eq!(Threshold::Count(50).limit(10), 50);
+    assert_eq!(Threshold::Percent(10.0).limit(2489), 248);
+    assert_eq!(String::from(Threshold::Percent(10.0)), "10%");
 }
@@ -1,12 +1,15 @@
 use super::{comment, target};
-use crate::cluster::ClusterTrait;
-use crate::cluster::RegexCluster;
+use crate::cluster::Clusters;
+use crate::hostlist;
 use async_graphql::*;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tracing::warn;
 
+/// kind of the issue ctt opens for a flapping node
+pub const FLAPPING: &str = "flapping";
+
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize, SimpleObject)]
 #[sea_orm(table_name = "issue")]
 #[graphql(concrete(name = "Issue", params()), complex)]
@@ -17,12 +20,24 @@ pub struct Model {
     pub created_by: String,
     pub description: String,
     pub to_offline: Option<ToOffline>,
+    /// failure domain level, eg switch or pdu, taken out when `to_offline` is `Domain`
+    pub domain: Option<String>,
     #[sea_orm(primary_key, auto_increment = true)]
     pub id: i32,
     pub status: IssueStatus,
     #[graphql(skip)]
     pub target_id: i32,
     pub title: String,
+    /// start of planned maintenance, nodes are reserved until then instead of offlined (utc)
+    pub scheduled_start: Option<chrono::NaiveDateTime>,
+    /// end of planned maintenance (utc)
+    pub scheduled_end: Option<chrono::NaiveDateTime>,
+    /// id of the scheduler reservation held for the maintenance window
+    #[graphql(skip)]
+    pub reservation: Option<String>,
+    /// what ctt opened the issue for, eg `flapping`, None for every other issue
+    #[graphql(skip)]
+    pub kind: Option<String>,
 }
 
 #[ComplexObject]
@@ -49,7 +64,7 @@ impl Model {
     }
     pub async fn related(&self, ctx: &Context<'_>) -> Vec<target::Model> {
         let db = ctx.data::<Arc<DatabaseConnection>>().unwrap().as_ref();
-        let cluster = ctx.data::<RegexCluster>().unwrap();
+        let clusters = ctx.data::<Arc<Clusters>>().unwrap();
         let mut related: Vec<target::Model> = vec![];
         let tar = self.target(ctx).await;
         if let Err(e) = tar {
@@ -57,28 +72,36 @@ impl Model {
             return related;
         };
         let tar = tar.unwrap().unwrap();
+        let Some(cluster) = clusters.get(&tar.cluster) else {
+            warn!("{} is in unknown cluster {}", tar.name, tar.cluster);
+            return vec![tar];
+        };
         match self.to_offline {
-            Some(ToOffline::Card) => {
-                for t in cluster.siblings(&tar.name) {
-                    if let Some(tmp) = target::Entity::from_name(&t, db, cluster).await {
-                        related.push(tmp);
-                    }
-                }
-            }
-            Some(ToOffline::Blade) => {
-                for t in cluster.cousins(&tar.name) {
+            Some(t) if t != ToOffline::Node || tar.kind != target::NODE => {
+                for t in cluster.group(&tar.name, t, self.domain.as_deref()) {
                     if let Some(tmp) = target::Entity::from_name(&t, db, cluster).await {
                         related.push(tmp);
                     }
                 }
             }
             _ => {
-                //target is related if ToOffline is Node or None
+                //node is related if ToOffline is Node or None
                 related.push(tar)
             }
         }
         related
     }
+    /// the related nodes as a compressed hostlist, eg `gu[0001-0004]`
+    pub async fn related_hostlist(&self, ctx: &Context<'_>) -> String {
+        let names: Vec<String> = self
+            .related(ctx)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        hostlist::compress(&names)
+    }
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -155,4 +178,11 @@ pub enum ToOffline {
     Card,
     #[sea_orm(string_value = "Blade")]
     Blade,
+    #[sea_orm(string_value = "Chassis")]
+    Chassis,
+    #[sea_orm(string_value = "Rack")]
+    Rack,
+    /// every node sharing the issue's named failure domain with the target
+    #[sea_orm(string_value = "Domain")]
+    Domain,
 }
@@ -1,4 +1,7 @@
 pub mod comment;
 pub mod issue;
+pub mod planned_action;
 pub mod prelude;
+pub mod state_change;
+pub mod tag;
 pub mod target;
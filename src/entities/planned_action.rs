@@ -0,0 +1,111 @@
+use async_graphql::*;
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::ActiveValue;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// something the sync loop would have done to a node in observe mode
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize, SimpleObject)]
+#[sea_orm(table_name = "planned_action")]
+#[graphql(concrete(name = "PlannedAction", params()))]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[graphql(skip)]
+    pub id: i32,
+    pub cluster: String,
+    /// node, or infrastructure for issue changes
+    pub target: String,
+    pub action: PlannedActionKind,
+    /// comment the node would get or title of the issue
+    pub detail: String,
+    /// issue the action is for
+    pub issue_id: Option<i32>,
+    /// first sync that planned it
+    pub created_at: chrono::NaiveDateTime,
+    /// latest sync that planned it, every sync plans it again until it no longer would be done
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(
+    Copy,
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    EnumIter,
+    DeriveActiveEnum,
+    async_graphql::Enum,
+    Serialize,
+    Deserialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "action")]
+pub enum PlannedActionKind {
+    #[sea_orm(string_value = "Offline")]
+    Offline,
+    #[sea_orm(string_value = "Release")]
+    Release,
+    /// change the comment on a node ctt already offlined
+    #[sea_orm(string_value = "UpdateComment")]
+    UpdateComment,
+    #[sea_orm(string_value = "OpenIssue")]
+    OpenIssue,
+    /// close every issue on a down node that came back up
+    #[sea_orm(string_value = "CloseIssues")]
+    CloseIssues,
+    #[sea_orm(string_value = "Reserve")]
+    Reserve,
+    #[sea_orm(string_value = "DeleteReservation")]
+    DeleteReservation,
+}
+
+impl Entity {
+    /// note that the sync loop would have done `action`, an identical plan from an earlier sync
+    /// is only bumped
+    pub async fn record(
+        db: &DatabaseConnection,
+        cluster: &str,
+        target: &str,
+        action: PlannedActionKind,
+        detail: &str,
+        issue: Option<i32>,
+    ) {
+        info!(
+            "observing, not doing {:?} on {} in {}: {}",
+            action, target, cluster, detail
+        );
+        let now = Utc::now().naive_utc();
+        let existing = Self::find()
+            .filter(Column::Cluster.eq(cluster))
+            .filter(Column::Target.eq(target))
+            .filter(Column::Action.eq(action))
+            .filter(Column::Detail.eq(detail))
+            .one(db)
+            .await
+            .unwrap();
+        if let Some(p) = existing {
+            let mut p: ActiveModel = p.into();
+            p.updated_at = ActiveValue::Set(now);
+            p.update(db).await.unwrap();
+            return;
+        }
+        ActiveModel {
+            cluster: ActiveValue::Set(cluster.to_string()),
+            target: ActiveValue::Set(target.to_string()),
+            action: ActiveValue::Set(action),
+            detail: ActiveValue::Set(detail.to_string()),
+            issue_id: ActiveValue::Set(issue),
+            created_at: ActiveValue::Set(now),
+            updated_at: ActiveValue::Set(now),
+            ..Default::default()
+        }
+        .insert(db)
+        .await
+        .unwrap();
+    }
+}
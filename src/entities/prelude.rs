@@ -1,4 +1,9 @@
 #[allow(unused_imports)]
 pub use super::comment::Entity as Comment;
 pub use super::issue::Entity as Issue;
+pub use super::planned_action::Entity as PlannedAction;
+#[allow(unused_imports)]
+pub use super::state_change::Entity as StateChange;
+#[allow(unused_imports)]
+pub use super::tag::Entity as Tag;
 pub use super::target::Entity as Target;
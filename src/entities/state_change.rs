@@ -0,0 +1,41 @@
+use super::target::TargetStatus;
+use async_graphql::*;
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// a change in a target's status seen by the sync loop
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize, SimpleObject)]
+#[sea_orm(table_name = "state_change")]
+#[graphql(concrete(name = "StateChange", params()))]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[graphql(skip)]
+    pub id: i32,
+    #[graphql(skip)]
+    pub target_id: i32,
+    pub old_status: TargetStatus,
+    pub new_status: TargetStatus,
+    pub changed_at: chrono::NaiveDateTime,
+    /// ctt offlined or resumed the node, these don't count towards flapping
+    pub by_ctt: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::target::Entity",
+        from = "Column::TargetId",
+        to = "super::target::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    Target,
+}
+
+impl Related<super::target::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Target.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
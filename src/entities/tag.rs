@@ -0,0 +1,49 @@
+use async_graphql::*;
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize, SimpleObject)]
+#[sea_orm(table_name = "tag")]
+#[graphql(concrete(name = "Tag", params()))]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[graphql(skip)]
+    pub id: i32,
+    #[graphql(skip)]
+    pub target_id: i32,
+    pub name: String,
+    /// set from the node type's tags, these are replaced every sync
+    pub from_config: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::target::Entity",
+        from = "Column::TargetId",
+        to = "super::target::Column::Id",
+        on_update = "Cascade",
+        on_delete = "Cascade"
+    )]
+    Target,
+}
+
+impl Related<super::target::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Target.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// tags are letters, numbers, `-`, `_` and `.`, so they can't be mistaken for a hostlist
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(format!("bad tag {:?}", name));
+    }
+    Ok(())
+}
@@ -1,27 +1,87 @@
-use super::issue;
+use super::{issue, state_change, tag};
 use crate::cluster::ClusterTrait;
-use crate::cluster::RegexCluster;
 use async_graphql::*;
 use sea_orm::entity::prelude::*;
-use sea_orm::{ActiveValue, QueryOrder};
+use sea_orm::sea_query::SimpleExpr;
+use sea_orm::{ActiveValue, QueryOrder, QuerySelect, QueryTrait};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use tracing::{debug, info, instrument, warn};
 
+/// kind of every target that isn't infrastructure
+pub const NODE: &str = "node";
+/// kind of the target cluster wide issues are opened against, it is named after the cluster
+pub const CLUSTER: &str = "cluster";
+
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize, SimpleObject)]
 #[sea_orm(table_name = "target")]
-#[graphql(concrete(name = "Target", params()))]
+#[graphql(concrete(name = "Target", params()), complex)]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = true)]
     #[graphql(skip)]
     pub id: i32,
     pub name: String,
+    /// cluster the node belongs to, node names are only unique within a cluster
+    pub cluster: String,
+    /// `node`, `cluster` or the kind of infrastructure it is, eg switch
+    pub kind: String,
+    /// only tracked for nodes
     pub status: TargetStatus,
+    /// state exactly as the scheduler last reported it
+    pub scheduler_state: Option<String>,
+    pub scheduler_comment: Option<String>,
+    /// comma separated ids of running jobs
+    #[graphql(skip)]
+    pub jobs: Option<String>,
+    pub ncpus: Option<i32>,
+    pub ngpus: Option<i32>,
+    /// memory in MB
+    pub mem: Option<i64>,
+    pub last_state_change: Option<chrono::NaiveDateTime>,
+}
+
+#[ComplexObject]
+impl Model {
+    /// ids of the jobs running on the node at the last sync
+    pub async fn jobs(&self) -> Vec<String> {
+        match &self.jobs {
+            Some(j) if !j.is_empty() => j.split(',').map(|j| j.to_string()).collect(),
+            _ => vec![],
+        }
+    }
+    /// status changes the sync loop has seen, most recent first
+    pub async fn state_changes(&self, ctx: &Context<'_>) -> Vec<state_change::Model> {
+        let db = ctx.data::<Arc<DatabaseConnection>>().unwrap().as_ref();
+        self.find_related(state_change::Entity)
+            .order_by_desc(state_change::Column::ChangedAt)
+            .order_by_desc(state_change::Column::Id)
+            .all(db)
+            .await
+            .unwrap()
+    }
+    /// tags from the node type and set through the api, sorted
+    pub async fn tags(&self, ctx: &Context<'_>) -> Vec<String> {
+        let db = ctx.data::<Arc<DatabaseConnection>>().unwrap().as_ref();
+        self.find_related(tag::Entity)
+            .select_only()
+            .column(tag::Column::Name)
+            .distinct()
+            .order_by_asc(tag::Column::Name)
+            .into_tuple()
+            .all(db)
+            .await
+            .unwrap()
+    }
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::issue::Entity")]
     Issue,
+    #[sea_orm(has_many = "super::tag::Entity")]
+    Tag,
+    #[sea_orm(has_many = "super::state_change::Entity")]
+    StateChange,
 }
 
 impl Related<super::issue::Entity> for Entity {
@@ -30,6 +90,18 @@ impl Related<super::issue::Entity> for Entity {
     }
 }
 
+impl Related<super::state_change::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::StateChange.def()
+    }
+}
+
+impl Related<super::tag::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Tag.def()
+    }
+}
+
 impl Model {
     #[instrument]
     pub fn issues(&self) -> Select<issue::Entity> {
@@ -40,21 +112,38 @@ impl Model {
 impl ActiveModelBehavior for ActiveModel {}
 
 impl Entity {
+    /// every node and piece of infrastructure, targets standing for a whole cluster are left out
     #[instrument]
     pub fn all() -> Select<Entity> {
-        Self::find().order_by_asc(Column::Name)
+        Self::find()
+            .filter(Column::Kind.ne(CLUSTER))
+            .order_by_asc(Column::Name)
+    }
+    /// matches targets with the tag `name`
+    pub fn has_tag(name: &str) -> SimpleExpr {
+        Column::Id.in_subquery(
+            tag::Entity::find()
+                .select_only()
+                .column(tag::Column::TargetId)
+                .filter(tag::Column::Name.eq(name))
+                .into_query(),
+        )
     }
     #[instrument]
-    pub async fn from_name(
+    pub async fn from_name<C: ConnectionTrait + std::fmt::Debug>(
         name: &str,
-        db: &DatabaseConnection,
-        cluster: &RegexCluster,
+        db: &C,
+        cluster: &dyn ClusterTrait,
     ) -> Option<Model> {
-        if !cluster.real_node(name) {
+        if !cluster.real_target(name) {
             debug!("request node {} is not real", name);
             return None;
         }
-        let target = Self::find().filter(Column::Name.eq(name)).one(db).await;
+        let target = Self::find()
+            .filter(Column::Name.eq(name))
+            .filter(Column::Cluster.eq(cluster.name()))
+            .one(db)
+            .await;
         if let Err(e) = target {
             warn!("Error getting target {} by name: {}", name, e);
             return None;
@@ -69,16 +158,46 @@ impl Entity {
     }
 
     #[instrument]
-    async fn create_target(
+    async fn create_target<C: ConnectionTrait + std::fmt::Debug>(
         name: &str,
         state: TargetStatus,
-        db: &DatabaseConnection,
-        cluster: &RegexCluster,
+        db: &C,
+        cluster: &dyn ClusterTrait,
     ) -> Option<Model> {
-        if !cluster.real_node(name) {
+        if !cluster.real_target(name) {
             warn!("Tried making target for fake node {}", name);
             return None;
         }
+        let kind = cluster.infrastructure().kind(name).unwrap_or(NODE);
+        Some(Self::insert_target(name, kind, state, db, cluster).await)
+    }
+
+    /// the target standing for the whole of `cluster`, created the first time it is needed
+    #[instrument]
+    pub async fn for_cluster(db: &DatabaseConnection, cluster: &dyn ClusterTrait) -> Model {
+        let target = Self::find()
+            .filter(Column::Name.eq(cluster.name()))
+            .filter(Column::Cluster.eq(cluster.name()))
+            .filter(Column::Kind.eq(CLUSTER))
+            .one(db)
+            .await
+            .unwrap();
+        match target {
+            Some(t) => t,
+            None => {
+                Self::insert_target(cluster.name(), CLUSTER, TargetStatus::Online, db, cluster)
+                    .await
+            }
+        }
+    }
+
+    async fn insert_target<C: ConnectionTrait + std::fmt::Debug>(
+        name: &str,
+        kind: &str,
+        state: TargetStatus,
+        db: &C,
+        cluster: &dyn ClusterTrait,
+    ) -> Model {
         let max = if let Some(t) = Self::find()
             .order_by_desc(Column::Id)
             .one(db)
@@ -91,11 +210,14 @@ impl Entity {
         };
         let new_target = ActiveModel {
             name: ActiveValue::Set(name.to_string()),
+            cluster: ActiveValue::Set(cluster.name().to_string()),
+            kind: ActiveValue::Set(kind.to_string()),
             status: ActiveValue::Set(state),
             id: ActiveValue::Set(max + 1),
+            ..Default::default()
         };
         info!("Creating target {:?}", new_target);
-        Some(new_target.insert(db).await.unwrap())
+        new_target.insert(db).await.unwrap()
     }
 }
 
@@ -121,6 +243,9 @@ pub enum TargetStatus {
     Offline,
     #[sea_orm(string_value = "Down")]
     Down,
+    /// the scheduler reported a state ctt doesn't understand
+    #[sea_orm(string_value = "Unknown")]
+    Unknown,
 }
 
 impl TargetStatus {
@@ -130,6 +255,7 @@ impl TargetStatus {
             "Draining" => Some(Self::Draining),
             "Offline" => Some(Self::Offline),
             "Down" => Some(Self::Down),
+            "Unknown" => Some(Self::Unknown),
             _ => None,
         }
     }
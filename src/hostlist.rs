@@ -0,0 +1,193 @@
+//! Hostlist expressions, eg `gu[0001-0032,0040],login1`
+//!
+//! ranges are zero padded to the width of their lower bound, and a name can have several
+//! bracketed parts, `r[1-2]n[1-3]` is every node 1-3 in racks 1 and 2
+use std::collections::{BTreeMap, BTreeSet};
+
+/// refuse to expand lists bigger than this, a typo like `gu[1-9999999]` shouldn't open millions
+/// of issues
+const MAX_NODES: usize = 100_000;
+
+/// every node name in `expr`, in the order written
+pub fn expand(expr: &str) -> Result<Vec<String>, String> {
+    let mut names = Vec::new();
+    for part in split(expr)? {
+        names.extend(expand_part(part)?);
+        if names.len() > MAX_NODES {
+            return Err(format!("{} is more than {} nodes", expr, MAX_NODES));
+        }
+    }
+    if names.is_empty() {
+        return Err("empty hostlist".to_string());
+    }
+    Ok(names)
+}
+
+/// split on the commas outside of brackets, `gu[0001-0002],login1` is `gu[0001-0002]` and
+/// `login1`
+pub fn split(expr: &str) -> Result<Vec<&str>, String> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in expr.char_indices() {
+        match c {
+            '[' if depth == 0 => depth += 1,
+            ']' if depth == 1 => depth -= 1,
+            '[' | ']' => return Err(format!("unbalanced brackets in {}", expr)),
+            ',' if depth == 0 => {
+                parts.push(expr[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(format!("unbalanced brackets in {}", expr));
+    }
+    parts.push(expr[start..].trim());
+    Ok(parts.into_iter().filter(|p| !p.is_empty()).collect())
+}
+
+/// expand a single name, which may have bracketed ranges in it
+fn expand_part(part: &str) -> Result<Vec<String>, String> {
+    let Some(open) = part.find('[') else {
+        return Ok(vec![part.to_string()]);
+    };
+    // split already checked the brackets are balanced
+    let close = open + part[open..].find(']').unwrap();
+    let prefix = &part[..open];
+    let suffixes = expand_part(&part[close + 1..])?;
+    let mut names = Vec::new();
+    for range in part[open + 1..close].split(',') {
+        for num in expand_range(range.trim())? {
+            for suffix in &suffixes {
+                names.push(format!("{}{}{}", prefix, num, suffix));
+                if names.len() > MAX_NODES {
+                    return Err(format!("{} is more than {} nodes", part, MAX_NODES));
+                }
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn expand_range(range: &str) -> Result<Vec<String>, String> {
+    let (lo, hi) = range.split_once('-').unwrap_or((range, range));
+    let bad = || format!("bad range {:?}", range);
+    let start: u32 = lo.parse().map_err(|_| bad())?;
+    let end: u32 = hi.parse().map_err(|_| bad())?;
+    if end < start {
+        return Err(bad());
+    }
+    if (end - start) as usize >= MAX_NODES {
+        return Err(format!("{} is more than {} nodes", range, MAX_NODES));
+    }
+    Ok((start..=end)
+        .map(|n| format!("{:0>width$}", n, width = lo.len()))
+        .collect())
+}
+
+/// shortest hostlist covering `names`, sorted with duplicates dropped
+pub fn compress<S: AsRef<str>>(names: &[S]) -> String {
+    // names are split into a prefix and a trailing number, padded numbers are grouped by width
+    let mut split = Vec::new();
+    let mut plain = BTreeSet::new();
+    for name in names {
+        let name = name.as_ref();
+        let prefix = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        let digits = &name[prefix.len()..];
+        match digits.parse::<u32>() {
+            Ok(num) => {
+                let width = if digits.len() > 1 && digits.starts_with('0') {
+                    digits.len()
+                } else {
+                    0
+                };
+                split.push((prefix, width, digits.len(), num));
+            }
+            Err(_) => {
+                plain.insert(name.to_string());
+            }
+        }
+    }
+    let padded: BTreeSet<(&str, usize)> = split
+        .iter()
+        .filter(|(_, width, _, _)| *width > 0)
+        .map(|(prefix, width, _, _)| (*prefix, *width))
+        .collect();
+    let mut groups: BTreeMap<(&str, usize), BTreeSet<u32>> = BTreeMap::new();
+    for (prefix, width, len, num) in split {
+        // gu1000 belongs with gu0999
+        let width = if width == 0 && padded.contains(&(prefix, len)) {
+            len
+        } else {
+            width
+        };
+        groups.entry((prefix, width)).or_default().insert(num);
+    }
+    let mut parts: Vec<String> = plain.into_iter().collect();
+    for ((prefix, width), nums) in groups {
+        let pad = |n: u32| format!("{:0>width$}", n, width = width);
+        if nums.len() == 1 {
+            parts.push(format!("{}{}", prefix, pad(*nums.first().unwrap())));
+            continue;
+        }
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for n in nums {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == n => *end = n,
+                _ => ranges.push((n, n)),
+            }
+        }
+        let ranges: Vec<String> = ranges
+            .into_iter()
+            .map(|(start, end)| {
+                if start == end {
+                    pad(start)
+                } else {
+                    format!("{}-{}", pad(start), pad(end))
+                }
+            })
+            .collect();
+        parts.push(format!("{}[{}]", prefix, ranges.join(",")));
+    }
+    parts.sort();
+    parts.join(",")
+}
+
+#[test]
+fn expand_hostlists() {
+    assert_eq!(expand("gu0001").unwrap(), vec!["gu0001"]);
+    assert_eq!(
+        expand("gu[0001-0003,0040],login1").unwrap(),
+        vec!["gu0001", "gu0002", "gu0003", "gu0040", "login1"]
+    );
+    assert_eq!(expand("n[9-11]").unwrap(), vec!["n9", "n10", "n11"]);
+    assert_eq!(
+        expand("r[1-2]i0n[0-1]").unwrap(),
+        vec!["r1i0n0", "r1i0n1", "r2i0n0", "r2i0n1"]
+    );
+    assert_eq!(expand(" a , b ,").unwrap(), vec!["a", "b"]);
+    assert!(expand("gu[0001-0003").is_err());
+    assert!(expand("gu0001]").is_err());
+    assert!(expand("gu[[1-2]]").is_err());
+    assert!(expand("gu[3-1]").is_err());
+    assert!(expand("gu[a-b]").is_err());
+    assert!(expand("gu[1-999999]").is_err());
+    assert!(expand("").is_err());
+}
+
+#[test]
+fn compress_hostlists() {
+    assert_eq!(compress(&["gu0001"]), "gu0001");
+    assert_eq!(
+        compress(&["gu0003", "gu0001", "gu0002", "gu0040", "login1", "gu0002"]),
+        "gu[0001-0003,0040],login1"
+    );
+    assert_eq!(compress(&["n9", "n10", "n12"]), "n[9-10,12]");
+    assert_eq!(compress(&["gu0999", "gu1000"]), "gu[0999-1000]");
+    assert_eq!(compress(&["gpu-a", "deg0001"]), "deg0001,gpu-a");
+    assert_eq!(compress::<&str>(&[]), "");
+    let names = expand("dec[0001-0128,0200],deg[01-04]").unwrap();
+    assert_eq!(compress(&names), "dec[0001-0128,0200],deg[01-04]");
+}
@@ -1,14 +1,20 @@
 #![feature(let_chains)]
 #![feature(addr_parse_ascii)]
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+#[cfg(all(test, feature = "bench"))]
+extern crate test;
 mod changelog;
 mod cluster;
 mod conf;
 mod entities;
+mod hostlist;
 mod migrator;
 mod setup;
 mod sync;
+#[cfg(test)]
+mod test_harness;
 use crate::conf::Conf;
-use async_graphql::{extensions::Tracing, http::GraphiQLSource, EmptySubscription, Schema};
+use async_graphql::{http::GraphiQLSource, EmptySubscription, Schema};
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
 use axum::{
     error_handling::HandleErrorLayer,
@@ -19,8 +25,7 @@ use axum::{
 };
 use axum_server::tls_rustls::RustlsConfig;
 use axum_server::Handle;
-use cluster::scheduler::PbsScheduler;
-use cluster::RegexCluster;
+use cluster::Clusters;
 use http::StatusCode;
 use setup::setup_and_connect;
 use std::env;
@@ -75,15 +80,9 @@ async fn main() {
 
     let (tx, rx): (mpsc::Sender<ChangeLogMsg>, mpsc::Receiver<ChangeLogMsg>) = mpsc::channel(10);
     let db = Arc::new(setup_and_connect(&conf.db).await.unwrap());
-    let schema = Schema::build(model::Query, model::Mutation, EmptySubscription)
-        .extension(Tracing)
-        .data(db.clone())
-        .data(tx.clone())
-        .data(RegexCluster::new(
-            conf.node_types.clone(),
-            PbsScheduler::new(pbs::Server::new()),
-        ))
-        .finish();
+    // the api and the sync loop share the clusters, and with them each scheduler connection
+    let clusters = Arc::new(Clusters::from_conf(&conf).expect("Error in cluster config"));
+    let schema = model::schema(db.clone(), tx.clone(), clusters.clone());
 
     // get certificate and private key used by https
     let keys = RustlsConfig::from_pem_file(
@@ -96,7 +95,7 @@ async fn main() {
 
     let handle = Handle::new();
     tokio::spawn(graceful_shutdown(handle.clone()));
-    tokio::spawn(sync::cluster_sync(db.clone(), conf.clone(), tx));
+    tokio::spawn(sync::cluster_sync(db.clone(), clusters, conf.clone(), tx));
     tokio::spawn(changelog::slack_updater(rx, CONFIG.get().unwrap().clone()));
 
     let app = Router::new()
@@ -0,0 +1,66 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // sqlite can only add one column per alter
+        for mut col in [
+            ColumnDef::new(Target::SchedulerState).string().to_owned(),
+            ColumnDef::new(Target::SchedulerComment).string().to_owned(),
+            ColumnDef::new(Target::Jobs).string().to_owned(),
+            ColumnDef::new(Target::Ncpus).integer().to_owned(),
+            ColumnDef::new(Target::Ngpus).integer().to_owned(),
+            ColumnDef::new(Target::Mem).big_integer().to_owned(),
+            ColumnDef::new(Target::LastStateChange)
+                .date_time()
+                .to_owned(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Target::Table)
+                        .add_column(&mut col)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for col in [
+            Target::SchedulerState,
+            Target::SchedulerComment,
+            Target::Jobs,
+            Target::Ncpus,
+            Target::Ngpus,
+            Target::Mem,
+            Target::LastStateChange,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Target::Table)
+                        .drop_column(col)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Target {
+    Table,
+    SchedulerState,
+    SchedulerComment,
+    Jobs,
+    Ncpus,
+    Ngpus,
+    Mem,
+    LastStateChange,
+}
@@ -0,0 +1,41 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // targets from before multiple clusters were supported belong to the default cluster
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Target::Table)
+                    .add_column(
+                        ColumnDef::new(Target::Cluster)
+                            .string()
+                            .not_null()
+                            .default(crate::conf::DEFAULT_CLUSTER),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Target::Table)
+                    .drop_column(Target::Cluster)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Target {
+    Table,
+    Cluster,
+}
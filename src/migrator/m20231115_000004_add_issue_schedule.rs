@@ -0,0 +1,52 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // sqlite can only add one column per alter
+        for mut col in [
+            ColumnDef::new(Issue::ScheduledStart).date_time().to_owned(),
+            ColumnDef::new(Issue::ScheduledEnd).date_time().to_owned(),
+            ColumnDef::new(Issue::Reservation).string().to_owned(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Issue::Table)
+                        .add_column(&mut col)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for col in [
+            Issue::ScheduledStart,
+            Issue::ScheduledEnd,
+            Issue::Reservation,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Issue::Table)
+                        .drop_column(col)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum Issue {
+    Table,
+    ScheduledStart,
+    ScheduledEnd,
+    Reservation,
+}
@@ -0,0 +1,71 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PlannedAction::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PlannedAction::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(PlannedAction::Cluster).string().not_null())
+                    .col(ColumnDef::new(PlannedAction::Target).string().not_null())
+                    .col(ColumnDef::new(PlannedAction::Action).string().not_null())
+                    .col(ColumnDef::new(PlannedAction::Detail).string().not_null())
+                    .col(ColumnDef::new(PlannedAction::IssueId).integer())
+                    .col(
+                        ColumnDef::new(PlannedAction::CreatedAt)
+                            .date_time()
+                            .default(Expr::current_timestamp())
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PlannedAction::UpdatedAt)
+                            .date_time()
+                            .default(Expr::current_timestamp())
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("planned_action_target")
+                    .table(PlannedAction::Table)
+                    .col(PlannedAction::Cluster)
+                    .col(PlannedAction::Target)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PlannedAction::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum PlannedAction {
+    Table,
+    Id,
+    Cluster,
+    Target,
+    Action,
+    Detail,
+    IssueId,
+    CreatedAt,
+    UpdatedAt,
+}
@@ -0,0 +1,104 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(StateChange::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(StateChange::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(StateChange::TargetId).integer().not_null())
+                    .col(ColumnDef::new(StateChange::OldStatus).string().not_null())
+                    .col(ColumnDef::new(StateChange::NewStatus).string().not_null())
+                    .col(
+                        ColumnDef::new(StateChange::ChangedAt)
+                            .date_time()
+                            .default(Expr::current_timestamp())
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(StateChange::ByCtt)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("target")
+                            .from(StateChange::Table, StateChange::TargetId)
+                            .to(Target::Table, Target::Id)
+                            .on_delete(ForeignKeyAction::Cascade)
+                            .on_update(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("state_change_target")
+                    .table(StateChange::Table)
+                    .col(StateChange::TargetId)
+                    .col(StateChange::ChangedAt)
+                    .to_owned(),
+            )
+            .await?;
+        // marks the issues ctt opens for flapping nodes, existing issues have no kind
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Issue::Table)
+                    .add_column(ColumnDef::new(Issue::Kind).string())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Issue::Table)
+                    .drop_column(Issue::Kind)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_table(Table::drop().table(StateChange::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum StateChange {
+    Table,
+    Id,
+    TargetId,
+    OldStatus,
+    NewStatus,
+    ChangedAt,
+    ByCtt,
+}
+
+#[derive(DeriveIden)]
+enum Issue {
+    Table,
+    Kind,
+}
+
+#[derive(DeriveIden)]
+enum Target {
+    Table,
+    Id,
+}
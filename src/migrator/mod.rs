@@ -1,12 +1,30 @@
 use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20231015_000002_add_node_snapshot;
+mod m20231101_000003_add_target_cluster;
+mod m20231115_000004_add_issue_schedule;
+mod m20231201_000005_add_issue_domain;
+mod m20231215_000006_create_tag_table;
+mod m20240101_000007_add_target_kind;
+mod m20240115_000008_create_planned_action_table;
+mod m20240201_000009_create_state_change_table;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20231015_000002_add_node_snapshot::Migration),
+            Box::new(m20231101_000003_add_target_cluster::Migration),
+            Box::new(m20231115_000004_add_issue_schedule::Migration),
+            Box::new(m20231201_000005_add_issue_domain::Migration),
+            Box::new(m20231215_000006_create_tag_table::Migration),
+            Box::new(m20240101_000007_add_target_kind::Migration),
+            Box::new(m20240115_000008_create_planned_action_table::Migration),
+            Box::new(m20240201_000009_create_state_change_table::Migration),
+        ]
     }
 }
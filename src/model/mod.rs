@@ -1,7 +1,25 @@
-use async_graphql::{EmptySubscription, Schema};
+use crate::cluster::Clusters;
+use crate::ChangeLogMsg;
+use async_graphql::{extensions::Tracing, EmptySubscription, Schema};
+use sea_orm::DatabaseConnection;
+use std::sync::Arc;
+use tokio::sync::mpsc;
 pub mod mutation;
 mod query;
 pub use mutation::{Mutation, NewIssue};
 pub use query::Query;
 
 pub type CttSchema = Schema<query::Query, mutation::Mutation, EmptySubscription>;
+
+pub fn schema(
+    db: Arc<DatabaseConnection>,
+    tx: mpsc::Sender<ChangeLogMsg>,
+    clusters: Arc<Clusters>,
+) -> CttSchema {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .extension(Tracing)
+        .data(db)
+        .data(tx)
+        .data(clusters)
+        .finish()
+}
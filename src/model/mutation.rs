@@ -1,15 +1,20 @@
 use crate::auth::{Role, RoleChecker, RoleGuard};
-use crate::cluster::{ClusterTrait, RegexCluster};
+use crate::cluster::{ClusterTrait, Clusters};
 use crate::entities::comment;
 use crate::entities::issue::{self, IssueStatus, ToOffline};
 use crate::entities::prelude::*;
-use crate::entities::target::TargetStatus;
+use crate::entities::tag;
+use crate::entities::target::{self, TargetStatus};
+use crate::hostlist;
 use crate::ChangeLogMsg;
 use async_graphql::{Context, InputObject, Object, Result};
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 use sea_orm::entity::ActiveValue;
 use sea_orm::EntityTrait;
-use sea_orm::{ActiveModelTrait, ColumnTrait, DatabaseConnection, QueryFilter};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, QueryFilter, QuerySelect,
+    TransactionTrait,
+};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tracing::{info, instrument, warn};
@@ -20,17 +25,33 @@ pub struct UpdateIssue {
     description: Option<String>,
     enforce_down: Option<bool>,
     to_offline: Option<issue::ToOffline>,
+    /// failure domain to take out with `to_offline` DOMAIN, eg switch
+    domain: Option<String>,
     id: i32,
     title: Option<String>,
+    scheduled_start: Option<NaiveDateTime>,
+    scheduled_end: Option<NaiveDateTime>,
 }
 
-#[derive(InputObject, Debug)]
+#[derive(InputObject, Clone, Debug)]
 pub struct NewIssue {
     assigned_to: Option<String>,
     description: String,
     to_offline: Option<issue::ToOffline>,
+    /// failure domain to take out with `to_offline` DOMAIN, eg switch
+    domain: Option<String>,
+    /// a node or infrastructure name, or for `openMany` a hostlist like `gu[0001-0004]` where
+    /// `@gpu` is every node tagged gpu
     target: String,
+    /// only needed when the node name matches node types in more than one cluster
+    cluster: Option<String>,
     title: String,
+    /// reserve the nodes until this time (utc) instead of offlining them right away
+    scheduled_start: Option<NaiveDateTime>,
+    scheduled_end: Option<NaiveDateTime>,
+    /// set by ctt for issues it opens for a reason it has to find them by, see `issue::Model`
+    #[graphql(skip)]
+    kind: Option<String>,
 }
 
 impl NewIssue {
@@ -41,20 +62,30 @@ impl NewIssue {
         title: String,
         target: String,
         to_offline: Option<issue::ToOffline>,
-        cluster: &RegexCluster,
+        cluster: &dyn ClusterTrait,
     ) -> Option<Self> {
         if cluster.real_node(&target) {
             Some(Self {
                 assigned_to,
                 description,
                 to_offline,
+                domain: None,
                 target,
+                cluster: Some(cluster.name().to_string()),
                 title,
+                scheduled_start: None,
+                scheduled_end: None,
+                kind: None,
             })
         } else {
             None
         }
     }
+
+    pub fn with_kind(mut self, kind: Option<String>) -> Self {
+        self.kind = kind;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -73,6 +104,20 @@ async fn issue_update(
         return Err(format!("Issue {} not found", i.id));
     }
     let issue = issue.unwrap();
+    check_schedule(
+        i.scheduled_start.or(issue.scheduled_start),
+        i.scheduled_end.or(issue.scheduled_end),
+    )?;
+    let to_offline = i.to_offline.or(issue.to_offline);
+    let domain = if to_offline == Some(ToOffline::Domain) {
+        i.domain.clone().or(issue.domain.clone())
+    } else {
+        i.domain.clone()
+    };
+    if to_offline == Some(ToOffline::Domain) || domain.is_some() {
+        let (target, cluster) = issue_cluster(&issue, ctx).await?;
+        check_domain(&target.name, to_offline, domain.as_deref(), cluster)?;
+    }
     let mut updated_issue: issue::ActiveModel = issue.clone().into();
     if let Some(s) = &i.assigned_to
         && i.assigned_to != issue.assigned_to
@@ -140,6 +185,64 @@ async fn issue_update(
         };
         c.insert(db).await.unwrap();
     }
+    // only kept while the issue takes out a domain
+    let domain = domain.filter(|_| to_offline == Some(ToOffline::Domain));
+    if domain != issue.domain {
+        updated_issue.domain = ActiveValue::Set(domain.clone());
+        let c = comment::ActiveModel {
+            created_by: ActiveValue::Set(operator.to_string()),
+            comment: ActiveValue::Set(format!(
+                "Updating domain from {:?} to {:?}",
+                issue.domain, domain
+            )),
+            issue_id: ActiveValue::Set(issue.id),
+            ..Default::default()
+        };
+        c.insert(db).await.unwrap();
+    }
+    if let Some(start) = i.scheduled_start
+        && Some(start) != issue.scheduled_start
+    {
+        updated_issue.scheduled_start = ActiveValue::Set(Some(start));
+        let c = comment::ActiveModel {
+            created_by: ActiveValue::Set(operator.to_string()),
+            comment: ActiveValue::Set(format!(
+                "Updating scheduled_start from {:?} to {}",
+                issue.scheduled_start, start
+            )),
+            issue_id: ActiveValue::Set(issue.id),
+            ..Default::default()
+        };
+        c.insert(db).await.unwrap();
+    }
+    if let Some(end) = i.scheduled_end
+        && Some(end) != issue.scheduled_end
+    {
+        updated_issue.scheduled_end = ActiveValue::Set(Some(end));
+        let c = comment::ActiveModel {
+            created_by: ActiveValue::Set(operator.to_string()),
+            comment: ActiveValue::Set(format!(
+                "Updating scheduled_end from {:?} to {}",
+                issue.scheduled_end, end
+            )),
+            issue_id: ActiveValue::Set(issue.id),
+            ..Default::default()
+        };
+        c.insert(db).await.unwrap();
+    }
+    // the reservation no longer covers the right nodes or window, the sync loop makes a new one
+    if let Some(id) = &issue.reservation
+        && (updated_issue.scheduled_start.is_set()
+            || updated_issue.scheduled_end.is_set()
+            || updated_issue.to_offline.is_set()
+            || updated_issue.domain.is_set())
+    {
+        let (_, cluster) = issue_cluster(&issue, ctx).await?;
+        if let Err(e) = cluster.delete_reservation(id).await {
+            warn!("Error deleting reservation {}: {}", id, e);
+        }
+        updated_issue.reservation = ActiveValue::Set(None);
+    }
     info!("Updating issue {}: {:?}", issue.id, updated_issue);
     let _ = tx
         .send(ChangeLogMsg::Update {
@@ -152,61 +255,50 @@ async fn issue_update(
     // needs to happen before node state check so that crate::sync::desired_state uses the new
     // to_offline value for this issue
     updated_issue.updated_at = ActiveValue::Set(Utc::now().naive_utc());
-    updated_issue.update(db).await.unwrap();
-    //TODO FIXME how to handle a reduction in to_offline? (blade->card->node)
+    let updated = updated_issue.update(db).await.unwrap();
+    //TODO FIXME how to handle a reduction in to_offline? (rack->chassis->blade->card->node)
     //sync code doesn't know a node was offline due to being a sibling, so it will
     //open a new ticket for the sibling instead of resuming it
     //resuming nodes here for now instead of the sync loop since its easier
     if let Some(t_o) = issue.to_offline
-        && i.to_offline.is_some()
-        && i.to_offline != issue.to_offline
+        && (updated.to_offline != issue.to_offline || updated.domain != issue.domain)
     {
-        let cluster = ctx.data::<RegexCluster>().unwrap();
-        let target = issue.target(ctx).await.unwrap().unwrap().name;
-        let cousins = cluster.cousins(&target);
-        let siblings = cluster.siblings(&target);
+        let (target, cluster) = issue_cluster(&issue, ctx).await?;
+        let target = target.name;
+        let old = cluster.group(&target, t_o, issue.domain.as_deref());
+        let new = updated
+            .to_offline
+            .map(|t| cluster.group(&target, t, updated.domain.as_deref()))
+            .unwrap_or_default();
 
-        //t_o != i.to_offline therefore issue no longer enforces cousins being down
-        if t_o == issue::ToOffline::Blade {
-            //resume cousins (but not siblings)
-            for c in cousins {
-                if c == target || siblings.contains(&c) {
-                    continue;
-                }
-                let (desired_node_state, _) = crate::sync::desired_state(&c, db, cluster).await;
-                if desired_node_state == TargetStatus::Online {
-                    //TODO add changelog msg
-                    if cluster.release_node(&c).is_err() {
-                        warn!("Error releasing node {}", c);
-                    } else {
-                        let _ = tx
-                            .send(ChangeLogMsg::Resume {
-                                target: c.to_string(),
-                            })
-                            .await;
-                    }
-                }
+        //issue no longer enforces nodes only in the old group being down
+        let mut release = Vec::new();
+        for n in old {
+            if n == target || new.contains(&n) {
+                continue;
+            }
+            let (desired_node_state, _, _) = crate::sync::desired_state(&n, db, cluster).await;
+            if desired_node_state == TargetStatus::Online {
+                release.push(n);
             }
         }
 
-        //t_o is something, and != i.to_offline, so issue no longer enforces sibling being down
-        if i.to_offline.unwrap() == issue::ToOffline::Node {
-            for s in siblings {
-                if s == target {
-                    continue;
-                }
-                let (desired_node_state, _) = crate::sync::desired_state(&s, db, cluster).await;
-                if desired_node_state == TargetStatus::Online {
-                    //TODO add changelog msg
-                    cluster.release_node(&s).unwrap();
-                    if cluster.release_node(&s).is_err() {
-                        warn!("Error releasing node {}", s);
-                    } else {
-                        let _ = tx
-                            .send(ChangeLogMsg::Resume {
-                                target: s.to_string(),
-                            })
-                            .await;
+        if !release.is_empty() {
+            for (node, result) in cluster.release_nodes(&release).await {
+                match result {
+                    Ok(()) => {
+                        let _ = tx.send(ChangeLogMsg::Resume { target: node }).await;
+                    }
+                    // leave a note on the issue, the node needs to be resumed by hand
+                    Err(e) => {
+                        warn!("Error releasing node {}: {}", node, e);
+                        let c = comment::ActiveModel {
+                            created_by: ActiveValue::Set("ctt".to_string()),
+                            comment: ActiveValue::Set(format!("could not resume {}: {}", node, e)),
+                            issue_id: ActiveValue::Set(i.id),
+                            ..Default::default()
+                        };
+                        c.insert(db).await.unwrap();
                     }
                 }
             }
@@ -215,55 +307,67 @@ async fn issue_update(
     Ok(Issue::find_by_id(i.id).one(db).await.unwrap().unwrap())
 }
 
-#[instrument]
-fn node_group(
-    target: &str,
-    group: Option<issue::ToOffline>,
-    cluster: &RegexCluster,
-) -> Vec<String> {
-    match group {
-        None => vec![],
-        Some(issue::ToOffline::Blade) => cluster.cousins(target),
-        Some(issue::ToOffline::Card) => cluster.siblings(target),
-        Some(issue::ToOffline::Node) => {
-            vec![]
+/// target of an issue and the cluster it is in
+async fn issue_cluster<'a>(
+    issue: &issue::Model,
+    ctx: &Context<'a>,
+) -> Result<(target::Model, &'a dyn ClusterTrait), String> {
+    let target = issue.target(ctx).await.unwrap().unwrap();
+    match ctx.data::<Arc<Clusters>>().unwrap().get(&target.cluster) {
+        Some(cluster) => Ok((target, cluster)),
+        None => Err(format!(
+            "{} is in unknown cluster {}",
+            target.name, target.cluster
+        )),
+    }
+}
+
+fn check_schedule(start: Option<NaiveDateTime>, end: Option<NaiveDateTime>) -> Result<(), String> {
+    match (start, end) {
+        (None, Some(_)) => Err("scheduled_end needs a scheduled_start".to_string()),
+        (Some(s), Some(e)) if e <= s => {
+            Err("scheduled_end has to be after scheduled_start".to_string())
         }
+        _ => Ok(()),
     }
 }
 
-#[instrument(skip(status))]
-fn to_offline(
+/// a domain has to be given with, and only with, to_offline Domain, and `target` has to be in it
+fn check_domain(
     target: &str,
-    status: pbs::StatResp,
-    group: Option<issue::ToOffline>,
-    cluster: &RegexCluster,
-) -> Vec<String> {
-    let to_offline = node_group(target, group, cluster);
-    status
-        .resources
-        .into_iter()
-        //only care about nodes in `to_offline`
-        .filter(|n| to_offline.iter().any(|t| n.name().eq(t)))
-        .filter(|t| t.name().ne(target))
-        //only care about ones that aren't already offline
-        .filter(|n| {
-            &pbs::Attrl::Value(pbs::Op::Equal("offline".to_string()))
-                != n.attribs().get("state").unwrap()
-        })
-        .map(|n| n.name())
-        .collect()
+    to_offline: Option<ToOffline>,
+    domain: Option<&str>,
+    cluster: &dyn ClusterTrait,
+) -> Result<(), String> {
+    match (to_offline, domain) {
+        (Some(ToOffline::Domain), None) => Err("to_offline Domain needs a domain".to_string()),
+        (Some(ToOffline::Domain), Some(d)) if cluster.domain(target, d).is_empty() => {
+            Err(format!("{} isn't in a {} domain", target, d))
+        }
+        (Some(ToOffline::Domain), Some(_)) => Ok(()),
+        (_, Some(_)) => Err("domain is only used with to_offline Domain".to_string()),
+        _ => Ok(()),
+    }
 }
+
+/// every check on a new issue that doesn't need the db
+fn check_new_issue(i: &NewIssue, cluster: &dyn ClusterTrait) -> Result<(), String> {
+    if !cluster.real_target(&i.target) {
+        return Err(format!("{} is not a real node", i.target));
+    }
+    check_schedule(i.scheduled_start, i.scheduled_end)?;
+    check_domain(&i.target, i.to_offline, i.domain.as_deref(), cluster)
+}
+
+/// insert `i` unless its node already has an open issue with the same title,
+/// the bool is false when that existing issue is returned instead
 #[instrument]
-pub async fn issue_open(
+async fn insert_issue<C: ConnectionTrait + std::fmt::Debug>(
     i: &NewIssue,
     operator: &str,
-    db: &DatabaseConnection,
-    tx: &mpsc::Sender<ChangeLogMsg>,
-    cluster: &RegexCluster,
-) -> Result<issue::Model, String> {
-    if !cluster.real_node(&i.target) {
-        return Err(format!("{} is not a real node", &i.target));
-    }
+    db: &C,
+    cluster: &dyn ClusterTrait,
+) -> Result<(issue::Model, bool), String> {
     let target = if let Some(t) = Target::from_name(&i.target, db, cluster).await {
         t
     } else {
@@ -278,7 +382,7 @@ pub async fn issue_open(
         .await
         .unwrap()
     {
-        return Ok(i);
+        return Ok((i, false));
     }
     let target_id = target.id;
 
@@ -287,19 +391,16 @@ pub async fn issue_open(
         created_by: ActiveValue::Set(operator.to_string()),
         description: ActiveValue::Set(i.description.clone()),
         to_offline: ActiveValue::Set(i.to_offline),
+        domain: ActiveValue::Set(i.domain.clone()),
         status: ActiveValue::Set(IssueStatus::Opening),
         target_id: ActiveValue::Set(target_id),
         title: ActiveValue::Set(i.title.clone()),
+        scheduled_start: ActiveValue::Set(i.scheduled_start),
+        scheduled_end: ActiveValue::Set(i.scheduled_end),
+        kind: ActiveValue::Set(i.kind.clone()),
         ..Default::default()
     };
     let new_issue = new_issue.insert(db).await.unwrap();
-    let _ = tx
-        .send(ChangeLogMsg::Open {
-            title: i.title.clone(),
-            issue: new_issue.id,
-            operator: operator.to_string(),
-        })
-        .await;
     let c = comment::ActiveModel {
         created_by: ActiveValue::Set(operator.to_string()),
         comment: ActiveValue::Set("Opening issue".to_string()),
@@ -307,7 +408,33 @@ pub async fn issue_open(
         ..Default::default()
     };
     c.insert(db).await.unwrap();
-    Ok(new_issue)
+    Ok((new_issue, true))
+}
+
+async fn send_open(tx: &mpsc::Sender<ChangeLogMsg>, issue: &issue::Model, operator: &str) {
+    let _ = tx
+        .send(ChangeLogMsg::Open {
+            title: issue.title.clone(),
+            issue: issue.id,
+            operator: operator.to_string(),
+        })
+        .await;
+}
+
+#[instrument]
+pub async fn issue_open(
+    i: &NewIssue,
+    operator: &str,
+    db: &DatabaseConnection,
+    tx: &mpsc::Sender<ChangeLogMsg>,
+    cluster: &dyn ClusterTrait,
+) -> Result<issue::Model, String> {
+    check_new_issue(i, cluster)?;
+    let (issue, new) = insert_issue(i, operator, db, cluster).await?;
+    if new {
+        send_open(tx, &issue, operator).await;
+    }
+    Ok(issue)
 }
 
 #[instrument(skip(ctx))]
@@ -328,6 +455,8 @@ async fn issue_close(
         let title = issue.title.clone();
         let mut issue: issue::ActiveModel = issue.into();
         issue.status = ActiveValue::Set(IssueStatus::Closing);
+        // when a flapping node was acknowledged
+        issue.updated_at = ActiveValue::Set(Utc::now().naive_utc());
         issue.update(db).await.unwrap();
         let c = comment::ActiveModel {
             created_by: ActiveValue::Set(operator.clone()),
@@ -353,14 +482,72 @@ async fn issue_close(
 impl Mutation {
     #[graphql(guard = "RoleChecker::new(Role::Admin)")]
     #[instrument(skip(ctx))]
-    async fn open<'a>(&self, ctx: &Context<'a>, issue: NewIssue) -> Result<issue::Model, String> {
+    async fn open<'a>(
+        &self,
+        ctx: &Context<'a>,
+        mut issue: NewIssue,
+    ) -> Result<issue::Model, String> {
         let usr = &ctx.data_opt::<RoleGuard>().unwrap().user;
         let tx = ctx.data_opt::<mpsc::Sender<ChangeLogMsg>>().unwrap();
         let db = ctx.data_opt::<Arc<DatabaseConnection>>().unwrap().as_ref();
-        let cluster = ctx.data::<RegexCluster>().unwrap();
+        let mut nodes = hostlist::expand(&issue.target)?;
+        if nodes.len() != 1 {
+            return Err(format!(
+                "{} is more than one node, use openMany",
+                issue.target
+            ));
+        }
+        issue.target = nodes.remove(0);
+        let Some(cluster) = ctx
+            .data::<Arc<Clusters>>()
+            .unwrap()
+            .find(issue.cluster.as_deref(), &issue.target)
+        else {
+            return Err(format!("{} is not a real node", issue.target));
+        };
 
         issue_open(&issue, usr, db, tx, cluster).await
     }
+    /// open the same issue on every node in `target`, every node is checked first and the
+    /// issues are inserted in one transaction, so either all of them are opened or none are
+    #[graphql(guard = "RoleChecker::new(Role::Admin)")]
+    #[instrument(skip(ctx))]
+    async fn open_many<'a>(
+        &self,
+        ctx: &Context<'a>,
+        issue: NewIssue,
+    ) -> Result<Vec<issue::Model>, String> {
+        let usr = &ctx.data_opt::<RoleGuard>().unwrap().user;
+        let tx = ctx.data_opt::<mpsc::Sender<ChangeLogMsg>>().unwrap();
+        let db = ctx.data_opt::<Arc<DatabaseConnection>>().unwrap().as_ref();
+        let clusters = ctx.data::<Arc<Clusters>>().unwrap();
+        let mut todo = vec![];
+        for (node, cluster) in
+            find_targets(&issue.target, issue.cluster.as_deref(), db, clusters).await?
+        {
+            let i = NewIssue {
+                target: node,
+                ..issue.clone()
+            };
+            check_new_issue(&i, cluster)?;
+            todo.push((i, cluster));
+        }
+        let txn = db.begin().await.unwrap();
+        let mut opened = vec![];
+        for (i, cluster) in &todo {
+            // dropping txn on an error rolls back the nodes already inserted
+            opened.push(insert_issue(i, usr, &txn, *cluster).await?);
+        }
+        txn.commit().await.unwrap();
+        let mut issues = vec![];
+        for (i, new) in opened {
+            if new {
+                send_open(tx, &i, usr).await;
+            }
+            issues.push(i);
+        }
+        Ok(issues)
+    }
     #[graphql(guard = "RoleChecker::new(Role::Admin)")]
     #[instrument(skip(ctx))]
     async fn close<'a>(
@@ -384,4 +571,156 @@ impl Mutation {
 
         issue_update(issue, &usr, ctx).await
     }
+    /// resume automation the sync loop paused in `cluster`, the next sync does everything it
+    /// planned even if that is over the limit
+    #[graphql(guard = "RoleChecker::new(Role::Admin)")]
+    #[instrument(skip(ctx))]
+    async fn resume_automation<'a>(
+        &self,
+        ctx: &Context<'a>,
+        cluster: String,
+        comment: String,
+    ) -> Result<String, String> {
+        let usr: String = ctx.data_opt::<RoleGuard>().unwrap().user.clone();
+        let db = ctx.data_opt::<Arc<DatabaseConnection>>().unwrap().as_ref();
+        let Some(c) = ctx.data::<Arc<Clusters>>().unwrap().get(&cluster) else {
+            return Err(format!("{} is not a cluster", cluster));
+        };
+        let paused = Target::for_cluster(db, c)
+            .await
+            .issues()
+            .filter(issue::Column::Status.is_in([IssueStatus::Open, IssueStatus::Opening]))
+            .all(db)
+            .await
+            .unwrap();
+        if paused.is_empty() {
+            return Err(format!("automation in {} isn't paused", cluster));
+        }
+        for i in paused {
+            issue_close(i.id, usr.clone(), comment.clone(), ctx).await?;
+        }
+        Ok(format!("resumed {}", cluster))
+    }
+    /// add `tags` to every node in `target`
+    #[graphql(guard = "RoleChecker::new(Role::Admin)")]
+    #[instrument(skip(ctx))]
+    async fn tag<'a>(
+        &self,
+        ctx: &Context<'a>,
+        target: String,
+        cluster: Option<String>,
+        tags: Vec<String>,
+    ) -> Result<Vec<target::Model>, String> {
+        let db = ctx.data_opt::<Arc<DatabaseConnection>>().unwrap().as_ref();
+        let clusters = ctx.data::<Arc<Clusters>>().unwrap();
+        for t in &tags {
+            tag::check_name(t)?;
+        }
+        let mut targets = vec![];
+        for (node, cluster) in find_targets(&target, cluster.as_deref(), db, clusters).await? {
+            let Some(t) = Target::from_name(&node, db, cluster).await else {
+                return Err(format!("Node {} does not exist", node));
+            };
+            let existing: Vec<String> = tag::Entity::find()
+                .filter(tag::Column::TargetId.eq(t.id))
+                .filter(tag::Column::FromConfig.eq(false))
+                .select_only()
+                .column(tag::Column::Name)
+                .into_tuple()
+                .all(db)
+                .await
+                .unwrap();
+            for name in tags.iter().filter(|n| !existing.contains(n)) {
+                tag::ActiveModel {
+                    target_id: ActiveValue::Set(t.id),
+                    name: ActiveValue::Set(name.clone()),
+                    from_config: ActiveValue::Set(false),
+                    ..Default::default()
+                }
+                .insert(db)
+                .await
+                .unwrap();
+            }
+            targets.push(t);
+        }
+        Ok(targets)
+    }
+    /// remove `tags` from every node in `target`, tags from the node type come back on the next
+    /// sync
+    #[graphql(guard = "RoleChecker::new(Role::Admin)")]
+    #[instrument(skip(ctx))]
+    async fn untag<'a>(
+        &self,
+        ctx: &Context<'a>,
+        target: String,
+        cluster: Option<String>,
+        tags: Vec<String>,
+    ) -> Result<Vec<target::Model>, String> {
+        let db = ctx.data_opt::<Arc<DatabaseConnection>>().unwrap().as_ref();
+        let clusters = ctx.data::<Arc<Clusters>>().unwrap();
+        let mut targets = vec![];
+        for (node, cluster) in find_targets(&target, cluster.as_deref(), db, clusters).await? {
+            let Some(t) = Target::from_name(&node, db, cluster).await else {
+                return Err(format!("Node {} does not exist", node));
+            };
+            tag::Entity::delete_many()
+                .filter(tag::Column::TargetId.eq(t.id))
+                .filter(tag::Column::FromConfig.eq(false))
+                .filter(tag::Column::Name.is_in(tags.iter().cloned()))
+                .exec(db)
+                .await
+                .unwrap();
+            targets.push(t);
+        }
+        Ok(targets)
+    }
+}
+
+/// nodes named by the hostlist `expr` and their clusters, `@tag` parts are every node with that
+/// tag
+///
+/// errors if any node isn't real, or a tag is on no nodes
+#[instrument(skip(db, clusters))]
+async fn find_targets<'a>(
+    expr: &str,
+    cluster: Option<&str>,
+    db: &DatabaseConnection,
+    clusters: &'a Clusters,
+) -> Result<Vec<(String, &'a dyn ClusterTrait)>, String> {
+    let mut found: Vec<(String, &dyn ClusterTrait)> = vec![];
+    let mut missing = vec![];
+    for part in hostlist::split(expr)? {
+        if let Some(name) = part.strip_prefix('@') {
+            let mut select = target::Entity::all().filter(target::Entity::has_tag(name));
+            if let Some(c) = cluster {
+                select = select.filter(target::Column::Cluster.eq(c));
+            }
+            let tagged = select.all(db).await.unwrap();
+            if tagged.is_empty() {
+                return Err(format!("nothing is tagged {}", name));
+            }
+            for t in tagged {
+                match clusters.get(&t.cluster) {
+                    Some(c) => found.push((t.name, c)),
+                    None => warn!("{} is in unknown cluster {}", t.name, t.cluster),
+                }
+            }
+            continue;
+        }
+        for node in hostlist::expand(part)? {
+            match clusters.find(cluster, &node) {
+                Some(c) if c.real_target(&node) => found.push((node, c)),
+                _ => missing.push(node),
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!(
+            "{} are not real nodes",
+            hostlist::compress(&missing)
+        ));
+    }
+    let mut seen = std::collections::HashSet::new();
+    found.retain(|(node, c)| seen.insert((node.clone(), c.name().to_string())));
+    Ok(found)
 }
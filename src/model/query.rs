@@ -1,7 +1,10 @@
 use crate::auth::{Role, RoleChecker};
+use crate::cluster::Clusters;
 use crate::entities::issue::{self, IssueStatus};
+use crate::entities::planned_action;
 use crate::entities::prelude::*;
 use crate::entities::target;
+use crate::hostlist;
 use async_graphql::{Context, Object};
 use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder};
 use std::sync::Arc;
@@ -19,6 +22,7 @@ impl Query {
         Issue::find_by_id(issue).one(db).await.unwrap()
     }
 
+    /// `target` takes a hostlist, eg `gu[0001-0004]`, and `tag` limits it to nodes with the tag
     #[graphql(guard = "RoleChecker::new(Role::Admin).or(RoleChecker::new(Role::Guest))")]
     #[instrument(skip(ctx))]
     async fn issues<'a>(
@@ -26,7 +30,9 @@ impl Query {
         ctx: &Context<'a>,
         issue_status: Option<issue::IssueStatus>,
         target: Option<String>,
-    ) -> Vec<issue::Model> {
+        cluster: Option<String>,
+        tag: Option<String>,
+    ) -> Result<Vec<issue::Model>, String> {
         let db = ctx.data::<Arc<DatabaseConnection>>().unwrap().as_ref();
         let mut select = target::Entity::find().find_with_related(issue::Entity);
         if let Some(status) = issue_status {
@@ -38,9 +44,17 @@ impl Query {
             );
         }
         if let Some(t) = target {
-            select = select.filter(<target::Entity as sea_orm::EntityTrait>::Column::Name.eq(t));
+            let names = hostlist::expand(&t)?;
+            select =
+                select.filter(<target::Entity as sea_orm::EntityTrait>::Column::Name.is_in(names));
+        }
+        if let Some(c) = cluster {
+            select = select.filter(<target::Entity as sea_orm::EntityTrait>::Column::Cluster.eq(c));
+        }
+        if let Some(t) = tag {
+            select = select.filter(target::Entity::has_tag(&t));
         }
-        select
+        Ok(select
             .order_by_asc(crate::entities::target::Column::Name)
             .all(db)
             .await
@@ -51,6 +65,66 @@ impl Query {
                 acc.append(&mut c);
                 acc
             })
-            .unwrap_or(vec![])
+            .unwrap_or(vec![]))
+    }
+
+    /// nodes ctt is tracking, `target` takes a hostlist
+    #[graphql(guard = "RoleChecker::new(Role::Admin).or(RoleChecker::new(Role::Guest))")]
+    #[instrument(skip(ctx))]
+    async fn targets<'a>(
+        &self,
+        ctx: &Context<'a>,
+        target: Option<String>,
+        cluster: Option<String>,
+        tag: Option<String>,
+    ) -> Result<Vec<target::Model>, String> {
+        let db = ctx.data::<Arc<DatabaseConnection>>().unwrap().as_ref();
+        let mut select = target::Entity::all();
+        if let Some(t) = target {
+            select = select.filter(target::Column::Name.is_in(hostlist::expand(&t)?));
+        }
+        if let Some(c) = cluster {
+            select = select.filter(target::Column::Cluster.eq(c));
+        }
+        if let Some(t) = tag {
+            select = select.filter(target::Entity::has_tag(&t));
+        }
+        Ok(select.all(db).await.unwrap())
+    }
+
+    /// what the sync loop would have done in observe mode, most recently planned first
+    #[graphql(guard = "RoleChecker::new(Role::Admin).or(RoleChecker::new(Role::Guest))")]
+    #[instrument(skip(ctx))]
+    async fn planned_actions<'a>(
+        &self,
+        ctx: &Context<'a>,
+        target: Option<String>,
+        cluster: Option<String>,
+    ) -> Result<Vec<planned_action::Model>, String> {
+        let db = ctx.data::<Arc<DatabaseConnection>>().unwrap().as_ref();
+        let mut select = PlannedAction::find();
+        if let Some(t) = target {
+            select = select.filter(planned_action::Column::Target.is_in(hostlist::expand(&t)?));
+        }
+        if let Some(c) = cluster {
+            select = select.filter(planned_action::Column::Cluster.eq(c));
+        }
+        Ok(select
+            .order_by_desc(planned_action::Column::UpdatedAt)
+            .order_by_asc(planned_action::Column::Id)
+            .all(db)
+            .await
+            .unwrap())
+    }
+
+    /// names of the clusters this instance manages
+    #[graphql(guard = "RoleChecker::new(Role::Admin).or(RoleChecker::new(Role::Guest))")]
+    #[instrument(skip(ctx))]
+    async fn clusters<'a>(&self, ctx: &Context<'a>) -> Vec<String> {
+        ctx.data::<Arc<Clusters>>()
+            .unwrap()
+            .iter()
+            .map(|c| c.name().to_string())
+            .collect()
     }
 }
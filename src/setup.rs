@@ -11,15 +11,25 @@ pub async fn setup_and_connect(db_url: &str) -> Result<DatabaseConnection, DbErr
 
     let schema_manager = SchemaManager::new(&db);
 
-    if !schema_manager.has_table("issue").await?
-        || !schema_manager.has_table("comment").await?
-        || !schema_manager.has_table("target").await?
-    {
-        Migrator::refresh(&db).await?;
-    }
+    // creates the tables for a new db, and applies any pending migrations to an existing one
+    Migrator::up(&db, None).await?;
     assert!(schema_manager.has_table("issue").await?);
     assert!(schema_manager.has_table("comment").await?);
     assert!(schema_manager.has_table("target").await?);
 
     Ok(db)
 }
+
+/// fresh in memory db, for tests
+#[cfg(test)]
+pub async fn setup_in_memory() -> Result<DatabaseConnection, DbErr> {
+    // every pooled connection would get its own in memory db, so only ever use one
+    let mut opts = ConnectOptions::new("sqlite::memory:");
+    opts.max_connections(1)
+        .min_connections(1)
+        .idle_timeout(std::time::Duration::from_secs(3600))
+        .sqlx_logging(false);
+    let db = Database::connect(opts).await?;
+    Migrator::refresh(&db).await?;
+    Ok(db)
+}
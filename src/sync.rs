@@ -1,18 +1,22 @@
-use crate::cluster::scheduler::PbsScheduler;
-use crate::cluster::ClusterTrait;
-use crate::cluster::RegexCluster;
-use crate::conf::Conf;
+use crate::cluster::scheduler::{NodeSnapshot, SchedulerError};
+use crate::cluster::{ClusterTrait, Clusters};
+use crate::conf::{self, Conf, UnknownState};
 use crate::entities;
 use crate::entities::issue::IssueStatus;
 use crate::entities::issue::ToOffline;
+use crate::entities::planned_action::{self, PlannedActionKind};
+use crate::entities::state_change;
 use crate::entities::target::TargetStatus;
 use crate::model::mutation;
 use crate::ChangeLogMsg;
-use pbs::Server;
+use chrono::Utc;
 use sea_orm::prelude::Expr;
-use sea_orm::EntityTrait;
-use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, QueryFilter, QuerySelect};
-use std::collections::HashMap;
+use sea_orm::sea_query::{Query, SimpleExpr};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, QueryFilter, QuerySelect, QueryTrait,
+};
+use sea_orm::{EntityTrait, Select};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 
@@ -21,94 +25,514 @@ use std::time::Duration;
 use tokio::time;
 use tracing::{debug, info, instrument, trace, warn};
 
-#[instrument(skip(db, conf))]
-pub async fn cluster_sync(db: Arc<DatabaseConnection>, conf: Conf, tx: mpsc::Sender<ChangeLogMsg>) {
+#[instrument(skip(db, clusters, conf))]
+pub async fn cluster_sync(
+    db: Arc<DatabaseConnection>,
+    clusters: Arc<Clusters>,
+    conf: Conf,
+    tx: mpsc::Sender<ChangeLogMsg>,
+) {
     let mut interval = time::interval(Duration::from_secs(conf.poll_interval));
-    let mut cluster = RegexCluster::new(conf.node_types.clone(), PbsScheduler::new(Server::new()));
+    // clusters were built from this config, so every one has a sync section
+    let policies: HashMap<String, conf::SyncPolicy> = conf
+        .clusters()
+        .into_iter()
+        .map(|c| (c.name, c.sync))
+        .collect();
     // don't let ticks stack up if a sync takes longer than interval
     interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
     loop {
         interval.tick().await;
         // don't want multiple ctt threads messing with scheduler concurrently
-        let db = db.as_ref();
-        info!("performing sync with pbs");
-        let mut pbs_node_state = cluster.nodes_status();
-        if let Err(ref e) = pbs_node_state
-            && e == "Expired credential"
-        {
-            info!("refreshing conn, existing one has expired");
-            cluster.refresh_conn();
-            pbs_node_state = cluster.nodes_status();
+        for cluster in clusters.iter() {
+            sync_once(db.as_ref(), cluster, &policies[cluster.name()], &tx).await;
         }
-        if let Err(e) = pbs_node_state {
-            warn!("could not get node state from cluster: {}", e);
-            continue;
+    }
+}
+
+/// perform a single sync between ctt and the scheduler
+#[instrument(skip(db, tx))]
+pub async fn sync_once(
+    db: &DatabaseConnection,
+    cluster: &dyn ClusterTrait,
+    conf: &conf::SyncPolicy,
+    tx: &mpsc::Sender<ChangeLogMsg>,
+) {
+    info!("performing sync with scheduler for {}", cluster.name());
+    let mut pbs_node_state = cluster.nodes_status().await;
+    if let Err(SchedulerError::CredentialExpired) = pbs_node_state {
+        info!("refreshing conn, existing one has expired");
+        cluster.refresh_conn();
+        pbs_node_state = cluster.nodes_status().await;
+    }
+    if let Err(e) = pbs_node_state {
+        warn!("could not get node state from cluster: {}", e);
+        return;
+    }
+    let pbs_node_state = pbs_node_state.unwrap();
+    let mut ctt_node_state = get_ctt_nodes(db, cluster).await;
+
+    //add any pbs nodes not in ctt into ctt for tracking
+    pbs_node_state
+        .keys()
+        .filter(|t| !ctt_node_state.contains_key(*t))
+        .filter(|t| cluster.real_node(t))
+        .collect::<Vec<&String>>()
+        .iter()
+        .for_each(|t| {
+            ctt_node_state.insert(t.to_string(), TargetStatus::Online);
+        });
+
+    let flaps = match conf.flapping {
+        Some(f) => flapping_changes(db, cluster, f).await,
+        None => HashMap::new(),
+    };
+
+    // sync ctt and pbs
+    let mut transitions = Vec::new();
+    // issues ctt opens itself, with whether the node is observed
+    let mut openings = Vec::new();
+    for (target, old_state) in &ctt_node_state {
+        if let Some(snapshot) = pbs_node_state.get(target) {
+            let changes = flaps.get(target).copied().unwrap_or_default();
+            let mut t =
+                plan_transition(target, snapshot, old_state, changes, conf, db, cluster).await;
+            if let Some(issue) = t.open.take() {
+                openings.push((target.clone(), issue, t.observe));
+            }
+            transitions.push(t);
+        } else {
+            warn!("{} not found in pbs", target);
+            let observe = conf.observe || cluster.observed(target);
+            let issue = PendingIssue::new("Node not found in pbs", "Node not found in pbs", None);
+            openings.push((target.clone(), issue, observe));
         }
-        let pbs_node_state = pbs_node_state.unwrap();
-        let mut ctt_node_state = get_ctt_nodes(db).await;
-
-        //add any pbs nodes not in ctt into ctt for tracking
-        pbs_node_state
-            .keys()
-            .filter(|t| !ctt_node_state.contains_key(*t))
-            .filter(|t| cluster.real_node(t))
-            .collect::<Vec<&String>>()
-            .iter()
-            .for_each(|t| {
-                ctt_node_state.insert(t.to_string(), TargetStatus::Online);
-            });
-
-        // sync ctt and pbs
-        for (target, old_state) in &ctt_node_state {
-            if let Some((new_state, pbs_comment)) = pbs_node_state.get(target) {
-                handle_transition(target, pbs_comment, old_state, new_state, db, &tx, &cluster)
-                    .await;
-            } else {
-                warn!("{} not found in pbs", target);
-                if let Some(new_issue) = crate::model::NewIssue::new(
-                    None,
-                    "Node not found in pbs".to_string(),
-                    "Node not found in pbs".to_string(),
-                    target.to_string(),
-                    None,
-                    &cluster,
-                ) {
-                    mutation::issue_open(&new_issue, "ctt", db, &tx, &cluster)
-                        .await
-                        .unwrap();
-                }
+    }
+    let offlines: Vec<Option<i32>> = transitions
+        .iter()
+        .filter(|t| !t.observe)
+        .filter_map(|t| match t.action {
+            Some(Action::Offline { issue, .. }) => Some(issue),
+            _ => None,
+        })
+        .collect();
+    let automated = ctt_offlines(db, &offlines).await
+        + openings.iter().filter(|(_, _, observe)| !observe).count();
+    if automation_paused(db, cluster, conf, automated, ctt_node_state.len(), tx).await {
+        for t in transitions.iter_mut().filter(|t| !t.observe) {
+            if let Some(Action::Offline { .. }) = t.action {
+                t.action = None;
+                t.state = t.snapshot.status;
             }
         }
-        entities::issue::Entity::update_many()
-            .col_expr(
-                entities::issue::Column::Status,
-                Expr::value(IssueStatus::Open),
-            )
-            .filter(entities::issue::Column::Status.eq(IssueStatus::Opening))
+        openings.retain(|(_, _, observe)| *observe);
+    }
+    for (target, issue, observe) in openings {
+        open_issue(&target, issue, observe, db, tx, cluster).await;
+    }
+    let mut results = run_actions(&transitions, cluster).await;
+    for t in transitions {
+        let result = results.remove(&t.target);
+        finish_transition(t, result.as_ref(), conf, db, tx, cluster).await;
+    }
+    if let Some(f) = conf.flapping {
+        prune_state_changes(db, cluster, f).await;
+    }
+    sync_reservations(db, cluster, conf).await;
+    sync_tags(db, cluster).await;
+    // other clusters haven't acted on their issues yet, so leave them alone
+    entities::issue::Entity::update_many()
+        .col_expr(
+            entities::issue::Column::Status,
+            Expr::value(IssueStatus::Open),
+        )
+        .filter(entities::issue::Column::Status.eq(IssueStatus::Opening))
+        .filter(in_cluster(cluster))
+        .exec(db)
+        .await
+        .unwrap();
+    entities::issue::Entity::update_many()
+        .col_expr(
+            entities::issue::Column::Status,
+            Expr::value(IssueStatus::Closed),
+        )
+        .filter(entities::issue::Column::Status.eq(IssueStatus::Closing))
+        .filter(in_cluster(cluster))
+        .exec(db)
+        .await
+        .unwrap();
+    info!("scheduler sync complete for {}", cluster.name());
+}
+
+/// issue the sync loop opens for a node it found in a state ctt didn't put it in
+#[derive(Debug)]
+struct PendingIssue {
+    title: String,
+    description: String,
+    to_offline: Option<ToOffline>,
+    /// see `issue::Model::kind`
+    kind: Option<String>,
+}
+
+impl PendingIssue {
+    fn new(title: &str, description: &str, to_offline: Option<ToOffline>) -> Self {
+        Self {
+            title: title.to_string(),
+            description: description.to_string(),
+            to_offline,
+            kind: None,
+        }
+    }
+}
+
+/// open an issue on `target` as ctt, when it is observed only record that it would have been
+async fn open_issue(
+    target: &str,
+    issue: PendingIssue,
+    observe: bool,
+    db: &DatabaseConnection,
+    tx: &mpsc::Sender<ChangeLogMsg>,
+    cluster: &dyn ClusterTrait,
+) {
+    info!("opening issue for {}: {}", target, issue.title);
+    if observe {
+        planned_action::Entity::record(
+            db,
+            cluster.name(),
+            target,
+            PlannedActionKind::OpenIssue,
+            &issue.title,
+            None,
+        )
+        .await;
+        return;
+    }
+    if let Some(new_issue) = crate::model::NewIssue::new(
+        None,
+        issue.description,
+        issue.title,
+        target.to_string(),
+        issue.to_offline,
+        cluster,
+    ) {
+        let new_issue = new_issue.with_kind(issue.kind);
+        mutation::issue_open(&new_issue, "ctt", db, tx, cluster)
+            .await
+            .unwrap();
+    }
+}
+
+/// whether ctt's own offlines and issue openings in `cluster` are held this sync
+///
+/// automation is paused while the cluster has an open issue, and a sync that would offline or
+/// open issues for more than `max_automated` of the `total` nodes opens one. Closing it through
+/// the api lets the next sync through whatever it would do
+#[instrument(skip(db, tx))]
+async fn automation_paused(
+    db: &DatabaseConnection,
+    cluster: &dyn ClusterTrait,
+    conf: &conf::SyncPolicy,
+    automated: usize,
+    total: usize,
+    tx: &mpsc::Sender<ChangeLogMsg>,
+) -> bool {
+    let target = entities::target::Entity::for_cluster(db, cluster).await;
+    let issues = target
+        .issues()
+        .filter(entities::issue::Column::Status.ne(IssueStatus::Closed))
+        .all(db)
+        .await
+        .unwrap();
+    if issues.iter().any(|i| i.status != IssueStatus::Closing) {
+        warn!(
+            "automation in {} is paused, holding {} offlines and issues",
+            cluster.name(),
+            automated
+        );
+        return true;
+    }
+    if !issues.is_empty() {
+        info!("automation in {} was resumed", cluster.name());
+        return false;
+    }
+    let Some(limit) = conf.max_automated.map(|m| m.limit(total)) else {
+        return false;
+    };
+    if automated <= limit {
+        return false;
+    }
+    let title = format!(
+        "automation paused, sync would offline or open issues for {} of {} nodes",
+        automated, total
+    );
+    warn!("{}: {}", cluster.name(), title);
+    let issue = entities::issue::ActiveModel {
+        created_by: ActiveValue::Set("ctt".to_string()),
+        description: ActiveValue::Set(format!(
+            "the limit is {}, resumeAutomation once the scheduler looks right",
+            limit
+        )),
+        status: ActiveValue::Set(IssueStatus::Opening),
+        target_id: ActiveValue::Set(target.id),
+        title: ActiveValue::Set(title.clone()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .unwrap();
+    let _ = tx
+        .send(ChangeLogMsg::Paused {
+            cluster: cluster.name().to_string(),
+            issue: issue.id,
+            title,
+        })
+        .await;
+    true
+}
+
+/// how many of the offlines, each with the issue it is for, ctt started on its own
+///
+/// offlines for issues admins opened, like planned maintenance on a rack, don't count towards
+/// `max_automated`
+async fn ctt_offlines(db: &DatabaseConnection, offlines: &[Option<i32>]) -> usize {
+    let ids: Vec<i32> = offlines
+        .iter()
+        .flatten()
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let mut by_ctt = HashSet::new();
+    // batched to stay under sqlite's limit on bound parameters
+    for ids in ids.chunks(500) {
+        by_ctt.extend(
+            entities::issue::Entity::find()
+                .select_only()
+                .column(entities::issue::Column::Id)
+                .filter(entities::issue::Column::Id.is_in(ids.iter().copied()))
+                .filter(entities::issue::Column::CreatedBy.eq("ctt"))
+                .into_tuple::<i32>()
+                .all(db)
+                .await
+                .unwrap(),
+        );
+    }
+    offlines
+        .iter()
+        .filter(|i| i.is_none_or(|id| by_ctt.contains(&id)))
+        .count()
+}
+
+/// matches issues against nodes in `cluster`
+fn in_cluster(cluster: &dyn ClusterTrait) -> SimpleExpr {
+    entities::issue::Column::TargetId.in_subquery(
+        entities::target::Entity::find()
+            .select_only()
+            .column(entities::target::Column::Id)
+            .filter(entities::target::Column::Cluster.eq(cluster.name()))
+            .into_query(),
+    )
+}
+
+/// bring the tags targets get from their node type in line with the config
+///
+/// tags set through the api are left alone
+#[instrument(skip(db))]
+async fn sync_tags(db: &DatabaseConnection, cluster: &dyn ClusterTrait) {
+    use entities::tag;
+    let targets = entities::target::Entity::find()
+        .filter(entities::target::Column::Cluster.eq(cluster.name()))
+        .all(db)
+        .await
+        .unwrap();
+    let mut existing: HashMap<(i32, String), i32> = tag::Entity::find()
+        .filter(tag::Column::FromConfig.eq(true))
+        .filter(
+            tag::Column::TargetId.in_subquery(
+                entities::target::Entity::find()
+                    .select_only()
+                    .column(entities::target::Column::Id)
+                    .filter(entities::target::Column::Cluster.eq(cluster.name()))
+                    .into_query(),
+            ),
+        )
+        .all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|t| ((t.target_id, t.name), t.id))
+        .collect();
+    let mut new_tags = vec![];
+    for t in &targets {
+        for name in cluster.tags(&t.name) {
+            if existing.remove(&(t.id, name.clone())).is_none() {
+                new_tags.push(tag::ActiveModel {
+                    target_id: ActiveValue::Set(t.id),
+                    name: ActiveValue::Set(name),
+                    from_config: ActiveValue::Set(true),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    // anything left over was dropped from the config, batched to stay under sqlite's limit on
+    // bound parameters
+    let stale: Vec<i32> = existing.into_values().collect();
+    if !stale.is_empty() {
+        info!("removing {} tags no longer in the config", stale.len());
+    }
+    for ids in stale.chunks(500) {
+        tag::Entity::delete_many()
+            .filter(tag::Column::Id.is_in(ids.iter().copied()))
             .exec(db)
             .await
             .unwrap();
-        entities::issue::Entity::update_many()
-            .col_expr(
-                entities::issue::Column::Status,
-                Expr::value(IssueStatus::Closed),
-            )
-            .filter(entities::issue::Column::Status.eq(IssueStatus::Closing))
+    }
+    if !new_tags.is_empty() {
+        info!("adding {} tags from the config", new_tags.len());
+    }
+    for batch in new_tags.chunks(500) {
+        tag::Entity::insert_many(batch.to_vec())
             .exec(db)
             .await
             .unwrap();
-        info!("pbs sync complete");
     }
 }
 
+/// matches issues that aren't planned maintenance or whose window has started
+fn started() -> Condition {
+    Condition::any()
+        .add(entities::issue::Column::ScheduledStart.is_null())
+        .add(entities::issue::Column::ScheduledStart.lte(Utc::now().naive_utc()))
+}
+
+/// reserve the nodes of issues with planned maintenance until the window starts
+///
+/// once it has, desired_state has the nodes offlined and the reservation is dropped. Reservations
+/// of issues closed before their window are dropped too
 #[instrument(skip(db))]
-pub async fn get_ctt_nodes(db: &DatabaseConnection) -> HashMap<String, TargetStatus> {
+async fn sync_reservations(
+    db: &DatabaseConnection,
+    cluster: &dyn ClusterTrait,
+    conf: &conf::SyncPolicy,
+) {
+    let now = Utc::now().naive_utc();
+    let issues = entities::issue::Entity::find()
+        .filter(in_cluster(cluster))
+        .filter(entities::issue::Column::ScheduledStart.is_not_null())
+        .filter(
+            Condition::any()
+                .add(entities::issue::Column::Reservation.is_not_null())
+                .add(
+                    entities::issue::Column::Status
+                        .is_in([IssueStatus::Open, IssueStatus::Opening]),
+                ),
+        )
+        .all(db)
+        .await
+        .unwrap();
+    for iss in issues {
+        let start = iss.scheduled_start.unwrap();
+        let open = matches!(iss.status, IssueStatus::Open | IssueStatus::Opening);
+        let target = entities::target::Entity::find_by_id(iss.target_id)
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap();
+        let nodes = iss
+            .to_offline
+            .map(|t| cluster.group(&target.name, t, iss.domain.as_deref()))
+            .unwrap_or_default();
+        let observe = conf.observe || nodes.iter().any(|n| cluster.observed(n));
+        let reservation = match &iss.reservation {
+            None if open && start > now && iss.to_offline.is_some() && observe => {
+                let detail = format!("ctt{} {}", iss.id, crate::hostlist::compress(&nodes));
+                planned_action::Entity::record(
+                    db,
+                    cluster.name(),
+                    &target.name,
+                    PlannedActionKind::Reserve,
+                    &detail,
+                    Some(iss.id),
+                )
+                .await;
+                continue;
+            }
+            None if open && start > now && iss.to_offline.is_some() => {
+                // the reservation only has to last until the window starts, without an end it
+                // gets an hour
+                let end = iss
+                    .scheduled_end
+                    .unwrap_or(start + chrono::Duration::hours(1));
+                let name = format!("ctt{}", iss.id);
+                match cluster.create_reservation(&name, &nodes, start, end).await {
+                    Ok(id) => {
+                        info!("reserved {:?} for issue {} as {}", nodes, iss.id, id);
+                        Some(id)
+                    }
+                    // the nodes are offlined once the window starts like any other issue
+                    Err(SchedulerError::Unsupported(_)) => {
+                        debug!(
+                            "{} can't reserve nodes, skipping reservations",
+                            cluster.name()
+                        );
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("could not reserve nodes for issue {}: {}", iss.id, e);
+                        continue;
+                    }
+                }
+            }
+            Some(id) if (!open || start <= now) && observe => {
+                planned_action::Entity::record(
+                    db,
+                    cluster.name(),
+                    &target.name,
+                    PlannedActionKind::DeleteReservation,
+                    id,
+                    Some(iss.id),
+                )
+                .await;
+                continue;
+            }
+            Some(id) if !open || start <= now => match cluster.delete_reservation(id).await {
+                Ok(()) => None,
+                // don't keep trying to delete a reservation the scheduler won't ever delete
+                Err(e) if !e.is_transient() => {
+                    warn!("dropping reservation {} for issue {}: {}", id, iss.id, e);
+                    None
+                }
+                Err(e) => {
+                    warn!("could not delete reservation {}: {}", id, e);
+                    continue;
+                }
+            },
+            _ => continue,
+        };
+        let mut i: entities::issue::ActiveModel = iss.into();
+        i.reservation = ActiveValue::Set(reservation);
+        i.update(db).await.unwrap();
+    }
+}
+
+/// state of every node ctt tracks in `cluster`, infrastructure isn't in the scheduler so is left
+/// out
+#[instrument(skip(db))]
+pub async fn get_ctt_nodes(
+    db: &DatabaseConnection,
+    cluster: &dyn ClusterTrait,
+) -> HashMap<String, TargetStatus> {
     let ctt_node_state = entities::target::Entity::all()
+        .filter(entities::target::Column::Cluster.eq(cluster.name()))
+        .filter(entities::target::Column::Kind.eq(entities::target::NODE))
         .select_only()
         .columns([
             entities::target::Column::Name,
             entities::target::Column::Status,
             entities::target::Column::Id,
+            entities::target::Column::Cluster,
+            entities::target::Column::Kind,
         ])
         .all(db)
         .await
@@ -119,10 +543,74 @@ pub async fn get_ctt_nodes(db: &DatabaseConnection) -> HashMap<String, TargetSta
         .collect()
 }
 
+/// every level an issue on another node can take `target` out at, with the nodes an issue at
+/// that level has to be on to do it
+fn failure_groups(
+    target: &str,
+    cluster: &dyn ClusterTrait,
+) -> Vec<(ToOffline, Option<String>, Vec<String>)> {
+    let mut groups = vec![
+        (ToOffline::Card, None, cluster.siblings(target)),
+        (ToOffline::Blade, None, cluster.cousins(target)),
+        (ToOffline::Chassis, None, cluster.chassis(target)),
+        (ToOffline::Rack, None, cluster.rack(target)),
+    ];
+    for d in cluster.domains(target) {
+        let nodes = cluster.domain(target, &d);
+        groups.push((ToOffline::Domain, Some(d), nodes));
+    }
+    groups
+}
+
+/// issues taking out `level` (and `domain`) on any of `nodes`
+fn group_issues(
+    cluster: &dyn ClusterTrait,
+    level: ToOffline,
+    domain: Option<String>,
+    nodes: Vec<String>,
+) -> Select<entities::issue::Entity> {
+    let mut issues = entities::issue::Entity::find()
+        .filter(
+            entities::issue::Column::TargetId.in_subquery(
+                entities::target::Entity::find()
+                    .select_only()
+                    .column(entities::target::Column::Id)
+                    .filter(entities::target::Column::Cluster.eq(cluster.name()))
+                    .filter(entities::target::Column::Name.is_in(nodes))
+                    .into_query(),
+            ),
+        )
+        .filter(entities::issue::Column::ToOffline.eq(Some(level)));
+    if let Some(d) = domain {
+        issues = issues.filter(entities::issue::Column::Domain.eq(d));
+    }
+    issues
+}
+
+/// issues on the infrastructure `infra` that take out the nodes behind it, which is any with
+/// to_offline set
+fn infrastructure_issues(
+    cluster: &dyn ClusterTrait,
+    infra: &str,
+) -> Select<entities::issue::Entity> {
+    entities::issue::Entity::find()
+        .filter(
+            entities::issue::Column::TargetId.in_subquery(
+                entities::target::Entity::find()
+                    .select_only()
+                    .column(entities::target::Column::Id)
+                    .filter(entities::target::Column::Cluster.eq(cluster.name()))
+                    .filter(entities::target::Column::Name.eq(infra))
+                    .into_query(),
+            ),
+        )
+        .filter(Expr::col(entities::issue::Column::ToOffline).is_not_null())
+}
+
 pub async fn related_closing(
     target: &str,
     db: &DatabaseConnection,
-    cluster: &RegexCluster,
+    cluster: &dyn ClusterTrait,
 ) -> Vec<entities::issue::Model> {
     let mut issues = Vec::new();
     let t = entities::target::Entity::from_name(target, db, cluster).await;
@@ -142,45 +630,27 @@ pub async fn related_closing(
             t
         }
     };
-    for c in cluster.siblings(target) {
-        match entities::target::Entity::from_name(&c, db, cluster).await {
-            None => warn!("expected sibling {} doesn't exist", c),
-            Some(t) => {
-                if t.name == target {
-                    continue;
-                }
-                for iss in t
-                    .issues()
-                    .filter(entities::issue::Column::Status.eq(IssueStatus::Closing))
-                    .filter(entities::issue::Column::ToOffline.eq(Some(ToOffline::Card)))
-                    .all(db)
-                    .await
-                    .unwrap()
-                {
-                    issues.push(iss);
-                }
-            }
-        };
+    for (level, domain, nodes) in failure_groups(target, cluster) {
+        // the target's own issues were already picked up above
+        let nodes = nodes.into_iter().filter(|n| n != target).collect();
+        for iss in group_issues(cluster, level, domain, nodes)
+            .filter(entities::issue::Column::Status.eq(IssueStatus::Closing))
+            .all(db)
+            .await
+            .unwrap()
+        {
+            issues.push(iss);
+        }
     }
-    for c in cluster.cousins(target) {
-        match entities::target::Entity::from_name(&c, db, cluster).await {
-            None => warn!("expected sibling {} doesn't exist", c),
-            Some(t) => {
-                if t.name == target {
-                    continue;
-                }
-                for iss in t
-                    .issues()
-                    .filter(entities::issue::Column::Status.eq(IssueStatus::Closing))
-                    .filter(entities::issue::Column::ToOffline.eq(Some(ToOffline::Blade)))
-                    .all(db)
-                    .await
-                    .unwrap()
-                {
-                    issues.push(iss);
-                }
-            }
-        };
+    for infra in cluster.infrastructure().serving(target) {
+        for iss in infrastructure_issues(cluster, &infra)
+            .filter(entities::issue::Column::Status.eq(IssueStatus::Closing))
+            .all(db)
+            .await
+            .unwrap()
+        {
+            issues.push(iss);
+        }
     }
     for iss in t
         .issues()
@@ -195,15 +665,16 @@ pub async fn related_closing(
     issues
 }
 
+/// state ctt wants the node in, the comment to set on it, and the issue responsible
 #[instrument(skip(db))]
 pub async fn desired_state(
     target: &str,
     db: &DatabaseConnection,
-    cluster: &RegexCluster,
-) -> (TargetStatus, String) {
+    cluster: &dyn ClusterTrait,
+) -> (TargetStatus, String, Option<i32>) {
     let t = entities::target::Entity::from_name(target, db, cluster).await;
     let t = match t {
-        None => return (TargetStatus::Offline, "Not a real node".to_string()),
+        None => return (TargetStatus::Offline, "Not a real node".to_string(), None),
         Some(t) => {
             if let Some(iss) = t
                 .issues()
@@ -212,57 +683,58 @@ pub async fn desired_state(
                         .is_in([IssueStatus::Open, IssueStatus::Opening]),
                 )
                 .filter(Expr::col(entities::issue::Column::ToOffline).is_not_null())
+                .filter(started())
                 .one(db)
                 .await
                 .unwrap()
             {
                 debug!("Offline due to node ticket");
-                return (TargetStatus::Offline, iss.title);
+                return (TargetStatus::Offline, iss.title, Some(iss.id));
             }
             t
         }
     };
-    for c in cluster.siblings(target) {
-        match entities::target::Entity::from_name(&c, db, cluster).await {
-            None => warn!("expected sibling {} doesn't exist", c),
-            Some(t) => {
-                if t.issues()
-                    .filter(
-                        entities::issue::Column::Status
-                            .is_in([IssueStatus::Open, IssueStatus::Opening]),
-                    )
-                    .filter(entities::issue::Column::ToOffline.eq(Some(ToOffline::Card)))
-                    .one(db)
-                    .await
-                    .unwrap()
-                    .is_some()
-                {
-                    debug!("Offline due to card wide ticket");
-                    return (TargetStatus::Offline, format!("{} sibling", &target));
-                }
-            }
-        };
+    for (level, domain, nodes) in failure_groups(target, cluster) {
+        if let Some(iss) = group_issues(cluster, level, domain, nodes)
+            .filter(
+                entities::issue::Column::Status.is_in([IssueStatus::Open, IssueStatus::Opening]),
+            )
+            .filter(started())
+            .one(db)
+            .await
+            .unwrap()
+        {
+            debug!("Offline due to {:?} wide ticket", level);
+            // name the node the issue is on, same as for infrastructure
+            let origin = entities::target::Entity::find_by_id(iss.target_id)
+                .one(db)
+                .await
+                .unwrap()
+                .map_or_else(|| target.to_string(), |t| t.name);
+            return (
+                TargetStatus::Offline,
+                format!("{}: {}", origin, iss.title),
+                Some(iss.id),
+            );
+        }
     }
-    for c in cluster.cousins(target) {
-        match entities::target::Entity::from_name(&c, db, cluster).await {
-            None => warn!("expected sibling {} doesn't exist", c),
-            Some(t) => {
-                if t.issues()
-                    .filter(
-                        entities::issue::Column::Status
-                            .is_in([IssueStatus::Open, IssueStatus::Opening]),
-                    )
-                    .filter(entities::issue::Column::ToOffline.eq(Some(ToOffline::Blade)))
-                    .one(db)
-                    .await
-                    .unwrap()
-                    .is_some()
-                {
-                    debug!("Offline due to blade wide ticket");
-                    return (TargetStatus::Offline, format!("{} sibling", &target));
-                }
-            }
-        };
+    for infra in cluster.infrastructure().serving(target) {
+        if let Some(iss) = infrastructure_issues(cluster, &infra)
+            .filter(
+                entities::issue::Column::Status.is_in([IssueStatus::Open, IssueStatus::Opening]),
+            )
+            .filter(started())
+            .one(db)
+            .await
+            .unwrap()
+        {
+            debug!("Offline due to ticket on {}", infra);
+            return (
+                TargetStatus::Offline,
+                format!("{}: {}", infra, iss.title),
+                Some(iss.id),
+            );
+        }
     }
     if let Some(iss) = t
         .issues()
@@ -273,14 +745,14 @@ pub async fn desired_state(
         .unwrap()
     {
         debug!("Down due to node ticket");
-        return (TargetStatus::Down, iss.title);
+        return (TargetStatus::Down, iss.title, Some(iss.id));
     }
     trace!("Online due to no related tickets");
-    (TargetStatus::Online, "".to_string())
+    (TargetStatus::Online, "".to_string(), None)
 }
 
 #[instrument(skip(db))]
-pub async fn close_open_issues(target: &str, db: &DatabaseConnection, cluster: &RegexCluster) {
+pub async fn close_open_issues(target: &str, db: &DatabaseConnection, cluster: &dyn ClusterTrait) {
     for issue in entities::target::Entity::from_name(target, db, cluster)
         .await
         .unwrap()
@@ -304,104 +776,417 @@ pub async fn close_open_issues(target: &str, db: &DatabaseConnection, cluster: &
     }
 }
 
-#[instrument(skip(db, tx))]
-#[allow(clippy::too_many_arguments)]
-async fn handle_transition(
+/// what the sync loop decided to do with a node
+#[derive(Debug)]
+struct Transition {
+    target: String,
+    old_state: TargetStatus,
+    snapshot: NodeSnapshot,
+    /// state to record once the action, if any, succeeds
+    state: TargetStatus,
+    action: Option<Action>,
+    /// only record the action, the scheduler isn't touched
+    observe: bool,
+    /// issue to open for the node
+    open: Option<PendingIssue>,
+}
+
+/// scheduler call a transition needs
+#[derive(Debug)]
+enum Action {
+    Offline {
+        comment: String,
+        /// issue the node is being offlined for
+        issue: Option<i32>,
+    },
+    /// node is already offline, but the comment is out of date
+    UpdateComment { comment: String },
+    Release {
+        /// closing issues that were holding the node offline
+        issues: Vec<i32>,
+    },
+}
+
+#[instrument(skip(db))]
+async fn plan_transition(
     target: &str,
-    new_comment: &str,
+    snapshot: &NodeSnapshot,
     old_state: &TargetStatus,
-    new_state: &TargetStatus,
+    // changes that count towards the node flapping, see `flapping_changes`
+    flaps: usize,
+    conf: &conf::SyncPolicy,
     db: &DatabaseConnection,
-    tx: &mpsc::Sender<ChangeLogMsg>,
-    cluster: &RegexCluster,
-) {
-    let (expected_state, comment) = desired_state(target, db, cluster).await;
+    cluster: &dyn ClusterTrait,
+) -> Transition {
+    let new_state = &snapshot.status;
+    let new_comment = &snapshot.comment;
+    let observe = conf.observe || cluster.observed(target);
+    let (expected_state, comment, issue) = desired_state(target, db, cluster).await;
+    let comment = match issue {
+        Some(id) => conf.comment_format.render(id, &comment),
+        None => comment,
+    };
+    // issue ctt offlined the node for, None if the comment wasn't set by ctt
+    let owner = conf.comment_format.parse(new_comment).map(|(id, _)| id);
 
+    let mut open = None;
+    // a flapping node gets its issue instead of yet another one for going down
+    let flapping = match (expected_state, conf.flapping) {
+        (TargetStatus::Offline, _) | (_, None) => None,
+        (_, Some(f)) => flapping_issue(target, flaps, f),
+    };
     //dont use old_state to figure out how to handle nodes
     //things could have changed between when it was collected and now, so only consider
     //the current state (new_state) and the expected_state
-    let final_state = match expected_state {
+    let (final_state, action) = match expected_state {
         TargetStatus::Draining => panic!("Expected state is never Draining"),
+        TargetStatus::Unknown => panic!("Expected state is never Unknown"),
         TargetStatus::Online => {
             if *new_state == TargetStatus::Online {
-                TargetStatus::Online
-            } else if !related_closing(target, db, cluster).await.is_empty() {
-                info!("resuming {}, all open issues are Closing", target);
-                cluster.release_node(target).unwrap();
-                let _ = tx
-                    .send(ChangeLogMsg::Resume {
-                        target: target.to_string(),
-                    })
-                    .await;
-                TargetStatus::Online
+                (TargetStatus::Online, None)
+            } else if *new_state == TargetStatus::Unknown {
+                open = unknown_issue(target, &snapshot.state, conf.unknown_state);
+                (TargetStatus::Unknown, None)
             } else {
-                // expected node to be online, but it wasn't so open an issue
-                // we know no issues are currently open since expected state
-                // would not be online if there were
-                if let Some(new_issue) = crate::model::NewIssue::new(
-                    None,
-                    new_comment.to_string(),
-                    new_comment.to_string(),
-                    target.to_string(),
-                    None,
-                    cluster,
-                ) {
-                    info!("opening issue for {}: {}", target, new_comment);
-                    mutation::issue_open(&new_issue, "ctt", db, tx, cluster)
-                        .await
-                        .unwrap();
+                let closing = related_closing(target, db, cluster).await;
+                if !closing.is_empty() {
+                    info!("resuming {}, all open issues are Closing", target);
+                    let issues = closing.iter().map(|i| i.id).collect();
+                    (TargetStatus::Online, Some(Action::Release { issues }))
+                } else if let Some(id) = owner
+                    && *new_state != TargetStatus::Down
+                {
+                    // releasing doesn't bring down nodes back up, so those still get an issue
+                    info!(
+                        "resuming {}, issue {} it was offlined for isn't open",
+                        target, id
+                    );
+                    (
+                        TargetStatus::Online,
+                        Some(Action::Release { issues: vec![] }),
+                    )
+                } else {
+                    // expected node to be online, but it wasn't so open an issue
+                    // we know no issues are currently open since expected state
+                    // would not be online if there were
+                    let description =
+                        format!("found {:?} outside of ctt: {}", new_state, new_comment);
+                    open = Some(PendingIssue::new(new_comment, &description, None));
+                    (*new_state, None)
                 }
-                *new_state
             }
         }
         TargetStatus::Offline => match new_state {
-            TargetStatus::Draining => TargetStatus::Draining,
-            TargetStatus::Offline => TargetStatus::Offline,
+            TargetStatus::Draining | TargetStatus::Offline => {
+                // keep the comment in sync with the issue, but leave comments ctt didn't set
+                if owner.is_some() && *new_comment != comment {
+                    info!("updating comment on {} to {}", target, comment);
+                    (*new_state, Some(Action::UpdateComment { comment }))
+                } else {
+                    (*new_state, None)
+                }
+            }
             state => {
                 info!("{} found in state {:?}, expected offline", target, state);
-                cluster.offline_node(target, &comment).unwrap();
-                let _ = tx
-                    .send(ChangeLogMsg::Offline {
-                        target: target.to_string(),
-                    })
-                    .await;
-                if *state == TargetStatus::Down {
+                let final_state = if *state == TargetStatus::Down {
                     TargetStatus::Offline
                 } else {
                     // node was online, might have running jobs
                     TargetStatus::Draining
-                }
+                };
+                (final_state, Some(Action::Offline { comment, issue }))
             }
         },
         TargetStatus::Down => match new_state {
-            TargetStatus::Draining => TargetStatus::Draining,
-            TargetStatus::Down => TargetStatus::Down,
-            TargetStatus::Offline => TargetStatus::Offline,
+            TargetStatus::Draining => (TargetStatus::Draining, None),
+            TargetStatus::Down => (TargetStatus::Down, None),
+            TargetStatus::Offline => (TargetStatus::Offline, None),
+            TargetStatus::Unknown => (TargetStatus::Unknown, None),
             TargetStatus::Online => {
                 info!("closing open issues for {}", target);
                 // know it is safe to simply close all issue open against the node because
                 // expected status would be Offline if there were any issues with ToOffline set
-                close_open_issues(target, db, cluster).await;
-                TargetStatus::Online
+                if observe {
+                    let kind = PlannedActionKind::CloseIssues;
+                    planned_action::Entity::record(db, cluster.name(), target, kind, "", issue)
+                        .await;
+                } else {
+                    close_open_issues(target, db, cluster).await;
+                }
+                (TargetStatus::Online, None)
             }
         },
     };
-    //dont update state if it hasn't changed
-    if *old_state != final_state {
-        debug!(
-            "{}: current: {:?}, expected: {:?}, final: {:?}",
-            target, new_state, expected_state, final_state
-        );
-        let node = if let Some(tmp) = entities::target::Entity::from_name(target, db, cluster).await
-        {
-            tmp
-        } else {
-            warn!("trying to update state for fake node {}", target);
-            return;
-        };
+    debug!(
+        "{}: current: {:?}, expected: {:?}, final: {:?}",
+        target, new_state, expected_state, final_state
+    );
+    Transition {
+        target: target.to_string(),
+        old_state: *old_state,
+        snapshot: snapshot.clone(),
+        state: final_state,
+        action,
+        observe,
+        open: flapping.or(open),
+    }
+}
 
-        let mut updated_target: entities::target::ActiveModel = node.into();
-        updated_target.status = ActiveValue::Set(final_state);
-        updated_target.update(db).await.unwrap();
+/// changes to or from down ctt didn't make itself within the window, and since their last
+/// flapping issue was acknowledged, for every node in `cluster` with any
+#[instrument(skip(db))]
+async fn flapping_changes(
+    db: &DatabaseConnection,
+    cluster: &dyn ClusterTrait,
+    flapping: conf::Flapping,
+) -> HashMap<String, usize> {
+    use entities::{issue, target};
+    let since = Utc::now().naive_utc() - chrono::Duration::seconds(flapping.window as i64);
+    // closing a flapping issue acknowledges the changes before it
+    let acknowledged = Query::select()
+        .expr(Expr::val(1))
+        .from(issue::Entity)
+        .and_where(
+            Expr::col((issue::Entity, issue::Column::TargetId))
+                .equals((state_change::Entity, state_change::Column::TargetId)),
+        )
+        .and_where(issue::Column::Kind.eq(issue::FLAPPING))
+        .and_where(
+            Expr::col((issue::Entity, issue::Column::UpdatedAt)).gte(Expr::col((
+                state_change::Entity,
+                state_change::Column::ChangedAt,
+            ))),
+        )
+        .to_owned();
+    state_change::Entity::find()
+        .select_only()
+        .column(target::Column::Name)
+        .column_as(state_change::Column::Id.count(), "changes")
+        .inner_join(target::Entity)
+        .filter(target::Column::Cluster.eq(cluster.name()))
+        .filter(state_change::Column::ChangedAt.gt(since))
+        .filter(state_change::Column::ByCtt.eq(false))
+        .filter(
+            Condition::any()
+                .add(state_change::Column::OldStatus.eq(TargetStatus::Down))
+                .add(state_change::Column::NewStatus.eq(TargetStatus::Down)),
+        )
+        .filter(Expr::exists(acknowledged).not())
+        .group_by(target::Column::Name)
+        .into_tuple::<(String, i64)>()
+        .all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(name, changes)| (name, changes as usize))
+        .collect()
+}
+
+/// drop state changes in `cluster` too old to count towards flapping
+#[instrument(skip(db))]
+async fn prune_state_changes(
+    db: &DatabaseConnection,
+    cluster: &dyn ClusterTrait,
+    flapping: conf::Flapping,
+) {
+    let since = Utc::now().naive_utc() - chrono::Duration::seconds(flapping.window as i64);
+    state_change::Entity::delete_many()
+        .filter(state_change::Column::ChangedAt.lt(since))
+        .filter(
+            state_change::Column::TargetId.in_subquery(
+                entities::target::Entity::find()
+                    .select_only()
+                    .column(entities::target::Column::Id)
+                    .filter(entities::target::Column::Cluster.eq(cluster.name()))
+                    .into_query(),
+            ),
+        )
+        .exec(db)
+        .await
+        .unwrap();
+}
+
+/// issue offlining `target` if it made `changes` of the changes `flapping_changes` counts
+#[instrument]
+fn flapping_issue(target: &str, changes: usize, flapping: conf::Flapping) -> Option<PendingIssue> {
+    if changes < flapping.transitions {
+        return None;
+    }
+    let description = format!(
+        "{} changes to or from down in {} seconds",
+        changes, flapping.window
+    );
+    info!("{} is flapping, {}", target, description);
+    let mut issue = PendingIssue::new(
+        "Flapping between up and down",
+        &description,
+        Some(ToOffline::Node),
+    );
+    issue.kind = Some(entities::issue::FLAPPING.to_string());
+    Some(issue)
+}
+
+/// make the scheduler calls for every planned transition, batched into one offline and one
+/// release call
+async fn run_actions(
+    transitions: &[Transition],
+    cluster: &dyn ClusterTrait,
+) -> HashMap<String, Result<(), SchedulerError>> {
+    let mut offline = Vec::new();
+    let mut release = Vec::new();
+    for t in transitions.iter().filter(|t| !t.observe) {
+        match &t.action {
+            Some(Action::Offline { comment, .. }) | Some(Action::UpdateComment { comment }) => {
+                offline.push((t.target.clone(), comment.clone()))
+            }
+            Some(Action::Release { .. }) => release.push(t.target.clone()),
+            None => (),
+        }
     }
+    let mut results = HashMap::new();
+    if !offline.is_empty() {
+        results.extend(cluster.offline_nodes(&offline).await);
+    }
+    if !release.is_empty() {
+        results.extend(cluster.release_nodes(&release).await);
+    }
+    results
+}
+
+/// record the outcome of a transition, failed scheduler calls are noted on the related issues and
+/// retried next sync
+#[instrument(skip(db, tx))]
+async fn finish_transition(
+    t: Transition,
+    result: Option<&Result<(), SchedulerError>>,
+    conf: &conf::SyncPolicy,
+    db: &DatabaseConnection,
+    tx: &mpsc::Sender<ChangeLogMsg>,
+    cluster: &dyn ClusterTrait,
+) {
+    // the scheduler call went through, so ctt changed the node's state itself
+    let by_ctt = !t.observe && t.action.is_some() && matches!(result, Some(Ok(())));
+    let final_state = match (&t.action, result) {
+        (None, _) => t.state,
+        // nothing was done, so the node is still how the scheduler reported it
+        (Some(action), _) if t.observe => {
+            let (kind, detail, issue) = match action {
+                Action::Offline { comment, issue } => {
+                    (PlannedActionKind::Offline, comment.as_str(), *issue)
+                }
+                Action::UpdateComment { comment } => {
+                    (PlannedActionKind::UpdateComment, comment.as_str(), None)
+                }
+                Action::Release { issues } => {
+                    (PlannedActionKind::Release, "", issues.first().copied())
+                }
+            };
+            planned_action::Entity::record(db, cluster.name(), &t.target, kind, detail, issue)
+                .await;
+            t.snapshot.status
+        }
+        (Some(action), Some(Ok(()))) => {
+            let msg = match action {
+                Action::Offline { .. } => Some(ChangeLogMsg::Offline {
+                    target: t.target.clone(),
+                }),
+                Action::Release { .. } => Some(ChangeLogMsg::Resume {
+                    target: t.target.clone(),
+                }),
+                Action::UpdateComment { .. } => None,
+            };
+            if let Some(msg) = msg {
+                let _ = tx.send(msg).await;
+            }
+            t.state
+        }
+        (Some(action), result) => {
+            let err = match result {
+                Some(Err(e)) => e.to_string(),
+                _ => "no result from scheduler".to_string(),
+            };
+            // try again next sync
+            let (verb, issues) = match action {
+                Action::Offline { issue, .. } => ("offline", issue.iter().cloned().collect()),
+                Action::UpdateComment { .. } => ("update comment on", vec![]),
+                Action::Release { issues } => ("resume", issues.clone()),
+            };
+            warn!("could not {} {}: {}", verb, t.target, err);
+            for id in issues {
+                let c = entities::comment::ActiveModel {
+                    created_by: ActiveValue::Set("ctt".to_string()),
+                    comment: ActiveValue::Set(format!("could not {} {}: {}", verb, t.target, err)),
+                    issue_id: ActiveValue::Set(id),
+                    ..Default::default()
+                };
+                c.insert(db).await.unwrap();
+            }
+            t.snapshot.status
+        }
+    };
+    if t.old_state != final_state {
+        debug!("{}: {:?} -> {:?}", t.target, t.old_state, final_state);
+    }
+    let target = &t.target;
+    let snapshot = &t.snapshot;
+    let node = if let Some(tmp) = entities::target::Entity::from_name(target, db, cluster).await {
+        tmp
+    } else {
+        warn!("trying to update state for fake node {}", target);
+        return;
+    };
+    // only kept for flapping detection
+    if node.status != final_state && conf.flapping.is_some() {
+        state_change::ActiveModel {
+            target_id: ActiveValue::Set(node.id),
+            old_status: ActiveValue::Set(node.status),
+            new_status: ActiveValue::Set(final_state),
+            changed_at: ActiveValue::Set(Utc::now().naive_utc()),
+            by_ctt: ActiveValue::Set(by_ctt),
+            ..Default::default()
+        }
+        .insert(db)
+        .await
+        .unwrap();
+    }
+    let jobs = snapshot.jobs.join(",");
+    //dont update the target if nothing has changed
+    if node.status == final_state
+        && node.scheduler_state.as_ref() == Some(&snapshot.state)
+        && node.scheduler_comment.as_ref() == Some(&snapshot.comment)
+        && node.jobs.as_ref() == Some(&jobs)
+        && node.ncpus == snapshot.ncpus
+        && node.ngpus == snapshot.ngpus
+        && node.mem == snapshot.mem
+        && node.last_state_change == snapshot.last_state_change
+    {
+        return;
+    }
+    let mut updated_target: entities::target::ActiveModel = node.into();
+    updated_target.status = ActiveValue::Set(final_state);
+    updated_target.scheduler_state = ActiveValue::Set(Some(snapshot.state.clone()));
+    updated_target.scheduler_comment = ActiveValue::Set(Some(snapshot.comment.clone()));
+    updated_target.jobs = ActiveValue::Set(Some(jobs));
+    updated_target.ncpus = ActiveValue::Set(snapshot.ncpus);
+    updated_target.ngpus = ActiveValue::Set(snapshot.ngpus);
+    updated_target.mem = ActiveValue::Set(snapshot.mem);
+    updated_target.last_state_change = ActiveValue::Set(snapshot.last_state_change);
+    updated_target.update(db).await.unwrap();
+}
+
+/// issue the configured policy opens for a node the scheduler reported in an unrecognized state
+///
+/// only called when the node has no open issues
+#[instrument]
+fn unknown_issue(target: &str, raw_state: &str, policy: UnknownState) -> Option<PendingIssue> {
+    let to_offline = match policy {
+        UnknownState::Ignore => {
+            warn!("{} is in unknown scheduler state '{}'", target, raw_state);
+            return None;
+        }
+        UnknownState::Issue => None,
+        UnknownState::Offline => Some(ToOffline::Node),
+    };
+    let title = format!("Unknown scheduler state: {}", raw_state);
+    Some(PendingIssue::new(&title, &title, to_offline))
 }
@@ -0,0 +1,1389 @@
+//! End to end harness for the sync loop
+//!
+//! wires an in memory db, a FakeScheduler and the real CttSchema together the same way main does,
+//! so scenarios can script node state changes and api calls and then run sync cycles
+use crate::auth::{Role, RoleGuard};
+use crate::cluster::scheduler::{FakeScheduler, NodeSnapshot, SchedulerCall};
+use crate::cluster::{ClusterTrait, Clusters, Infrastructure, RegexCluster};
+use crate::conf::{self, NodeType, UnknownState};
+use crate::entities::comment;
+use crate::entities::issue::{self, IssueStatus, ToOffline};
+use crate::entities::prelude::*;
+use crate::entities::target::{self, TargetStatus};
+use crate::model::{self, CttSchema};
+use crate::setup::setup_in_memory;
+use crate::ChangeLogMsg;
+use async_graphql::{Request, Response, Variables};
+use chrono::Utc;
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+pub struct Harness {
+    pub db: Arc<DatabaseConnection>,
+    pub sched: FakeScheduler,
+    pub schema: CttSchema,
+    pub conf: conf::SyncPolicy,
+    // shared by the schema and the sync loop, same as in main
+    clusters: Arc<Clusters>,
+    // every cluster's definition, so the schema can be rebuilt
+    cluster_defs: Vec<ClusterDef>,
+    tx: mpsc::Sender<ChangeLogMsg>,
+    rx: mpsc::Receiver<ChangeLogMsg>,
+}
+
+impl Harness {
+    /// single cluster named "default" with `nodes` online
+    pub async fn new(node_types: Vec<NodeType>, nodes: &[&str]) -> Self {
+        let db = Arc::new(setup_in_memory().await.unwrap());
+        let sched = fake_scheduler(nodes);
+        // big enough that scenarios never block on a full channel
+        let (tx, rx) = mpsc::channel(1000);
+        let cluster_defs = vec![ClusterDef {
+            name: conf::DEFAULT_CLUSTER.to_string(),
+            node_types,
+            infrastructure: Infrastructure::default(),
+            sched: sched.clone(),
+        }];
+        let clusters = build_clusters(&cluster_defs);
+        let schema = model::schema(db.clone(), tx.clone(), clusters.clone());
+        Self {
+            db,
+            sched,
+            schema,
+            conf: conf::SyncPolicy::default(),
+            clusters,
+            cluster_defs,
+            tx,
+            rx,
+        }
+    }
+
+    /// add another cluster with `nodes` online, returns its scheduler
+    pub fn add_cluster(
+        &mut self,
+        name: &str,
+        node_types: Vec<NodeType>,
+        nodes: &[&str],
+    ) -> FakeScheduler {
+        let sched = fake_scheduler(nodes);
+        let def = ClusterDef {
+            name: name.to_string(),
+            node_types,
+            infrastructure: Infrastructure::default(),
+            sched: sched.clone(),
+        };
+        self.cluster_defs.push(def);
+        self.rebuild();
+        sched
+    }
+
+    /// give the first cluster `infrastructure`
+    pub fn set_infrastructure(&mut self, infrastructure: &[conf::Infrastructure]) {
+        self.cluster_defs[0].infrastructure = Infrastructure::from_conf(infrastructure).unwrap();
+        self.rebuild();
+    }
+
+    /// rebuild the clusters and schema after a cluster definition changes
+    fn rebuild(&mut self) {
+        self.clusters = build_clusters(&self.cluster_defs);
+        self.schema = model::schema(self.db.clone(), self.tx.clone(), self.clusters.clone());
+    }
+
+    /// 16 online nodes, gu0001-gu0016 tagged compute, 2 nodes per card, 4 per blade, 8 per
+    /// chassis and 16 per rack
+    pub async fn gust() -> Self {
+        let nodes: Vec<String> = (1..=16).map(|i| format!("gu{:0>4}", i)).collect();
+        Self::new(
+            vec![NodeType {
+                digits: Some(4),
+                last_num: Some(16),
+                board: Some(2),
+                slot: Some(4),
+                chassis: Some(8),
+                rack: Some(16),
+                tags: vec!["compute".to_string()],
+                ..NodeType::with_prefix("gu")
+            }],
+            &nodes.iter().map(|n| n.as_str()).collect::<Vec<_>>(),
+        )
+        .await
+    }
+
+    /// sync every cluster once
+    pub async fn sync(&mut self) {
+        for cluster in self.clusters.iter() {
+            crate::sync::sync_once(self.db.as_ref(), cluster, &self.conf, &self.tx).await;
+        }
+    }
+
+    /// run a graphql request as an admin, any errors are left in the response
+    pub async fn request(&self, query: &str, variables: Value) -> Response {
+        let req = Request::new(query)
+            .variables(Variables::from_json(variables))
+            .data(RoleGuard::new(
+                Role::Admin,
+                "tester".to_string(),
+                Utc::now().naive_utc() + chrono::Duration::minutes(60),
+            ));
+        self.schema.execute(req).await
+    }
+
+    /// run a graphql request as an admin, panics if it returns any errors
+    pub async fn run(&self, query: &str, variables: Value) -> Value {
+        let resp = self.request(query, variables).await;
+        assert!(resp.errors.is_empty(), "{:?}", resp.errors);
+        resp.data.into_json().unwrap()
+    }
+
+    /// open an issue through the api, returns its id
+    pub async fn open(&self, target: &str, title: &str, to_offline: Option<&str>) -> i32 {
+        let resp = self
+            .run(
+                "mutation OpenIssue($newIssue: NewIssue!) { open(issue: $newIssue) { id } }",
+                json!({"newIssue": {
+                    "title": title,
+                    "description": title,
+                    "target": target,
+                    "toOffline": to_offline,
+                }}),
+            )
+            .await;
+        resp["open"]["id"].as_i64().unwrap() as i32
+    }
+
+    pub async fn close(&self, id: i32, comment: &str) {
+        self.run(
+            "mutation CloseIssue($id: Int!, $comment: String!) { close(issue: $id, comment: $comment) }",
+            json!({"id": id, "comment": comment}),
+        )
+        .await;
+    }
+
+    pub async fn update(&self, update: Value) {
+        self.run(
+            "mutation UpdateIssue($issue: UpdateIssue!) { updateIssue(issue: $issue) { id } }",
+            json!({ "issue": update }),
+        )
+        .await;
+    }
+
+    pub async fn target_status(&self, name: &str) -> Option<TargetStatus> {
+        Target::find()
+            .filter(target::Column::Name.eq(name))
+            .one(self.db.as_ref())
+            .await
+            .unwrap()
+            .map(|t| t.status)
+    }
+
+    pub async fn issue(&self, id: i32) -> issue::Model {
+        Issue::find_by_id(id)
+            .one(self.db.as_ref())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// every issue, including closed ones, against `target`
+    pub async fn issues_for(&self, name: &str) -> Vec<issue::Model> {
+        let t = Target::find()
+            .filter(target::Column::Name.eq(name))
+            .one(self.db.as_ref())
+            .await
+            .unwrap();
+        match t {
+            Some(t) => t
+                .issues()
+                .order_by_asc(issue::Column::Id)
+                .all(self.db.as_ref())
+                .await
+                .unwrap(),
+            None => vec![],
+        }
+    }
+
+    pub async fn comments(&self, id: i32) -> Vec<String> {
+        Comment::for_issue(id)
+            .order_by_asc(comment::Column::Id)
+            .all(self.db.as_ref())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.comment)
+            .collect()
+    }
+
+    /// scheduler calls since the last time this was called, sorted so tests don't depend on the
+    /// order the sync loop visits nodes in
+    pub fn take_calls(&self) -> Vec<SchedulerCall> {
+        take_calls(&self.sched)
+    }
+
+    /// changelog messages sent since the last time this was called
+    pub fn take_changelog(&mut self) -> Vec<ChangeLogMsg> {
+        let mut msgs = vec![];
+        while let Ok(m) = self.rx.try_recv() {
+            msgs.push(m);
+        }
+        msgs
+    }
+}
+
+/// same as `Harness::take_calls` but for any scheduler, eg one from `add_cluster`
+pub fn take_calls(sched: &FakeScheduler) -> Vec<SchedulerCall> {
+    let mut calls = sched.calls();
+    sched.clear_calls();
+    calls.sort_by_key(|c| format!("{:?}", c));
+    calls
+}
+
+fn fake_scheduler(nodes: &[&str]) -> FakeScheduler {
+    FakeScheduler::with_nodes(&nodes.iter().map(|n| n.to_string()).collect::<Vec<_>>())
+}
+
+struct ClusterDef {
+    name: String,
+    node_types: Vec<NodeType>,
+    infrastructure: Infrastructure,
+    sched: FakeScheduler,
+}
+
+impl ClusterDef {
+    fn build(&self) -> Box<dyn ClusterTrait> {
+        Box::new(
+            RegexCluster::new(
+                &self.name,
+                self.node_types.clone(),
+                Box::new(self.sched.clone()),
+            )
+            .with_infrastructure(self.infrastructure.clone()),
+        )
+    }
+}
+
+fn build_clusters(cluster_defs: &[ClusterDef]) -> Arc<Clusters> {
+    Arc::new(Clusters::new(
+        cluster_defs.iter().map(|d| d.build()).collect(),
+    ))
+}
+
+pub fn offline(target: &str, comment: &str) -> SchedulerCall {
+    SchedulerCall::Offline {
+        target: target.to_string(),
+        comment: comment.to_string(),
+    }
+}
+
+pub fn release(target: &str) -> SchedulerCall {
+    SchedulerCall::Release {
+        target: target.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn new_nodes_are_tracked() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+    for i in 1..=16 {
+        let name = format!("gu{:0>4}", i);
+        assert_eq!(h.target_status(&name).await, Some(TargetStatus::Online));
+        assert!(h.issues_for(&name).await.is_empty());
+    }
+    assert!(h.take_calls().is_empty());
+}
+
+#[tokio::test]
+async fn snapshot_is_stored_on_target() {
+    let mut h = Harness::gust().await;
+    let mut snap = NodeSnapshot::new(TargetStatus::Draining, "job-busy,offline", "bad dimm");
+    snap.jobs = vec!["1234.pbs".to_string(), "1235.pbs".to_string()];
+    snap.ncpus = Some(128);
+    snap.ngpus = Some(4);
+    snap.mem = Some(262144);
+    snap.last_state_change = chrono::DateTime::from_timestamp(1697040000, 0).map(|d| d.naive_utc());
+    h.sched.set_snapshot("gu0004", snap);
+    h.sync().await;
+
+    let id = h.issues_for("gu0004").await[0].id;
+    let resp = h
+        .run(
+            "query Issue($id: Int!) { issue(issue: $id) { target { name status schedulerState \
+             schedulerComment jobs ncpus ngpus mem lastStateChange } } }",
+            json!({ "id": id }),
+        )
+        .await;
+    assert_eq!(
+        resp["issue"]["target"],
+        json!({
+            "name": "gu0004",
+            "status": "DRAINING",
+            "schedulerState": "job-busy,offline",
+            "schedulerComment": "bad dimm",
+            "jobs": ["1234.pbs", "1235.pbs"],
+            "ncpus": 128,
+            "ngpus": 4,
+            "mem": 262144,
+            "lastStateChange": "2023-10-11T16:00:00",
+        })
+    );
+
+    // jobs finishing shows up on the next sync
+    h.sched
+        .set_node("gu0004", TargetStatus::Offline, "bad dimm");
+    h.sync().await;
+    let resp = h
+        .run(
+            "query Issue($id: Int!) { issue(issue: $id) { target { schedulerState jobs ncpus } } }",
+            json!({ "id": id }),
+        )
+        .await;
+    assert_eq!(
+        resp["issue"]["target"],
+        json!({ "schedulerState": "offline", "jobs": [], "ncpus": null })
+    );
+}
+
+#[tokio::test]
+async fn down_node_opens_then_closes_issue() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    h.sched.set_node("gu0003", TargetStatus::Down, "bad dimm");
+    h.sync().await;
+    let issues = h.issues_for("gu0003").await;
+    assert_eq!(issues.len(), 1);
+    let iss = &issues[0];
+    assert_eq!(iss.title, "bad dimm");
+    assert_eq!(iss.created_by, "ctt");
+    assert_eq!(iss.status, IssueStatus::Open);
+    assert_eq!(iss.to_offline, None);
+    assert_eq!(h.target_status("gu0003").await, Some(TargetStatus::Down));
+    // issue doesn't enforce anything, so ctt leaves the node alone
+    assert!(h.take_calls().is_empty());
+
+    // still down, nothing changes
+    h.sync().await;
+    assert_eq!(h.issues_for("gu0003").await.len(), 1);
+
+    h.sched.set_node("gu0003", TargetStatus::Online, "");
+    h.sync().await;
+    assert_eq!(h.issue(iss.id).await.status, IssueStatus::Closed);
+    assert_eq!(
+        h.comments(iss.id).await.last().unwrap(),
+        "node found up, assuming issue is resolved"
+    );
+    assert_eq!(h.target_status("gu0003").await, Some(TargetStatus::Online));
+    assert!(h.take_calls().is_empty());
+}
+
+#[tokio::test]
+async fn node_issue_offlines_and_close_releases() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    let id = h.open("gu0005", "replace dimm", Some("NODE")).await;
+    assert_eq!(h.issue(id).await.status, IssueStatus::Opening);
+    assert_eq!(h.comments(id).await, vec!["Opening issue"]);
+
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![offline("gu0005", &format!("ctt#{}: replace dimm", id))]
+    );
+    assert_eq!(h.issue(id).await.status, IssueStatus::Open);
+    // node was online, so it could have had jobs
+    assert_eq!(
+        h.target_status("gu0005").await,
+        Some(TargetStatus::Draining)
+    );
+    assert!(h.take_changelog().contains(&ChangeLogMsg::Offline {
+        target: "gu0005".to_string()
+    }));
+
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert_eq!(h.target_status("gu0005").await, Some(TargetStatus::Offline));
+
+    h.close(id, "dimm replaced").await;
+    assert_eq!(h.issue(id).await.status, IssueStatus::Closing);
+    h.sync().await;
+    assert_eq!(h.take_calls(), vec![release("gu0005")]);
+    assert_eq!(h.issue(id).await.status, IssueStatus::Closed);
+    assert_eq!(h.target_status("gu0005").await, Some(TargetStatus::Online));
+    assert_eq!(h.comments(id).await, vec!["Opening issue", "dimm replaced"]);
+    assert!(h.take_changelog().contains(&ChangeLogMsg::Resume {
+        target: "gu0005".to_string()
+    }));
+}
+
+#[tokio::test]
+async fn card_issue_offlines_and_releases_siblings() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    let id = h.open("gu0003", "bad card", Some("CARD")).await;
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![
+            offline("gu0003", &format!("ctt#{}: bad card", id)),
+            offline("gu0004", &format!("ctt#{}: gu0003: bad card", id))
+        ]
+    );
+    h.sync().await;
+    assert_eq!(h.target_status("gu0003").await, Some(TargetStatus::Offline));
+    assert_eq!(h.target_status("gu0004").await, Some(TargetStatus::Offline));
+    // rest of the blade is untouched
+    assert_eq!(h.target_status("gu0001").await, Some(TargetStatus::Online));
+
+    h.close(id, "card replaced").await;
+    h.sync().await;
+    assert_eq!(h.take_calls(), vec![release("gu0003"), release("gu0004")]);
+    assert_eq!(h.issue(id).await.status, IssueStatus::Closed);
+    assert_eq!(h.target_status("gu0004").await, Some(TargetStatus::Online));
+}
+
+#[tokio::test]
+async fn blade_issue_reduced_to_node() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    let id = h.open("gu0001", "bad blade", Some("BLADE")).await;
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![
+            offline("gu0001", &format!("ctt#{}: bad blade", id)),
+            offline("gu0002", &format!("ctt#{}: gu0001: bad blade", id)),
+            offline("gu0003", &format!("ctt#{}: gu0001: bad blade", id)),
+            offline("gu0004", &format!("ctt#{}: gu0001: bad blade", id)),
+        ]
+    );
+    h.sync().await;
+
+    // the mutation releases everything the issue no longer covers right away
+    h.update(json!({"id": id, "toOffline": "NODE"})).await;
+    assert_eq!(h.issue(id).await.to_offline, Some(ToOffline::Node));
+    assert_eq!(
+        h.take_calls(),
+        vec![release("gu0002"), release("gu0003"), release("gu0004")]
+    );
+    assert!(h
+        .comments(id)
+        .await
+        .contains(&"Updating to_offline from Some(Blade) to Some(Node)".to_string()));
+
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert_eq!(h.target_status("gu0001").await, Some(TargetStatus::Offline));
+    for n in ["gu0002", "gu0003", "gu0004"] {
+        assert_eq!(h.target_status(n).await, Some(TargetStatus::Online));
+        // nodes were released, not left for the sync loop to open new issues about
+        assert!(h.issues_for(n).await.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn chassis_issue_reduced_to_card() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    let id = h.open("gu0006", "chassis psu", Some("CHASSIS")).await;
+    h.sync().await;
+    let chassis: Vec<String> = (1..=8).map(|i| format!("gu{:0>4}", i)).collect();
+    let calls = h.take_calls();
+    assert_eq!(calls.len(), 8);
+    assert!(calls.contains(&offline("gu0006", &format!("ctt#{}: chassis psu", id))));
+    assert!(calls.contains(&offline(
+        "gu0001",
+        &format!("ctt#{}: gu0006: chassis psu", id)
+    )));
+    h.sync().await;
+    for n in &chassis {
+        assert_eq!(h.target_status(n).await, Some(TargetStatus::Offline));
+    }
+    assert_eq!(h.target_status("gu0009").await, Some(TargetStatus::Online));
+
+    h.update(json!({"id": id, "toOffline": "CARD"})).await;
+    assert_eq!(
+        h.take_calls(),
+        ["gu0001", "gu0002", "gu0003", "gu0004", "gu0007", "gu0008"]
+            .into_iter()
+            .map(release)
+            .collect::<Vec<_>>()
+    );
+
+    h.close(id, "psu replaced").await;
+    h.sync().await;
+    assert_eq!(h.take_calls(), vec![release("gu0005"), release("gu0006")]);
+    assert_eq!(h.issue(id).await.status, IssueStatus::Closed);
+}
+
+#[tokio::test]
+async fn domain_needs_a_domain_name() {
+    let h = Harness::gust().await;
+    let resp = h
+        .request(
+            r#"mutation { open(issue: {title: "leaf down", description: "",
+                target: "gu0001", toOffline: DOMAIN, domain: "switch"}) { id } }"#,
+            json!({}),
+        )
+        .await;
+    // gust has no topology file, so there are no named domains
+    assert_eq!(resp.errors[0].message, "gu0001 isn't in a switch domain");
+}
+
+#[tokio::test]
+async fn hostlist_targets() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    let resp = h
+        .run(
+            "mutation OpenMany($newIssue: NewIssue!) { openMany(issue: $newIssue) { id relatedHostlist } }",
+            json!({"newIssue": {
+                "title": "bad cables",
+                "description": "bad cables",
+                "target": "gu[0001,0005-0006]",
+                "toOffline": "CARD",
+            }}),
+        )
+        .await;
+    let issues = resp["openMany"].as_array().unwrap();
+    assert_eq!(issues.len(), 3);
+    assert_eq!(issues[0]["relatedHostlist"], "gu[0001-0002]");
+    assert_eq!(issues[1]["relatedHostlist"], "gu[0005-0006]");
+
+    let query = "query Issues($target: String) { issues(target: $target) { target { name } } }";
+    let resp = h.run(query, json!({"target": "gu[0004-0005]"})).await;
+    assert_eq!(resp["issues"], json!([{"target": {"name": "gu0005"}}]));
+
+    // open only takes a single node
+    let id = h.open("gu[0009]", "bad dimm", None).await;
+    assert_eq!(h.issue(id).await.title, "bad dimm");
+    let resp = h
+        .request(
+            r#"mutation { open(issue: {title: "t", description: "", target: "gu[0009-0010]"}) { id } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(
+        resp.errors[0].message,
+        "gu[0009-0010] is more than one node, use openMany"
+    );
+    // nothing is opened if any node isn't real
+    let resp = h
+        .request(
+            r#"mutation { openMany(issue: {title: "t", description: "", target: "gu[0015-0018]"}) { id } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(resp.errors[0].message, "gu[0017-0018] are not real nodes");
+    assert!(h.issues_for("gu0015").await.is_empty());
+    // or if any node fails a check
+    let resp = h
+        .request(
+            r#"mutation { openMany(issue: {title: "t", description: "", target: "gu[0015-0016]",
+                toOffline: DOMAIN, domain: "switch"}) { id } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(resp.errors[0].message, "gu0015 isn't in a switch domain");
+    assert!(h.issues_for("gu0015").await.is_empty());
+}
+
+#[tokio::test]
+async fn failed_offline_is_retried_next_sync() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    h.sched.deny_node("gu0005", true);
+    let id = h.open("gu0005", "replace dimm", Some("NODE")).await;
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![offline("gu0005", &format!("ctt#{}: replace dimm", id))]
+    );
+    assert_eq!(h.target_status("gu0005").await, Some(TargetStatus::Online));
+    assert!(!h.take_changelog().contains(&ChangeLogMsg::Offline {
+        target: "gu0005".to_string()
+    }));
+
+    h.sched.deny_node("gu0005", false);
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![offline("gu0005", &format!("ctt#{}: replace dimm", id))]
+    );
+    assert_eq!(
+        h.target_status("gu0005").await,
+        Some(TargetStatus::Draining)
+    );
+}
+
+#[tokio::test]
+async fn partial_blade_offline_is_noted_on_issue() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    h.sched.deny_node("gu0002", true);
+    let id = h.open("gu0001", "bad blade", Some("BLADE")).await;
+    h.sync().await;
+    assert_eq!(h.take_calls().len(), 4);
+    // the rest of the blade still goes offline
+    for n in ["gu0001", "gu0003", "gu0004"] {
+        assert_eq!(h.target_status(n).await, Some(TargetStatus::Draining));
+    }
+    assert_eq!(h.target_status("gu0002").await, Some(TargetStatus::Online));
+    assert!(h
+        .comments(id)
+        .await
+        .contains(&"could not offline gu0002: permission denied: gu0002".to_string()));
+
+    h.sched.deny_node("gu0002", false);
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![offline("gu0002", &format!("ctt#{}: gu0001: bad blade", id))]
+    );
+    assert_eq!(
+        h.target_status("gu0002").await,
+        Some(TargetStatus::Draining)
+    );
+}
+
+#[tokio::test]
+async fn unknown_state_opens_issue() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    h.sched.set_snapshot(
+        "gu0003",
+        NodeSnapshot::new(TargetStatus::Unknown, "provisioning", ""),
+    );
+    h.sync().await;
+    let issues = h.issues_for("gu0003").await;
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].title, "Unknown scheduler state: provisioning");
+    assert_eq!(issues[0].to_offline, None);
+    assert_eq!(h.target_status("gu0003").await, Some(TargetStatus::Unknown));
+    // polling never touches the node
+    assert!(h.take_calls().is_empty());
+
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert_eq!(h.issues_for("gu0003").await.len(), 1);
+
+    // node coming back up closes the issue
+    h.sched.set_node("gu0003", TargetStatus::Online, "");
+    h.sync().await;
+    assert_eq!(h.issues_for("gu0003").await[0].status, IssueStatus::Closed);
+    assert_eq!(h.target_status("gu0003").await, Some(TargetStatus::Online));
+}
+
+#[tokio::test]
+async fn unknown_state_policies() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    h.conf.unknown_state = UnknownState::Ignore;
+    h.sched.set_snapshot(
+        "gu0003",
+        NodeSnapshot::new(TargetStatus::Unknown, "provisioning", ""),
+    );
+    h.sync().await;
+    assert!(h.issues_for("gu0003").await.is_empty());
+    assert_eq!(h.target_status("gu0003").await, Some(TargetStatus::Unknown));
+    assert!(h.take_calls().is_empty());
+
+    h.conf.unknown_state = UnknownState::Offline;
+    h.sync().await;
+    let issues = h.issues_for("gu0003").await;
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].to_offline, Some(ToOffline::Node));
+    assert!(h.take_calls().is_empty());
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![offline(
+            "gu0003",
+            &format!(
+                "ctt#{}: Unknown scheduler state: provisioning",
+                issues[0].id
+            )
+        )]
+    );
+}
+
+#[tokio::test]
+async fn title_change_updates_comment() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+    let id = h.open("gu0005", "replace dimm", Some("NODE")).await;
+    h.sync().await;
+    h.take_calls();
+
+    h.update(json!({"id": id, "title": "replace both dimms"}))
+        .await;
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![offline(
+            "gu0005",
+            &format!("ctt#{}: replace both dimms", id)
+        )]
+    );
+    assert_eq!(h.target_status("gu0005").await, Some(TargetStatus::Offline));
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+
+    // comments ctt didn't write are left alone
+    h.sched
+        .set_node("gu0005", TargetStatus::Offline, "admin testing");
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+}
+
+#[tokio::test]
+async fn stale_ctt_offline_is_resumed() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    // offlined by ctt for an issue that no longer exists
+    h.sched
+        .set_node("gu0006", TargetStatus::Offline, "ctt#999: old issue");
+    h.sync().await;
+    assert_eq!(h.take_calls(), vec![release("gu0006")]);
+    assert_eq!(h.target_status("gu0006").await, Some(TargetStatus::Online));
+    assert!(h.issues_for("gu0006").await.is_empty());
+}
+
+#[tokio::test]
+async fn manual_offline_opens_issue() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    h.sched
+        .set_node("gu0007", TargetStatus::Offline, "admin testing");
+    h.sync().await;
+    let issues = h.issues_for("gu0007").await;
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].title, "admin testing");
+    assert_eq!(
+        issues[0].description,
+        "found Offline outside of ctt: admin testing"
+    );
+    assert_eq!(issues[0].status, IssueStatus::Open);
+    assert_eq!(h.target_status("gu0007").await, Some(TargetStatus::Offline));
+    assert!(h.take_calls().is_empty());
+
+    // ctt doesn't resume nodes it didn't offline
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert_eq!(h.issues_for("gu0007").await.len(), 1);
+}
+
+#[tokio::test]
+async fn updating_ctt_issue_enforces_offline() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    h.sched.set_node("gu0009", TargetStatus::Down, "");
+    h.sync().await;
+    let id = h.issues_for("gu0009").await[0].id;
+    assert!(h.take_calls().is_empty());
+
+    // any update to an issue without to_offline set starts enforcing the node being offline
+    h.update(json!({"id": id, "title": "node won't boot"}))
+        .await;
+    let iss = h.issue(id).await;
+    assert_eq!(iss.title, "node won't boot");
+    assert_eq!(iss.to_offline, Some(ToOffline::Node));
+
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![offline("gu0009", &format!("ctt#{}: node won't boot", id))]
+    );
+    assert_eq!(h.target_status("gu0009").await, Some(TargetStatus::Offline));
+}
+
+#[tokio::test]
+async fn missing_node_opens_issue() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    h.sched.remove_node("gu0016");
+    h.sync().await;
+    let issues = h.issues_for("gu0016").await;
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].title, "Node not found in pbs");
+
+    // issue is reused rather than opening a new one every sync
+    h.sync().await;
+    assert_eq!(h.issues_for("gu0016").await.len(), 1);
+}
+
+#[tokio::test]
+async fn clusters_sync_independently() {
+    let mut h = Harness::gust().await;
+    let gpu = h.add_cluster(
+        "gpu",
+        vec![NodeType {
+            digits: Some(4),
+            board: Some(2),
+            ..NodeType::with_prefix("deg")
+        }],
+        &["deg0001", "deg0002", "deg0003", "deg0004"],
+    );
+    h.sync().await;
+    let resp = h.run("{ clusters }", json!({})).await;
+    assert_eq!(resp["clusters"], json!(["default", "gpu"]));
+
+    // the cluster is found from the node name
+    let id = h.open("deg0001", "bad card", Some("CARD")).await;
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert_eq!(
+        take_calls(&gpu),
+        vec![
+            offline("deg0001", &format!("ctt#{}: bad card", id)),
+            offline("deg0002", &format!("ctt#{}: deg0001: bad card", id))
+        ]
+    );
+
+    let query = "query Issues($cluster: String) { issues(cluster: $cluster) { id target { name cluster } } }";
+    let resp = h.run(query, json!({"cluster": "gpu"})).await;
+    assert_eq!(
+        resp["issues"],
+        json!([{"id": id, "target": {"name": "deg0001", "cluster": "gpu"}}])
+    );
+    let resp = h.run(query, json!({"cluster": "default"})).await;
+    assert_eq!(resp["issues"], json!([]));
+
+    h.close(id, "card replaced").await;
+    h.sync().await;
+    assert_eq!(
+        take_calls(&gpu),
+        vec![release("deg0001"), release("deg0002")]
+    );
+    assert_eq!(h.issue(id).await.status, IssueStatus::Closed);
+}
+
+#[tokio::test]
+async fn tags() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    // node type tags are added by the sync
+    let query = "query Targets($tag: String) { targets(tag: $tag) { name tags } }";
+    let resp = h.run(query, json!({"tag": "compute"})).await;
+    assert_eq!(resp["targets"].as_array().unwrap().len(), 16);
+    assert_eq!(
+        resp["targets"][0],
+        json!({"name": "gu0001", "tags": ["compute"]})
+    );
+
+    let tag = "mutation Tag($target: String!, $tags: [String!]!) { tag(target: $target, tags: $tags) { name tags } }";
+    let resp = h
+        .run(
+            tag,
+            json!({"target": "gu[0003-0004]", "tags": ["gpu", "a100"]}),
+        )
+        .await;
+    assert_eq!(
+        resp["tag"][0],
+        json!({"name": "gu0003", "tags": ["a100", "compute", "gpu"]})
+    );
+    // tagging again doesn't duplicate them
+    h.run(tag, json!({"target": "gu0003", "tags": ["gpu"]}))
+        .await;
+    let resp = h.run(query, json!({"tag": "gpu"})).await;
+    assert_eq!(
+        resp["targets"],
+        json!([
+            {"name": "gu0003", "tags": ["a100", "compute", "gpu"]},
+            {"name": "gu0004", "tags": ["a100", "compute", "gpu"]},
+        ])
+    );
+
+    let resp = h
+        .run(
+            "mutation OpenMany($newIssue: NewIssue!) { openMany(issue: $newIssue) { target { name } } }",
+            json!({"newIssue": {
+                "title": "gpu driver",
+                "description": "gpu driver",
+                "target": "@gpu,gu0004,gu0010",
+            }}),
+        )
+        .await;
+    assert_eq!(
+        resp["openMany"],
+        json!([
+            {"target": {"name": "gu0003"}},
+            {"target": {"name": "gu0004"}},
+            {"target": {"name": "gu0010"}},
+        ])
+    );
+    let resp = h
+        .run(
+            "query Issues($tag: String) { issues(tag: $tag) { target { name } } }",
+            json!({"tag": "a100"}),
+        )
+        .await;
+    assert_eq!(resp["issues"].as_array().unwrap().len(), 2);
+
+    // only tags set through the api can be removed
+    let resp = h
+        .run(
+            "mutation Untag($target: String!, $tags: [String!]!) { untag(target: $target, tags: $tags) { tags } }",
+            json!({"target": "gu0003", "tags": ["gpu", "compute"]}),
+        )
+        .await;
+    assert_eq!(resp["untag"], json!([{"tags": ["a100", "compute"]}]));
+    h.sync().await;
+    let resp = h.run(query, json!({"tag": "gpu"})).await;
+    assert_eq!(
+        resp["targets"],
+        json!([{"name": "gu0004", "tags": ["a100", "compute", "gpu"]}])
+    );
+
+    let resp = h
+        .request(
+            r#"mutation { tag(target: "gu0001", tags: ["a,b"]) { name } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(resp.errors[0].message, r#"bad tag "a,b""#);
+    let resp = h
+        .request(
+            r#"mutation { openMany(issue: {title: "t", description: "", target: "@bigmem"}) { id } }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(resp.errors[0].message, "nothing is tagged bigmem");
+}
+
+/// open an issue on `target` for maintenance starting in a day
+async fn open_scheduled(h: &Harness, target: &str, to_offline: &str) -> i32 {
+    let start = Utc::now().naive_utc() + chrono::Duration::days(1);
+    let resp = h
+        .run(
+            "mutation OpenIssue($newIssue: NewIssue!) { open(issue: $newIssue) { id } }",
+            json!({"newIssue": {
+                "title": "firmware update",
+                "description": "firmware update",
+                "target": target,
+                "toOffline": to_offline,
+                "scheduledStart": start,
+                "scheduledEnd": start + chrono::Duration::hours(4),
+            }}),
+        )
+        .await;
+    resp["open"]["id"].as_i64().unwrap() as i32
+}
+
+/// move the start of an issue's maintenance window to `start`, without going through the api
+async fn set_start(h: &Harness, id: i32, start: chrono::NaiveDateTime) {
+    use sea_orm::{ActiveModelTrait, ActiveValue};
+    let mut i: issue::ActiveModel = h.issue(id).await.into();
+    i.scheduled_start = ActiveValue::Set(Some(start));
+    i.update(h.db.as_ref()).await.unwrap();
+}
+
+#[tokio::test]
+async fn scheduled_maintenance_reserves_then_offlines() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    let id = open_scheduled(&h, "gu0005", "CARD").await;
+    h.sync().await;
+    let name = format!("ctt{}", id);
+    assert_eq!(
+        h.take_calls(),
+        vec![SchedulerCall::Reserve {
+            name: name.clone(),
+            targets: vec!["gu0005".to_string(), "gu0006".to_string()]
+        }]
+    );
+    assert_eq!(h.issue(id).await.reservation, Some(name.clone()));
+    // nothing is offlined before the window
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert_eq!(h.target_status("gu0005").await, Some(TargetStatus::Online));
+
+    set_start(
+        &h,
+        id,
+        Utc::now().naive_utc() - chrono::Duration::minutes(1),
+    )
+    .await;
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![
+            offline("gu0005", &format!("ctt#{}: firmware update", id)),
+            offline("gu0006", &format!("ctt#{}: gu0005: firmware update", id)),
+            SchedulerCall::Unreserve { id: name },
+        ]
+    );
+    assert_eq!(h.issue(id).await.reservation, None);
+    assert!(h.sched.reservations().is_empty());
+    assert_eq!(
+        h.target_status("gu0005").await,
+        Some(TargetStatus::Draining)
+    );
+
+    h.close(id, "firmware updated").await;
+    h.sync().await;
+    assert_eq!(h.take_calls(), vec![release("gu0005"), release("gu0006")]);
+}
+
+#[tokio::test]
+async fn scheduled_maintenance_closed_early() {
+    let mut h = Harness::gust().await;
+    h.sync().await;
+
+    let id = open_scheduled(&h, "gu0009", "NODE").await;
+    h.sync().await;
+    assert_eq!(h.sched.reservations().len(), 1);
+    h.take_calls();
+
+    // changing the window replaces the reservation
+    let start = Utc::now().naive_utc() + chrono::Duration::days(2);
+    h.update(json!({"id": id, "scheduledStart": start, "scheduledEnd": start + chrono::Duration::hours(1)}))
+        .await;
+    assert_eq!(h.issue(id).await.reservation, None);
+    h.sync().await;
+    let name = format!("ctt{}", id);
+    assert_eq!(
+        h.take_calls(),
+        vec![
+            SchedulerCall::Reserve {
+                name: name.clone(),
+                targets: vec!["gu0009".to_string()]
+            },
+            SchedulerCall::Unreserve { id: name.clone() },
+        ]
+    );
+
+    h.close(id, "not needed").await;
+    h.sync().await;
+    assert_eq!(h.take_calls(), vec![SchedulerCall::Unreserve { id: name }]);
+    assert!(h.sched.reservations().is_empty());
+    assert_eq!(h.issue(id).await.status, IssueStatus::Closed);
+    assert_eq!(h.target_status("gu0009").await, Some(TargetStatus::Online));
+}
+
+#[tokio::test]
+async fn infrastructure_issue_offlines_nodes_behind_it() {
+    let mut h = Harness::gust().await;
+    h.set_infrastructure(&[conf::Infrastructure {
+        name: "switch-r3-l2".to_string(),
+        kind: "switch".to_string(),
+        nodes: "gu[0005-0006]".to_string(),
+    }]);
+    h.sync().await;
+
+    let id = h
+        .open("switch-r3-l2", "leaf switch down", Some("NODE"))
+        .await;
+    let resp = h
+        .run(
+            "query Issue($id: Int!) { issue(issue: $id) { target { kind } relatedHostlist } }",
+            json!({"id": id}),
+        )
+        .await;
+    assert_eq!(
+        resp["issue"],
+        json!({"target": {"kind": "switch"}, "relatedHostlist": "gu[0005-0006]"})
+    );
+    h.sync().await;
+    let comment = format!("ctt#{}: switch-r3-l2: leaf switch down", id);
+    assert_eq!(
+        h.take_calls(),
+        vec![offline("gu0005", &comment), offline("gu0006", &comment)]
+    );
+    assert_eq!(h.issue(id).await.status, IssueStatus::Open);
+    // the switch isn't in the scheduler, but isn't missing from it either
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert_eq!(h.issues_for("switch-r3-l2").await.len(), 1);
+    assert_eq!(h.target_status("gu0005").await, Some(TargetStatus::Offline));
+    assert_eq!(h.target_status("gu0007").await, Some(TargetStatus::Online));
+
+    h.close(id, "switch replaced").await;
+    h.sync().await;
+    assert_eq!(h.take_calls(), vec![release("gu0005"), release("gu0006")]);
+    assert_eq!(h.issue(id).await.status, IssueStatus::Closed);
+
+    // without to_offline the issue is only tracked
+    h.open("switch-r3-l2", "flaky fan", None).await;
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+}
+
+#[tokio::test]
+async fn observe_mode_only_plans() {
+    let mut h = Harness::gust().await;
+    h.conf.observe = true;
+    h.sync().await;
+
+    let id = h.open("gu0005", "replace dimm", Some("NODE")).await;
+    h.sched
+        .set_node("gu0007", TargetStatus::Offline, "admin testing");
+    h.sync().await;
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert!(h.issues_for("gu0007").await.is_empty());
+    assert_eq!(h.target_status("gu0005").await, Some(TargetStatus::Online));
+    let query = "query Planned($target: String) { plannedActions(target: $target) { cluster target action detail issueId } }";
+    let resp = h.run(query, json!({"target": "gu[0005-0007]"})).await;
+    // planned again every sync, but only recorded once
+    assert_eq!(
+        resp["plannedActions"],
+        json!([
+            {"cluster": "default", "target": "gu0005", "action": "OFFLINE",
+             "detail": format!("ctt#{}: replace dimm", id), "issueId": id},
+            {"cluster": "default", "target": "gu0007", "action": "OPEN_ISSUE",
+             "detail": "admin testing", "issueId": null},
+        ])
+    );
+
+    // a node type can be observed on its own
+    let gpu = h.add_cluster(
+        "gpu",
+        vec![NodeType {
+            digits: Some(4),
+            observe: true,
+            ..NodeType::with_prefix("deg")
+        }],
+        &["deg0001", "deg0002"],
+    );
+    h.conf.observe = false;
+    h.sync().await;
+    let gpu_id = h.open("deg0001", "bad gpu", Some("NODE")).await;
+    h.sync().await;
+    assert!(take_calls(&gpu).is_empty());
+    assert_eq!(
+        h.take_calls(),
+        vec![offline("gu0005", &format!("ctt#{}: replace dimm", id))]
+    );
+    let resp = h.run(query, json!({"target": "deg0001"})).await;
+    assert_eq!(resp["plannedActions"][0]["issueId"], json!(gpu_id));
+}
+
+#[tokio::test]
+async fn mass_offline_pauses_automation() {
+    let mut h = Harness::gust().await;
+    h.conf.max_automated = Some(conf::Threshold::Count(4));
+    h.sync().await;
+
+    // under the limit everything goes ahead
+    h.sched.set_node("gu0016", TargetStatus::Down, "psu");
+    h.sync().await;
+    assert_eq!(h.issues_for("gu0016").await.len(), 1);
+
+    for i in 1..=8 {
+        h.sched
+            .set_node(&format!("gu{:0>4}", i), TargetStatus::Down, "no contact");
+    }
+    h.take_changelog();
+    h.sync().await;
+    assert!(h.issues_for("gu0001").await.is_empty());
+    let alert = h.issues_for("default").await;
+    assert_eq!(alert.len(), 1);
+    assert_eq!(alert[0].status, IssueStatus::Open);
+    assert_eq!(
+        alert[0].title,
+        "automation paused, sync would offline or open issues for 8 of 16 nodes"
+    );
+    assert!(h.take_changelog().contains(&ChangeLogMsg::Paused {
+        cluster: "default".to_string(),
+        issue: alert[0].id,
+        title: alert[0].title.clone(),
+    }));
+
+    // admin issues aren't offlined either until an admin resumes
+    let id = h.open("gu0010", "replace dimm", Some("NODE")).await;
+    h.sync().await;
+    assert!(h.take_calls().is_empty());
+    assert_eq!(h.issues_for("default").await.len(), 1);
+
+    h.run(
+        "mutation Resume($cluster: String!) { resumeAutomation(cluster: $cluster, comment: \"pbs restarted\") }",
+        json!({"cluster": "default"}),
+    )
+    .await;
+    h.sync().await;
+    assert_eq!(
+        h.take_calls(),
+        vec![offline("gu0010", &format!("ctt#{}: replace dimm", id))]
+    );
+    assert_eq!(h.issues_for("gu0001").await.len(), 1);
+    assert_eq!(h.issues_for("default").await[0].status, IssueStatus::Closed);
+
+    let resp = h
+        .request(
+            r#"mutation { resumeAutomation(cluster: "default", comment: "again") }"#,
+            json!({}),
+        )
+        .await;
+    assert_eq!(resp.errors[0].message, "automation in default isn't paused");
+}
+
+#[tokio::test]
+async fn admin_maintenance_doesnt_pause_automation() {
+    let mut h = Harness::gust().await;
+    h.conf.max_automated = Some(conf::Threshold::Count(4));
+    h.sync().await;
+    // the whole 16 node rack is taken out for maintenance
+    let id = h.open("gu0001", "rack maintenance", Some("RACK")).await;
+    h.sync().await;
+    assert_eq!(h.take_calls().len(), 16);
+    assert!(h.issues_for("default").await.is_empty());
+
+    // the cluster's own target isn't listed
+    h.close(id, "done").await;
+    h.sync().await;
+    h.sched.set_node("gu0003", TargetStatus::Down, "no contact");
+    h.conf.max_automated = Some(conf::Threshold::Count(0));
+    h.sync().await;
+    assert_eq!(h.issues_for("default").await.len(), 1);
+    let resp = h.run("{ targets { name } }", json!({})).await;
+    let names = resp["targets"].as_array().unwrap();
+    assert_eq!(names.len(), 16);
+    assert!(!names.contains(&json!({"name": "default"})));
+}
+
+#[tokio::test]
+async fn flapping_node_gets_one_issue() {
+    let mut h = Harness::gust().await;
+    h.conf.flapping = Some(conf::Flapping {
+        transitions: 4,
+        window: 3600,
+    });
+    h.sync().await;
+    for _ in 0..3 {
+        h.sched.set_node("gu0003", TargetStatus::Down, "no contact");
+        h.sync().await;
+        h.sched.set_node("gu0003", TargetStatus::Online, "");
+        h.sync().await;
+    }
+    let issues = h.issues_for("gu0003").await;
+    assert_eq!(issues.len(), 3);
+    let flap = &issues[2];
+    assert_eq!(flap.title, "Flapping between up and down");
+    assert_eq!(flap.status, IssueStatus::Open);
+    assert_eq!(flap.to_offline, Some(ToOffline::Node));
+    assert_eq!(
+        h.take_calls(),
+        vec![offline(
+            "gu0003",
+            &format!("ctt#{}: Flapping between up and down", flap.id)
+        )]
+    );
+
+    // it keeps bouncing under the issue, nothing new is opened or closed
+    h.sched.set_node("gu0003", TargetStatus::Down, "no contact");
+    h.sync().await;
+    h.sched.set_node(
+        "gu0003",
+        TargetStatus::Offline,
+        &format!("ctt#{}: x", flap.id),
+    );
+    h.sync().await;
+    let issues = h.issues_for("gu0003").await;
+    assert_eq!(issues.len(), 3);
+    assert_eq!(issues[2].status, IssueStatus::Open);
+
+    let resp = h
+        .run(
+            "{ targets(target: \"gu0003\") { stateChanges { oldStatus, newStatus } } }",
+            json!({}),
+        )
+        .await;
+    let changes = resp["targets"][0]["stateChanges"].as_array().unwrap().len();
+    assert!(changes >= 7, "{}", changes);
+
+    // closing acknowledges it, only changes after that count
+    h.take_calls();
+    h.close(flap.id, "reseated").await;
+    h.sync().await;
+    assert_eq!(h.take_calls(), vec![release("gu0003")]);
+    h.sched.set_node("gu0003", TargetStatus::Down, "no contact");
+    h.sync().await;
+    let issues = h.issues_for("gu0003").await;
+    assert_eq!(issues.len(), 4);
+    assert_eq!(issues[3].title, "no contact");
+}
+
+#[tokio::test]
+async fn flapping_skips_ctt_changes() {
+    use crate::entities::state_change;
+    use sea_orm::{ActiveModelTrait, ActiveValue};
+    let mut h = Harness::gust().await;
+    h.conf.flapping = Some(conf::Flapping {
+        transitions: 2,
+        window: 3600,
+    });
+    h.sync().await;
+    // old enough to be pruned, and it would have made the node flapping
+    let t = Target::find()
+        .filter(target::Column::Name.eq("gu0004"))
+        .one(h.db.as_ref())
+        .await
+        .unwrap()
+        .unwrap();
+    state_change::ActiveModel {
+        target_id: ActiveValue::Set(t.id),
+        old_status: ActiveValue::Set(TargetStatus::Online),
+        new_status: ActiveValue::Set(TargetStatus::Down),
+        changed_at: ActiveValue::Set(Utc::now().naive_utc() - chrono::Duration::hours(2)),
+        by_ctt: ActiveValue::Set(false),
+        ..Default::default()
+    }
+    .insert(h.db.as_ref())
+    .await
+    .unwrap();
+
+    h.sched.set_node("gu0004", TargetStatus::Down, "no contact");
+    h.sync().await;
+    let id = h.issues_for("gu0004").await[0].id;
+    // an admin issue with the same title doesn't acknowledge anything
+    let other = h.open("gu0004", "Flapping between up and down", None).await;
+    h.close(other, "not ctt's").await;
+    // ctt offlining and resuming the node doesn't count
+    h.update(json!({"id": id, "toOffline": "NODE"})).await;
+    h.sync().await;
+    h.sched
+        .set_node("gu0004", TargetStatus::Offline, &format!("ctt#{}: x", id));
+    h.close(id, "replaced dimm").await;
+    h.sync().await;
+    assert_eq!(h.target_status("gu0004").await, Some(TargetStatus::Online));
+
+    h.sched.set_node("gu0004", TargetStatus::Down, "no contact");
+    h.sync().await;
+    let issues = h.issues_for("gu0004").await;
+    assert_eq!(issues.last().unwrap().title, "no contact");
+    let changes: Vec<(TargetStatus, TargetStatus, bool)> = StateChange::find()
+        .filter(state_change::Column::TargetId.eq(t.id))
+        .order_by_asc(state_change::Column::Id)
+        .all(h.db.as_ref())
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|c| (c.old_status, c.new_status, c.by_ctt))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            (TargetStatus::Online, TargetStatus::Down, false),
+            (TargetStatus::Down, TargetStatus::Offline, true),
+            (TargetStatus::Offline, TargetStatus::Online, true),
+            (TargetStatus::Online, TargetStatus::Down, false),
+        ]
+    );
+}